@@ -0,0 +1,411 @@
+use crate::tls::CertResolver;
+use crate::logs::*;
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509, X509NameBuilder, X509ReqBuilder};
+use reqwest::{Client, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::time::{interval, sleep};
+
+const CHECK_INTERVAL: u64 = 12 * 60 * 60;
+const POLL_ATTEMPTS: usize = 30;
+const POLL_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+  // e.g. https://acme-v02.api.letsencrypt.org/directory
+  pub directory: String,
+  // Contact for expiry notices
+  pub email: Option<String>,
+  // Accepts a self-signed directory like Pebble's
+  pub insecure: bool,
+  // How long before expiry a certificate gets renewed
+  pub renew_days: u32,
+}
+
+// <Token, Key authorization> of the HTTP-01 challenges being validated right now
+fn challenges() -> &'static Mutex<HashMap<String, String>> {
+  static CHALLENGES: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+  CHALLENGES.get_or_init(Default::default)
+}
+
+pub async fn challenge(token: web::Path<String>) -> HttpResponse {
+  match challenges().lock().unwrap().get(token.as_str()) {
+    Some(authorization) => HttpResponse::Ok().content_type("application/octet-stream").body(authorization.clone()),
+    None => HttpResponse::NotFound().finish(),
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+  new_nonce: String,
+  new_account: String,
+  new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+  status: String,
+  authorizations: Vec<String>,
+  finalize: String,
+  certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+  status: String,
+  challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+  #[serde(rename = "type")]
+  kind: String,
+  url: String,
+  token: String,
+}
+
+// One signed conversation with the CA, RFC 8555 over JWS signed with a P-256 account key
+struct Account {
+  client: Client,
+  directory: Directory,
+  key: EcKey<Private>,
+  kid: Option<String>,
+  nonce: Option<String>,
+}
+
+impl Account {
+  async fn new(config: &Config, data_dir: &str) -> io::Result<Self> {
+    let client = Client::builder().danger_accept_invalid_certs(config.insecure).build().map_err(io::Error::other)?;
+    let directory = client.get(&config.directory).send().await.map_err(io::Error::other)?
+      .error_for_status().map_err(io::Error::other)?
+      .json::<Directory>().await.map_err(io::Error::other)?;
+
+    let mut account = Account { client, directory, key: account_key(data_dir)?, kid: None, nonce: None };
+
+    let contact = config.email.iter().map(|email| format!("mailto:{}", email)).collect::<Vec<_>>();
+    let url = account.directory.new_account.clone();
+    let res = account.post(&url, Some(json!({ "termsOfServiceAgreed": true, "contact": contact }))).await?;
+    account.kid = Some(header(&res, "Location")?);
+
+    Ok(account)
+  }
+
+  fn jwk(&self) -> io::Result<Value> {
+    let mut ctx = BigNumContext::new().map_err(io::Error::other)?;
+    let point = self.key.public_key().to_bytes(self.key.group(), PointConversionForm::UNCOMPRESSED, &mut ctx).map_err(io::Error::other)?;
+
+    // Uncompressed points are 0x04 followed by the 32 byte x and y coordinates
+    Ok(json!({ "crv": "P-256", "kty": "EC", "x": BASE64.encode(&point[1..33]), "y": BASE64.encode(&point[33..65]) }))
+  }
+
+  fn thumbprint(&self) -> io::Result<String> {
+    let jwk = self.jwk()?;
+    let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#, jwk["x"], jwk["y"]);
+    Ok(BASE64.encode(Sha256::digest(canonical.as_bytes())))
+  }
+
+  fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> io::Result<Value> {
+    let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+    match &self.kid {
+      Some(kid) => protected["kid"] = json!(kid),
+      None => protected["jwk"] = self.jwk()?,
+    }
+
+    let protected = BASE64.encode(protected.to_string());
+    // POST-as-GET requests sign an empty payload
+    let payload = payload.map_or(String::new(), |payload| BASE64.encode(payload.to_string()));
+
+    let digest = Sha256::digest(format!("{}.{}", protected, payload).as_bytes());
+    let signature = EcdsaSig::sign(&digest, &self.key).map_err(io::Error::other)?;
+    let mut raw = signature.r().to_vec_padded(32).map_err(io::Error::other)?;
+    raw.extend(signature.s().to_vec_padded(32).map_err(io::Error::other)?);
+
+    Ok(json!({ "protected": protected, "payload": payload, "signature": BASE64.encode(raw) }))
+  }
+
+  async fn nonce(&mut self) -> io::Result<String> {
+    if let Some(nonce) = self.nonce.take() {
+      return Ok(nonce);
+    }
+
+    let res = self.client.head(&self.directory.new_nonce).send().await.map_err(io::Error::other)?;
+    header(&res, "Replay-Nonce")
+  }
+
+  // Retries once when the CA rejects the nonce, which it may do at any time
+  async fn post(&mut self, url: &str, payload: Option<Value>) -> io::Result<Response> {
+    for attempt in 0..2 {
+      let nonce = self.nonce().await?;
+      let body = self.sign(url, &nonce, payload.as_ref())?;
+      let res = self.client.post(url)
+        .header("Content-Type", "application/jose+json")
+        .body(body.to_string())
+        .send().await.map_err(io::Error::other)?;
+
+      self.nonce = header(&res, "Replay-Nonce").ok();
+      if res.status().is_success() {
+        return Ok(res);
+      }
+
+      let status = res.status();
+      let problem = res.json::<Value>().await.unwrap_or_default();
+      if attempt == 0 && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+        continue;
+      }
+
+      return Err(io::Error::other(format!("{} answered {}: {}", url, status, problem["detail"].as_str().unwrap_or_default())));
+    }
+
+    unreachable!()
+  }
+
+  async fn get<T: for<'de> Deserialize<'de>>(&mut self, url: &str) -> io::Result<T> {
+    self.post(url, None).await?.json::<T>().await.map_err(io::Error::other)
+  }
+
+  async fn authorize(&mut self, url: &str) -> io::Result<()> {
+    let authorization = self.get::<Authorization>(url).await?;
+    if authorization.status == "valid" {
+      return Ok(());
+    }
+
+    let challenge = authorization.challenges.into_iter().find(|challenge| challenge.kind == "http-01")
+      .ok_or(io::Error::other("The CA offered no HTTP-01 challenge"))?;
+
+    let key_authorization = format!("{}.{}", challenge.token, self.thumbprint()?);
+    challenges().lock().unwrap().insert(challenge.token.clone(), key_authorization);
+
+    let result = async {
+      self.post(&challenge.url, Some(json!({}))).await?;
+      for _ in 0..POLL_ATTEMPTS {
+        sleep(POLL_DELAY).await;
+        match self.get::<Authorization>(url).await?.status.as_str() {
+          "valid" => return Ok(()),
+          "pending" | "processing" => continue,
+          status => return Err(io::Error::other(format!("Authorization became {}", status))),
+        }
+      }
+
+      Err(io::Error::other("Timed out waiting for the authorization"))
+    }.await;
+
+    challenges().lock().unwrap().remove(&challenge.token);
+    result
+  }
+
+  // Returns the PEM certificate chain and the PKCS#8 PEM key it was issued for. The certificate covers
+  // www. as well, the resolver serves it for both and the redirect to the bare domain needs a handshake first.
+  async fn order(&mut self, domain: &str) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let url = self.directory.new_order.clone();
+    let identifiers = names(domain).iter().map(|name| json!({ "type": "dns", "value": name })).collect::<Vec<_>>();
+    let res = self.post(&url, Some(json!({ "identifiers": identifiers }))).await?;
+    let order_url = header(&res, "Location")?;
+    let order = res.json::<Order>().await.map_err(io::Error::other)?;
+
+    for authorization in &order.authorizations {
+      self.authorize(authorization).await?;
+    }
+
+    let key = new_key()?;
+    self.post(&order.finalize, Some(json!({ "csr": BASE64.encode(csr(domain, &key)?) }))).await?;
+
+    for _ in 0..POLL_ATTEMPTS {
+      let order = self.get::<Order>(&order_url).await?;
+      match (order.status.as_str(), order.certificate) {
+        ("valid", Some(certificate)) => {
+          let chain = self.post(&certificate, None).await?.bytes().await.map_err(io::Error::other)?;
+          return Ok((chain.to_vec(), key.private_key_to_pem_pkcs8().map_err(io::Error::other)?));
+        },
+        ("pending" | "ready" | "processing" | "valid", _) => sleep(POLL_DELAY).await,
+        (status, _) => return Err(io::Error::other(format!("Order became {}", status))),
+      }
+    }
+
+    Err(io::Error::other("Timed out waiting for the certificate"))
+  }
+}
+
+fn header(res: &Response, name: &str) -> io::Result<String> {
+  res.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_owned)
+    .ok_or(io::Error::other(format!("Missing {} header", name)))
+}
+
+fn names(domain: &str) -> [String; 2] {
+  [domain.to_owned(), format!("www.{}", domain)]
+}
+
+// Written to <file>.tmp with the given permissions and fsynced, renaming it into place is up to the caller
+fn stage(path: &Path, contents: &[u8], mode: u32) -> io::Result<PathBuf> {
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  let tmp = PathBuf::from(tmp);
+
+  let _ = fs::remove_file(&tmp);
+  let mut file = OpenOptions::new().write(true).create_new(true).mode(mode).open(&tmp)?;
+  file.write_all(contents)?;
+  file.sync_all()?;
+  Ok(tmp)
+}
+
+fn new_key() -> io::Result<PKey<Private>> {
+  let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(io::Error::other)?;
+  EcKey::generate(&group).and_then(PKey::from_ec_key).map_err(io::Error::other)
+}
+
+// Kept across restarts so renewals happen under the same account
+fn account_key(data_dir: &str) -> io::Result<EcKey<Private>> {
+  let file = PathBuf::from(format!("{}acme/account.pem", data_dir));
+  if let Ok(pem) = fs::read(&file) {
+    // Installs from before the key was written 0600
+    fs::set_permissions(&file, Permissions::from_mode(0o600))?;
+    return PKey::private_key_from_pem(&pem).and_then(|key| key.ec_key()).map_err(io::Error::other);
+  }
+
+  let key = new_key()?;
+  fs::create_dir_all(format!("{}acme", data_dir))?;
+  let tmp = stage(&file, &key.private_key_to_pem_pkcs8().map_err(io::Error::other)?, 0o600)?;
+  fs::rename(tmp, &file)?;
+  key.ec_key().map_err(io::Error::other)
+}
+
+fn csr(domain: &str, key: &PKey<Private>) -> io::Result<Vec<u8>> {
+  let build = || {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_subject_name(&name.build())?;
+    builder.set_pubkey(key)?;
+
+    let [bare, www] = names(domain);
+    let mut extensions = Stack::new()?;
+    extensions.push(SubjectAlternativeName::new().dns(&bare).dns(&www).build(&builder.x509v3_context(None))?)?;
+    builder.add_extensions(&extensions)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    builder.build().to_der()
+  };
+
+  build().map_err(io::Error::other)
+}
+
+// A key that doesn't belong to the certificate, e.g. after a crash halfway through installing them, needs one too
+fn needs_renewal(resolver: &CertResolver, domain: &str, renew_days: u32) -> bool {
+  let threshold = match Asn1Time::days_from_now(renew_days) {
+    Ok(threshold) => threshold,
+    Err(_) => return true,
+  };
+
+  let (cert, key) = resolver.cert_paths(domain);
+  let cert = match fs::read(cert).ok().and_then(|pem| X509::from_pem(&pem).ok()) {
+    Some(cert) => cert,
+    None => return true,
+  };
+
+  let key = fs::read(key).ok().and_then(|pem| PKey::private_key_from_pem(&pem).ok());
+  let matches = key.is_some_and(|key| cert.public_key().is_ok_and(|public| public.public_eq(&key)));
+  !matches || cert.not_after() < threshold
+}
+
+async fn renew(resolver: &CertResolver, config: &Config) -> io::Result<()> {
+  let domains = resolver.domains().iter().filter(|domain| needs_renewal(resolver, domain, config.renew_days)).collect::<Vec<_>>();
+  if domains.is_empty() {
+    return Ok(());
+  }
+
+  let mut account = Account::new(config, resolver.data_dir()).await?;
+  for domain in domains {
+    info!("Requesting a certificate for {}...", domain);
+    let (chain, key) = match account.order(domain).await {
+      Ok(issued) => issued,
+      Err(err) => {
+        error!("Couldn't obtain a certificate for {}: {}", domain, err);
+        continue;
+      }
+    };
+
+    // Loaded right away instead of waiting for the watcher to notice the new files
+    let (cert_path, key_path) = resolver.cert_paths(domain);
+    if let Some(dir) = cert_path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+
+    // Both are staged before either replaces the old pair, the key is only readable by the server
+    let key_tmp = stage(&key_path, &key, 0o600)?;
+    let cert_tmp = stage(&cert_path, &chain, 0o644)?;
+    fs::rename(key_tmp, &key_path)?;
+    fs::rename(cert_tmp, &cert_path)?;
+    resolver.load(domain)?;
+    info!("Installed a new certificate for {}", domain);
+  }
+
+  Ok(())
+}
+
+pub fn start_renewal_loop(resolver: Arc<CertResolver>, config: Config) {
+  tokio::spawn(async move {
+    info!("Starting ACME renewal loop against {}...", config.directory);
+    let mut interval = interval(Duration::from_secs(CHECK_INTERVAL));
+
+    loop {
+      interval.tick().await;
+      if let Err(err) = renew(&resolver, &config).await {
+        error!("Certificate renewal failed: {}", err);
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::x509::X509Req;
+
+  #[test]
+  fn requests_the_www_name_too() {
+    let key = new_key().unwrap();
+    let csr = X509Req::from_der(&csr("entitia.com", &key).unwrap()).unwrap();
+    let der = csr.to_der().unwrap();
+
+    // The SAN extension holds the names as plain IA5 strings
+    for name in ["entitia.com", "www.entitia.com"] {
+      assert!(der.windows(name.len()).any(|window| window == name.as_bytes()), "{} is missing", name);
+    }
+  }
+
+  #[test]
+  fn staged_keys_are_private() {
+    let dir = std::env::temp_dir().join(format!("dashboard-acme-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let tmp = stage(&dir.join("private.pem"), b"key", 0o600).unwrap();
+    assert_eq!(tmp, dir.join("private.pem.tmp"));
+    assert_eq!(fs::metadata(&tmp).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(fs::read(&tmp).unwrap(), b"key");
+    assert!(!dir.join("private.pem").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,199 @@
+use crate::state::patient::Patient;
+use crate::state::session::Session;
+use crate::storage::{self, Storage};
+use crate::logs::*;
+
+use std::io;
+use std::sync::Arc;
+
+use actix_web::error::ErrorInternalServerError;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+  Patient,
+  Session,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+  Create,
+  Update,
+  Delete,
+  Restore,
+  Purge,
+}
+
+// Where the mutation came from: a REST call, the session WebSocket or a Google Calendar webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+  Api,
+  Socket,
+  Calendar,
+  System,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+  pub timestamp: u64,
+  pub actor: String,
+  pub source: Source,
+  pub action: Action,
+  pub entity: EntityKind,
+  pub uuid: String,
+  pub patient_uuid: String,
+
+  // For updates only the fields that changed are kept
+  pub before: Option<Value>,
+  pub after: Option<Value>,
+}
+
+// A page of the log, newest first. `next` is the cursor for the page after it, if there's more.
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+  pub entries: Vec<AuditEntry>,
+  pub next: Option<u64>,
+}
+
+pub trait Audited: Serialize {
+  const KIND: EntityKind;
+
+  fn uuid(&self) -> &str;
+  fn patient_uuid(&self) -> &str;
+}
+
+impl Audited for Patient {
+  const KIND: EntityKind = EntityKind::Patient;
+
+  fn uuid(&self) -> &str {
+    &self.uuid
+  }
+
+  fn patient_uuid(&self) -> &str {
+    &self.uuid
+  }
+}
+
+impl Audited for Session {
+  const KIND: EntityKind = EntityKind::Session;
+
+  fn uuid(&self) -> &str {
+    &self.uuid
+  }
+
+  fn patient_uuid(&self) -> &str {
+    &self.patient_uuid
+  }
+}
+
+// The entries of one change, appended together so the log never holds only part of it.
+// Everything is on disk once commit returns, callers only apply the change after that.
+pub struct Batch<'a> {
+  actor: &'a str,
+  source: Source,
+  entries: Vec<AuditEntry>,
+}
+
+impl<'a> Batch<'a> {
+  pub fn new(actor: &'a str, source: Source) -> Self {
+    Batch { actor, source, entries: Vec::new() }
+  }
+
+  pub fn created<T: Audited>(&mut self, after: &T) -> &mut Self {
+    self.push(Action::Create, after, None, serde_json::to_value(after).ok())
+  }
+
+  pub fn updated<T: Audited>(&mut self, before: &T, after: &T) -> &mut Self {
+    let (before_value, after_value) = match (serde_json::to_value(before), serde_json::to_value(after)) {
+      (Ok(before), Ok(after)) => diff(before, after),
+      _ => return self,
+    };
+
+    if before_value.is_empty() && after_value.is_empty() {
+      return self;
+    }
+
+    self.push(Action::Update, after, Some(Value::Object(before_value)), Some(Value::Object(after_value)))
+  }
+
+  pub fn deleted<T: Audited>(&mut self, before: &T) -> &mut Self {
+    self.push(Action::Delete, before, serde_json::to_value(before).ok(), None)
+  }
+
+  pub fn restored<T: Audited>(&mut self, after: &T) -> &mut Self {
+    self.push(Action::Restore, after, None, serde_json::to_value(after).ok())
+  }
+
+  pub fn purged<T: Audited>(&mut self, before: &T) -> &mut Self {
+    self.push(Action::Purge, before, serde_json::to_value(before).ok(), None)
+  }
+
+  fn push<T: Audited>(&mut self, action: Action, entity: &T, before: Option<Value>, after: Option<Value>) -> &mut Self {
+    self.entries.push(AuditEntry {
+      timestamp: Utc::now().timestamp() as u64,
+      actor: self.actor.to_owned(),
+      source: self.source,
+      action,
+      entity: T::KIND,
+      uuid: entity.uuid().to_owned(),
+      patient_uuid: entity.patient_uuid().to_owned(),
+      before,
+      after,
+    });
+
+    self
+  }
+
+  pub async fn commit(self, storage: &Arc<dyn Storage>) -> io::Result<()> {
+    if self.entries.is_empty() {
+      return Ok(());
+    }
+
+    let (storage, entries) = (Arc::clone(storage), self.entries);
+    storage::run(move || storage.append_audit(&entries)).await
+  }
+}
+
+pub async fn created<T: Audited>(storage: &Arc<dyn Storage>, actor: &str, source: Source, after: &T) -> io::Result<()> {
+  let mut batch = Batch::new(actor, source);
+  batch.created(after);
+  batch.commit(storage).await
+}
+
+pub async fn updated<T: Audited>(storage: &Arc<dyn Storage>, actor: &str, source: Source, before: &T, after: &T) -> io::Result<()> {
+  let mut batch = Batch::new(actor, source);
+  batch.updated(before, after);
+  batch.commit(storage).await
+}
+
+pub async fn deleted<T: Audited>(storage: &Arc<dyn Storage>, actor: &str, source: Source, before: &T) -> io::Result<()> {
+  let mut batch = Batch::new(actor, source);
+  batch.deleted(before);
+  batch.commit(storage).await
+}
+
+// What a request gets when its change couldn't be recorded, the change itself is dropped
+pub fn failed(err: io::Error) -> actix_web::Error {
+  error!("Couldn't append to the audit log, rejecting the change: {}", err);
+  ErrorInternalServerError("Couldn't record the change")
+}
+
+fn diff(before: Value, after: Value) -> (Map<String, Value>, Map<String, Value>) {
+  let (mut before, mut after) = match (before, after) {
+    (Value::Object(before), Value::Object(after)) => (before, after),
+    _ => return (Map::new(), Map::new()),
+  };
+
+  let unchanged = before.iter().filter(|(key, value)| after.get(*key) == Some(value)).map(|(key, _)| key.clone()).collect::<Vec<_>>();
+  for key in unchanged {
+    before.remove(&key);
+    after.remove(&key);
+  }
+
+  (before, after)
+}
@@ -1,58 +1,800 @@
+use crate::audit::{self, Audited, Source};
+use crate::config::EnvVars;
+use crate::crypto;
+use crate::jobs;
+use crate::replication;
+use crate::state::patient::Patient;
+use crate::state::session::Session;
+use crate::state::state::{DrainWith, SseEvent, State};
+use crate::storage::{self, Storage};
 use crate::logs::*;
 
-use std::fs;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
 
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
 use tokio::time::{Duration, interval};
-use tokio::process::Command;
 use chrono::prelude::*;
+use uuid::Uuid;
 
-const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 30); // Backup every 30 minutes
-const BACKUP_HISTORY: usize = 24 * 2 * 3; // Keep 3 days of backups
+const BACKUP_EXTENSION: &str = ".tar.gz";
+const ENCRYPTED_EXTENSION: &str = ".tar.gz.enc";
+const TMP_EXTENSION: &str = ".tmp";
+// No backup takes this long to write, a temp file this old was abandoned
+const STALE_TMP: Duration = Duration::from_secs(60 * 60);
+const MANIFEST: &str = "manifest.json";
+const LOGS: &str = "logs.txt";
 
-pub fn start_backup_loop(path: &String) {
-  let backups_path = format!("{}backups", path);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+  pub app_version: String,
+  pub created_at: u64,
+  pub fingerprint: String,
+  pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestFile {
+  pub path: String,
+  pub size: u64,
+  pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+  pub name: String,
+  pub size: u64,
+  pub created_at: u64,
+  pub patients: Option<usize>,
+  pub sessions: Option<usize>,
+}
+
+pub struct Snapshot {
+  pub patients: Vec<Patient>,
+  pub sessions: Vec<Session>,
+}
+
+impl Snapshot {
+  // Narrows the snapshot down to a single patient and its sessions
+  pub fn only(mut self, patient: &str) -> Self {
+    self.patients.retain(|p| p.uuid == patient);
+    self.sessions.retain(|s| s.patient_uuid == patient);
+    self
+  }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Changes {
+  pub restored: Vec<String>,
+  pub changed: Vec<String>,
+  pub removed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diff {
+  pub patients: Changes,
+  pub sessions: Changes,
+}
+
+pub fn backups_path(path: &str) -> String {
+  format!("{}backups", path)
+}
+
+pub fn is_archive(name: &str) -> bool {
+  name.ends_with(BACKUP_EXTENSION) || name.ends_with(ENCRYPTED_EXTENSION)
+}
+
+pub fn start_backup_loop(storage: Arc<dyn Storage>, env: &EnvVars) {
+  if storage.backup_paths().is_empty() {
+    info!("Storage backend has nothing to back up, skipping backup loop");
+    return;
+  }
+
+  let backups_path = backups_path(&env.data_dir);
   if let Err(err) = fs::create_dir_all(&backups_path) {
     error!("Failed to create backups directory: {}", err);
     return;
   }
 
-  let path = path.clone();
-  
-  info!("Starting backup loop...");
+  if env.backup_passphrase.is_none() {
+    warning!("BACKUP_PASSPHRASE is not set, backups will be stored unencrypted");
+  }
+
+  let env = env.clone();
+  let every = env.backup_interval.max(1);
+
+  info!("Starting backup loop, every {} minutes keeping {} backups...", every, env.backup_retention);
   tokio::spawn(async move {
-    let mut interval = interval(BACKUP_INTERVAL);
-    
+    let mut interval = interval(Duration::from_secs(every * 60));
+
     loop {
       interval.tick().await;
-      let output = Command::new("tar")
-        .arg("-czf")
-        .arg(format!("{}/{}.tar.gz", backups_path, Local::now().format("%Y-%m-%d_%H-%M-%S")))
-        .arg("-C")
-        .arg(&path)
-        .arg("patients")
-        .arg("sessions")
-        .output()
-        .await;
-    
-      match output {
-        Ok(output) if !output.status.success() => {
-          error!("Failed to create backup: {}", String::from_utf8_lossy(&output.stderr));
+      run(&*storage, &env).await;
+    }
+  });
+}
+
+// One round of the backup loop
+pub async fn run(storage: &dyn Storage, env: &EnvVars) {
+  if archive(storage, env).await {
+    replication::sync(&env.data_dir, env.replica.as_ref()).await;
+  }
+}
+
+// Takes a new archive if anything changed and removes the old ones, without replicating. Also run on
+// shutdown so nothing since the last round is left out. The archive is written on a thread of its own,
+// so a caller that stops waiting doesn't keep the process alive, the half-written file is pruned later.
+// Returns false when the storage backend has nothing to back up.
+pub async fn archive(storage: &dyn Storage, env: &EnvVars) -> bool {
+  let paths = storage.backup_paths();
+  if paths.is_empty() {
+    return false;
+  }
+
+  let (tx, rx) = oneshot::channel();
+  let backup_env = env.clone();
+  thread::spawn(move || {
+    let _ = tx.send(create_backup(&backup_env, &paths));
+  });
+
+  match rx.await {
+    Ok(Ok(Some(name))) => info!("Created backup {}", name),
+    Ok(Ok(None)) => info!("Nothing changed since the last backup, skipping"),
+    Ok(Err(err)) => error!("Failed to create backup: {}", err),
+    Err(err) => error!("Failed to create backup: {}", err),
+  };
+
+  if let Err(err) = prune(&env.data_dir, env.backup_retention) {
+    error!("Failed to remove old backups: {}", err);
+  }
+
+  true
+}
+
+// Archive names, newest first. Timestamped names sort chronologically.
+pub fn archives(path: &str) -> io::Result<Vec<String>> {
+  let mut backups = Vec::new();
+  for entry in fs::read_dir(backups_path(path))? {
+    let name = entry?.file_name().to_string_lossy().into_owned();
+    if is_archive(&name) {
+      backups.push(name);
+    }
+  }
+
+  backups.sort_by(|a, b| b.cmp(a));
+  Ok(backups)
+}
+
+fn prune(path: &str, retention: usize) -> io::Result<()> {
+  // Leftovers from backups that were interrupted halfway through. Newer ones may belong to a
+  // backup still being written, e.g. the one taken before a restore, so they're left alone.
+  for entry in fs::read_dir(backups_path(path))? {
+    let entry = entry?;
+    if !entry.file_name().to_string_lossy().ends_with(TMP_EXTENSION) {
+      continue;
+    }
+
+    let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+    if age >= STALE_TMP {
+      fs::remove_file(entry.path())?;
+    }
+  }
+
+  for name in archives(path)?.into_iter().skip(retention) {
+    fs::remove_file(PathBuf::from(backups_path(path)).join(&name))?;
+    counts().lock().unwrap().remove(&name);
+  }
+
+  Ok(())
+}
+
+fn sha256(contents: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(contents))
+}
+
+fn collect(root: &Path, relative: PathBuf, files: &mut Vec<(String, Vec<u8>)>) -> io::Result<()> {
+  let full = root.join(&relative);
+  let metadata = match fs::metadata(&full) {
+    Ok(metadata) => metadata,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+    Err(err) => return Err(err),
+  };
+
+  if metadata.is_dir() {
+    for entry in fs::read_dir(&full)? {
+      collect(root, relative.join(entry?.file_name()), files)?;
+    }
+  } else if !relative.to_string_lossy().ends_with(TMP_EXTENSION) {
+    files.push((relative.to_string_lossy().into_owned(), fs::read(&full)?));
+  }
+
+  Ok(())
+}
+
+// Logs change with every line written (including the one announcing the backup),
+// so they're archived but left out of the fingerprint used to detect changes
+fn fingerprint(files: &[(String, Vec<u8>)]) -> String {
+  let mut hasher = Sha256::new();
+  for (path, contents) in files.iter().filter(|(path, _)| path != LOGS) {
+    hasher.update(format!("{} {}\n", path, sha256(contents)));
+  }
+
+  format!("{:x}", hasher.finalize())
+}
+
+fn last_fingerprint(env: &EnvVars) -> &'static Mutex<Option<String>> {
+  static LAST: OnceLock<Mutex<Option<String>>> = OnceLock::new();
+  LAST.get_or_init(|| {
+    let newest = archives(&env.data_dir).ok().and_then(|archives| archives.into_iter().next());
+
+    let fingerprint = newest.and_then(|name| verify(env, &PathBuf::from(backups_path(&env.data_dir)).join(name)).ok()).map(|manifest| manifest.fingerprint);
+    Mutex::new(fingerprint)
+  })
+}
+
+// Archives everything the storage backend persists plus the logs. The archive is written to a
+// temp file and verified against its manifest before it's renamed into place.
+// Returns None when nothing changed since the last backup.
+pub fn create_backup(env: &EnvVars, paths: &[&str]) -> io::Result<Option<String>> {
+  let path = env.data_dir.as_str();
+  let mut files = Vec::new();
+  for &entry in paths.iter().chain(once(&LOGS)) {
+    collect(Path::new(path), PathBuf::from(entry), &mut files)?;
+  }
+  files.sort_by(|a, b| a.0.cmp(&b.0));
+
+  let fingerprint = fingerprint(&files);
+  let mut last = last_fingerprint(env).lock().unwrap();
+  if last.as_deref() == Some(fingerprint.as_str()) {
+    return Ok(None);
+  }
+
+  let now = Local::now();
+  let extension = if env.backup_passphrase.is_some() { ENCRYPTED_EXTENSION } else { BACKUP_EXTENSION };
+  let name = format!("{}{}", now.format("%Y-%m-%d_%H-%M-%S"), extension);
+  let archive = PathBuf::from(backups_path(path)).join(&name);
+  let tmp = PathBuf::from(backups_path(path)).join(format!("{}{}", name, TMP_EXTENSION));
+
+  let manifest = manifest(&files, fingerprint.clone(), now.timestamp() as u64);
+  let passphrase = env.backup_passphrase.as_deref();
+  let result = write_archive(&tmp, &files, &manifest, passphrase).and_then(|_| verify(env, &tmp));
+  if let Err(err) = result {
+    let _ = fs::remove_file(&tmp);
+    return Err(err);
+  }
+
+  fs::rename(&tmp, &archive)?;
+  *last = Some(fingerprint);
+  Ok(Some(name))
+}
+
+fn manifest(files: &[(String, Vec<u8>)], fingerprint: String, created_at: u64) -> Manifest {
+  Manifest {
+    app_version: env!("CARGO_PKG_VERSION").to_owned(),
+    created_at,
+    fingerprint,
+    files: files.iter().map(|(path, contents)| ManifestFile {
+      path: path.clone(),
+      size: contents.len() as u64,
+      sha256: sha256(contents),
+    }).collect(),
+  }
+}
+
+fn write_archive(archive: &Path, files: &[(String, Vec<u8>)], manifest: &Manifest, passphrase: Option<&str>) -> io::Result<()> {
+  let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+  let manifest = serde_json::to_vec_pretty(manifest)?;
+
+  for (path, contents) in files.iter().map(|(path, contents)| (path.as_str(), contents)).chain(once((MANIFEST, &manifest))) {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, path, contents.as_slice())?;
+  }
+
+  let mut contents = builder.into_inner()?.finish()?;
+  if let Some(passphrase) = passphrase {
+    contents = crypto::seal(passphrase, &contents)?;
+  }
+
+  let mut file = File::create(archive)?;
+  file.write_all(&contents)?;
+  file.sync_all()
+}
+
+// Returns the gzipped tarball, decrypting it first if needed
+fn read_archive(archive: &Path, passphrase: Option<&str>) -> io::Result<Vec<u8>> {
+  let contents = fs::read(archive)?;
+  if !crypto::is_sealed(&contents) {
+    return Ok(contents);
+  }
+
+  match passphrase {
+    Some(passphrase) => crypto::open(passphrase, &contents),
+    None => Err(io::Error::new(io::ErrorKind::InvalidData, "Backup is encrypted but BACKUP_PASSPHRASE is not set")),
+  }
+}
+
+// Re-reads the whole archive and checks every file against the hashes in its manifest
+pub fn verify(env: &EnvVars, archive: &Path) -> io::Result<Manifest> {
+  let mut hashes = HashMap::new();
+  let mut manifest = None;
+
+  let contents = read_archive(archive, env.backup_passphrase.as_deref())?;
+  for entry in tar::Archive::new(GzDecoder::new(contents.as_slice())).entries()? {
+    let mut entry = entry?;
+    let path = entry.path()?.to_string_lossy().into_owned();
+    let mut contents = Vec::new();
+    entry.read_to_end(&mut contents)?;
+
+    if path == MANIFEST {
+      manifest = Some(serde_json::from_slice::<Manifest>(&contents)?);
+    } else {
+      hashes.insert(path, sha256(&contents));
+    }
+  }
+
+  let manifest = manifest.ok_or(io::Error::new(io::ErrorKind::InvalidData, "Archive has no manifest"))?;
+  for file in &manifest.files {
+    if hashes.remove(&file.path).as_ref() != Some(&file.sha256) {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Checksum mismatch for {}", file.path)));
+    }
+  }
+
+  if let Some(path) = hashes.keys().next() {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is missing from the manifest", path)));
+  }
+
+  Ok(manifest)
+}
+
+// Only plain archive names from the backups directory are accepted, never paths
+pub fn archive_path(path: &str, name: &str) -> io::Result<PathBuf> {
+  if !is_archive(name) || name.contains(['/', '\\']) || name.starts_with('.') {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid backup name"));
+  }
+
+  let archive = PathBuf::from(backups_path(path)).join(name);
+  if !archive.is_file() {
+    return Err(io::Error::new(io::ErrorKind::NotFound, "Backup not found"));
+  }
+
+  Ok(archive)
+}
+
+// Record counts never change for a given archive, so they're only computed once
+fn counts() -> &'static Mutex<HashMap<String, (usize, usize)>> {
+  static COUNTS: OnceLock<Mutex<HashMap<String, (usize, usize)>>> = OnceLock::new();
+  COUNTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn list_backups(env: &EnvVars) -> io::Result<Vec<BackupInfo>> {
+  let mut backups = Vec::new();
+  for entry in fs::read_dir(backups_path(&env.data_dir))? {
+    let entry = entry?;
+    let name = entry.file_name().to_string_lossy().into_owned();
+    if !is_archive(&name) || !entry.file_type()?.is_file() {
+      continue;
+    }
+
+    let metadata = entry.metadata()?;
+    let created_at = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+    let cached = counts().lock().unwrap().get(&name).copied();
+    let count = match cached {
+      Some(count) => Some(count),
+      None => match load_backup(env, &name) {
+        Ok(snapshot) => {
+          let count = (snapshot.patients.len(), snapshot.sessions.len());
+          counts().lock().unwrap().insert(name.clone(), count);
+          Some(count)
         },
         Err(err) => {
-          error!("Failed to create backup: {}", err);
+          error!("Couldn't read backup {}: {}", name, err);
+          None
         },
-        _ => {},
-      };
-      
-      let mut backups = fs::read_dir(&backups_path).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
-      backups.sort_by(|a, b| b.metadata().unwrap().modified().unwrap().cmp(&a.metadata().unwrap().modified().unwrap()));
-
-      while backups.len() > BACKUP_HISTORY {
-        let backup = backups.pop().unwrap();
-        if let Err(err) = fs::remove_file(backup) {
-          error!("Failed to remove backup: {}", err);
+      },
+    };
+
+    backups.push(BackupInfo {
+      name,
+      size: metadata.len(),
+      created_at,
+      patients: count.map(|(patients, _)| patients),
+      sessions: count.map(|(_, sessions)| sessions),
+    });
+  }
+
+  backups.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+  Ok(backups)
+}
+
+// Unpacks the archive into a scratch directory and reads it with the storage layer,
+// so migrations and quarantine apply to old backups the same way they do to live data
+pub fn load_backup(env: &EnvVars, name: &str) -> io::Result<Snapshot> {
+  let archive = archive_path(&env.data_dir, name)?;
+  let scratch = std::env::temp_dir().join(format!("dashboard-restore-{}", Uuid::new_v4()));
+
+  let result = (|| {
+    tar::Archive::new(GzDecoder::new(read_archive(&archive, env.backup_passphrase.as_deref())?.as_slice())).unpack(&scratch)?;
+
+    let backup = storage::open_dir(&format!("{}/", scratch.display()))?;
+    Ok(Snapshot {
+      patients: backup.load_patients()?,
+      sessions: backup.load_sessions()?,
+    })
+  })();
+
+  if let Err(err) = fs::remove_dir_all(&scratch) {
+    warning!("Couldn't remove scratch directory {}: {}", scratch.display(), err);
+  }
+
+  result
+}
+
+fn same<T: Serialize>(a: &T, b: &T) -> bool {
+  serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+fn changes<T: Audited>(live: &[&T], backup: &[T]) -> Changes {
+  let mut changes = Changes::default();
+  for record in backup {
+    match live.iter().find(|l| l.uuid() == record.uuid()) {
+      Some(l) if !same(*l, record) => changes.changed.push(record.uuid().to_owned()),
+      Some(_) => {},
+      None => changes.restored.push(record.uuid().to_owned()),
+    }
+  }
+
+  for record in live {
+    if !backup.iter().any(|b| b.uuid() == record.uuid()) {
+      changes.removed.push(record.uuid().to_owned());
+    }
+  }
+
+  changes
+}
+
+// What restoring the snapshot would do to the live data, optionally limited to one patient
+pub fn diff(state: &State, backup: &Snapshot, patient: Option<&str>) -> Diff {
+  let patients = state.patients.iter().filter(|p| patient.is_none_or(|uuid| p.uuid == uuid)).collect::<Vec<_>>();
+  let sessions = state.sessions.iter().filter(|s| patient.is_none_or(|uuid| s.patient_uuid == uuid)).collect::<Vec<_>>();
+
+  Diff {
+    patients: changes(&patients, &backup.patients),
+    sessions: changes(&sessions, &backup.sessions),
+  }
+}
+
+// Replaces the live records (all of them, or a single patient's) with the snapshot, writing,
+// auditing and broadcasting every change so connected clients end up with the restored data.
+// The whole restore is recorded before anything is touched, nothing changes if that fails.
+pub async fn restore(state: &mut State, actor: &str, mut backup: Snapshot, patient: Option<&str>) -> io::Result<()> {
+  let storage = Arc::clone(&state.storage);
+  let in_scope = |uuid: &str| patient.is_none_or(|p| p == uuid);
+
+  // Live sessions keep the events that exist now. Events of sessions that come back were deleted
+  // with them, so like restoring from the trash they're created again in the calendars that may show them.
+  let mut calendars = HashMap::new();
+  for patient in backup.patients.iter().chain(state.patients.iter()) {
+    if !calendars.contains_key(&patient.uuid) {
+      calendars.insert(patient.uuid.clone(), state.calendars(patient).await);
+    }
+  }
+
+  for session in backup.sessions.iter_mut() {
+    match state.sessions.iter().find(|s| s.uuid == session.uuid) {
+      Some(live) => session.calendar_ids = live.calendar_ids.clone(),
+      None if !session.calendar_ids.is_empty() => {
+        let emails = calendars.get(&session.patient_uuid).map_or(&[][..], Vec::as_slice);
+        session.calendar_ids = emails.iter().map(|email| (email.clone(), jobs::event_id())).collect();
+      },
+      None => {},
+    }
+  }
+
+  let mut batch = audit::Batch::new(actor, Source::Api);
+  for session in state.sessions.iter().filter(|s| in_scope(&s.patient_uuid) && !backup.sessions.iter().any(|b| b.uuid == s.uuid)) {
+    batch.deleted(session);
+  }
+
+  for patient in state.patients.iter().filter(|p| in_scope(&p.uuid) && !backup.patients.iter().any(|b| b.uuid == p.uuid)) {
+    batch.deleted(patient);
+  }
+
+  for patient in &backup.patients {
+    match state.patients.iter().find(|p| p.uuid == patient.uuid) {
+      Some(current) if same(current, patient) => {},
+      Some(current) => { batch.updated(current, patient); },
+      None => { batch.restored(patient); },
+    }
+  }
+
+  for session in &backup.sessions {
+    match state.sessions.iter().find(|s| s.uuid == session.uuid) {
+      Some(current) if same(current, session) => {},
+      Some(current) => { batch.updated(current, session); },
+      None => { batch.restored(session); },
+    }
+  }
+
+  batch.commit(&storage).await?;
+
+  let sessions = state.sessions.drain_with(|s| in_scope(&s.patient_uuid) && !backup.sessions.iter().any(|b| b.uuid == s.uuid));
+  for session in sessions {
+    for (email, id) in &session.calendar_ids {
+      jobs::delete_event(&storage, email, id);
+    }
+
+    session.delete(&storage);
+    state.broadcast(SseEvent::SessionRemoved(&session.uuid)).await;
+  }
+
+  let patients = state.patients.drain_with(|p| in_scope(&p.uuid) && !backup.patients.iter().any(|b| b.uuid == p.uuid));
+  for patient in patients {
+    patient.delete(&storage);
+    state.broadcast(SseEvent::PatientRemoved(&patient.uuid)).await;
+  }
+
+  // Event titles and descriptions come from the patient, so its sessions' events are edited too
+  let mut edited = Vec::new();
+  for patient in backup.patients {
+    match state.patients.iter().position(|p| p.uuid == patient.uuid) {
+      Some(index) if same(&state.patients[index], &patient) => {},
+      Some(index) => {
+        edited.extend(state.sessions.iter().filter(|s| s.patient_uuid == patient.uuid).map(|s| s.uuid.clone()));
+        patient.write(&storage);
+        state.broadcast(SseEvent::PatientUpdated(&patient)).await;
+        state.patients[index] = patient;
+      },
+      None => {
+        patient.write(&storage);
+        state.broadcast(SseEvent::PatientAdded(&patient)).await;
+        state.patients.push(patient);
+      },
+    }
+  }
+
+  for session in backup.sessions {
+    match state.sessions.iter().position(|s| s.uuid == session.uuid) {
+      Some(index) if same(&state.sessions[index], &session) => {},
+      Some(index) => {
+        edited.push(session.uuid.clone());
+        session.write(&storage);
+        state.broadcast(SseEvent::SessionUpdated(&session)).await;
+        state.sessions[index] = session;
+      },
+      None => {
+        for (email, id) in &session.calendar_ids {
+          jobs::create_event(&storage, email, &session.uuid, id);
         }
-      }
+
+        session.write(&storage);
+        state.broadcast(SseEvent::SessionAdded(&session)).await;
+        state.sessions.push(session);
+      },
     }
-  });
+  }
+
+  edited.sort();
+  edited.dedup();
+  for session in state.sessions.iter().filter(|s| edited.contains(&s.uuid)) {
+    for (email, id) in &session.calendar_ids {
+      jobs::edit_event(&storage, email, &session.uuid, id);
+    }
+  }
+
+  // Anything brought back from the backup shouldn't also be restorable from the trash
+  let live = |uuid: &str| state.patients.iter().any(|p| p.uuid == uuid) || state.sessions.iter().any(|s| s.uuid == uuid);
+  let trashed = state.trash.iter().filter(|item| live(&item.uuid)).map(|item| item.uuid.clone()).collect::<Vec<_>>();
+  for uuid in trashed {
+    let index = state.trash.iter().position(|item| item.uuid == uuid).unwrap();
+    let item = state.trash.remove(index);
+    item.delete(&storage);
+    state.broadcast(SseEvent::TrashRemoved(&item.uuid)).await;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::trash::{TrashItem, Trashed};
+  use crate::storage::{encryption, FsStorage, StorageKind};
+
+  fn scratch() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dashboard-backup-{}", Uuid::new_v4()));
+    fs::create_dir_all(dir.join("backups")).unwrap();
+    dir
+  }
+
+  fn env(dir: &Path, passphrase: Option<&str>) -> EnvVars {
+    EnvVars { data_dir: format!("{}/", dir.display()), backup_passphrase: passphrase.map(str::to_owned), ..EnvVars::default() }
+  }
+
+  fn files() -> Vec<(String, Vec<u8>)> {
+    vec![("patients/p1.json".into(), b"{\"name\":\"Jan\"}".to_vec()), ("state.json".into(), b"{}".to_vec())]
+  }
+
+  // Writes `files` under a manifest that was made for `listed`
+  fn write(dir: &Path, listed: &[(String, Vec<u8>)], files: &[(String, Vec<u8>)], passphrase: Option<&str>) -> PathBuf {
+    let archive = dir.join("backups").join(format!("{}{}", Uuid::new_v4(), BACKUP_EXTENSION));
+    write_archive(&archive, files, &manifest(listed, fingerprint(listed), 0), passphrase).unwrap();
+    archive
+  }
+
+  fn rejected(env: &EnvVars, archive: &Path) -> String {
+    verify(env, archive).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn verifies_an_intact_archive() {
+    let dir = scratch();
+    let archive = write(&dir, &files(), &files(), None);
+
+    let manifest = verify(&env(&dir, None), &archive).unwrap();
+    assert_eq!(manifest.files.iter().map(|file| file.path.as_str()).collect::<Vec<_>>(), ["patients/p1.json", "state.json"]);
+    assert_eq!(manifest.files[1].size, 2);
+    assert_eq!(manifest.fingerprint, fingerprint(&files()));
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn detects_tampering() {
+    let dir = scratch();
+    let env = env(&dir, None);
+
+    let mut changed = files();
+    changed[0].1 = b"{\"name\":\"Anna\"}".to_vec();
+    assert_eq!(rejected(&env, &write(&dir, &files(), &changed, None)), "Checksum mismatch for patients/p1.json");
+
+    let missing = files()[1..].to_vec();
+    assert_eq!(rejected(&env, &write(&dir, &files(), &missing, None)), "Checksum mismatch for patients/p1.json");
+
+    let mut added = files();
+    added.push(("sessions/s1.json".into(), b"{}".to_vec()));
+    assert_eq!(rejected(&env, &write(&dir, &files(), &added, None)), "sessions/s1.json is missing from the manifest");
+
+    // Bit rot anywhere in the archive
+    let archive = write(&dir, &files(), &files(), None);
+    let mut contents = fs::read(&archive).unwrap();
+    let middle = contents.len() / 2;
+    contents[middle] ^= 0xff;
+    fs::write(&archive, contents).unwrap();
+    assert!(verify(&env, &archive).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn encrypted_archives_need_the_passphrase() {
+    let dir = scratch();
+    let archive = write(&dir, &files(), &files(), Some("secret"));
+    assert!(crypto::is_sealed(&fs::read(&archive).unwrap()));
+
+    assert!(verify(&env(&dir, Some("secret")), &archive).is_ok());
+    assert!(verify(&env(&dir, Some("wrong")), &archive).is_err());
+    assert_eq!(rejected(&env(&dir, None), &archive), "Backup is encrypted but BACKUP_PASSPHRASE is not set");
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn encrypted_backups_load_back() {
+    let _ = encryption::init();
+    let dir = scratch();
+    let env = env(&dir, Some("secret"));
+
+    let uuid = Uuid::new_v4().to_string();
+    let storage = FsStorage::new(&env.data_dir).unwrap();
+    storage.save_patient(&Patient { uuid: uuid.clone(), name: "Jan".into(), ..Default::default() }).unwrap();
+
+    let name = create_backup(&env, &storage.backup_paths()).unwrap().unwrap();
+    assert!(name.ends_with(ENCRYPTED_EXTENSION));
+
+    let snapshot = load_backup(&env, &name).unwrap();
+    assert_eq!(snapshot.patients.iter().map(|patient| patient.uuid.as_str()).collect::<Vec<_>>(), [uuid.as_str()]);
+    assert!(load_backup(&EnvVars { backup_passphrase: Some("wrong".into()), ..env.clone() }, &name).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  fn patient(uuid: &str, name: &str) -> Patient {
+    Patient { uuid: uuid.into(), name: name.into(), ..Default::default() }
+  }
+
+  fn session(uuid: &str, patient: &str) -> Session {
+    Session { uuid: uuid.into(), patient_uuid: patient.into(), ..Default::default() }
+  }
+
+  fn uuids<'a>(records: impl IntoIterator<Item = &'a String>) -> Vec<&'a str> {
+    let mut uuids = records.into_iter().map(String::as_str).collect::<Vec<_>>();
+    uuids.sort();
+    uuids
+  }
+
+  // p1 was renamed since the backup, p2 and s3 were added, and p3 and s2 were deleted
+  async fn live() -> (Arc<dyn Storage>, crate::AppState) {
+    let _ = encryption::init();
+    let storage = storage::open(StorageKind::Memory, "").unwrap();
+    [patient("p1", "Jan K"), patient("p2", "Anna")].iter().for_each(|p| storage.save_patient(p).unwrap());
+    [session("s1", "p1"), session("s3", "p2")].iter().for_each(|s| storage.save_session(s).unwrap());
+    let state = State::for_tests(Arc::clone(&storage)).unwrap();
+
+    let mut app_state = state.write().await;
+    app_state.move_to_trash(TrashItem::new(Trashed::Patient { patient: patient("p3", "Ewa"), sessions: Vec::new() }, "a@b.c")).await;
+    drop(app_state);
+
+    (storage, state)
+  }
+
+  fn backup() -> Snapshot {
+    Snapshot {
+      patients: vec![patient("p1", "Jan"), patient("p3", "Ewa")],
+      sessions: vec![session("s1", "p1"), session("s2", "p1")],
+    }
+  }
+
+  #[actix_web::test]
+  async fn diffs_against_the_live_data() {
+    let (_, state) = live().await;
+    let state = state.read().await;
+
+    let diff = super::diff(&state, &backup(), None);
+    assert_eq!((diff.patients.restored, diff.patients.changed, diff.patients.removed), (vec!["p3".to_owned()], vec!["p1".to_owned()], vec!["p2".to_owned()]));
+    assert_eq!((diff.sessions.restored, diff.sessions.changed, diff.sessions.removed), (vec!["s2".to_owned()], vec![], vec!["s3".to_owned()]));
+
+    let diff = super::diff(&state, &backup().only("p1"), Some("p1"));
+    assert!(diff.patients.removed.is_empty() && diff.sessions.removed.is_empty());
+    assert_eq!(diff.sessions.restored, ["s2"]);
+  }
+
+  #[actix_web::test]
+  async fn restores_everything_to_the_backup() {
+    let (storage, state) = live().await;
+    let mut app_state = state.write().await;
+
+    super::restore(&mut app_state, "a@b.c", backup(), None).await.unwrap();
+    storage::run(|| Ok(())).await.unwrap();
+
+    assert_eq!(uuids(app_state.patients.iter().map(|p| &p.uuid)), ["p1", "p3"]);
+    assert_eq!(app_state.patients.iter().find(|p| p.uuid == "p1").unwrap().name, "Jan");
+    assert_eq!(uuids(app_state.sessions.iter().map(|s| &s.uuid)), ["s1", "s2"]);
+    // p3 came back from the backup, so it's no longer in the trash
+    assert!(app_state.trash.is_empty());
+
+    assert_eq!(uuids(storage.load_patients().unwrap().iter().map(|p| &p.uuid)), ["p1", "p3"]);
+    assert_eq!(uuids(storage.load_sessions().unwrap().iter().map(|s| &s.uuid)), ["s1", "s2"]);
+    assert!(storage.load_trash().unwrap().is_empty());
+
+    // Every change is on record, the unchanged s1 isn't
+    let audited = storage.load_audit(None, 20, &|entry| entry.actor == "a@b.c").unwrap().entries;
+    assert_eq!(uuids(audited.iter().map(|entry| &entry.uuid)), ["p1", "p2", "p3", "s2", "s3"]);
+  }
+
+  #[actix_web::test]
+  async fn restores_a_single_patient() {
+    let (_, state) = live().await;
+    let mut app_state = state.write().await;
+
+    super::restore(&mut app_state, "a@b.c", backup().only("p1"), Some("p1")).await.unwrap();
+
+    assert_eq!(uuids(app_state.patients.iter().map(|p| &p.uuid)), ["p1", "p2"]);
+    assert_eq!(app_state.patients.iter().find(|p| p.uuid == "p1").unwrap().name, "Jan");
+    assert_eq!(uuids(app_state.sessions.iter().map(|s| &s.uuid)), ["s1", "s2", "s3"]);
+    assert_eq!(app_state.trash.len(), 1);
+  }
 }
@@ -0,0 +1,446 @@
+use crate::acme;
+use crate::headers::{self, SecurityHeaders};
+use crate::ratelimit::Limits;
+use crate::replication::{Replica, Target};
+use crate::state::user::Role;
+use crate::storage::StorageKind;
+use crate::tokens;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+// Everything that differs between deployments. Read from the TOML file named by CONFIG (config.toml by default,
+// optional), then overridden by the environment variables of the same meaning, e.g.
+//
+//   production = true
+//   data_dir = "/srv/practice/"
+//   public_url = "https://entitia.com"
+//   domains = ["entitia.com", "entitia.pl"]
+//   time_zone = "Europe/Warsaw"
+//
+//   [server]
+//   port = 443
+//
+//   [backup]
+//   passphrase = "..."
+//
+//   [replica]
+//   s3_bucket = "practice-backups"
+//
+//   [rate_limit]
+//   max_failures = 5
+//
+//   [headers]
+//   hsts_max_age = 31536000
+//
+//   [acme]
+//   directory = "https://acme-v02.api.letsencrypt.org/directory"
+#[derive(Debug, Clone)]
+pub struct EnvVars {
+  pub is_production: bool,
+  // Where state, patients, backups, logs and certificates are kept, always ends with a slash
+  pub data_dir: String,
+  // Where the dashboard is reachable from the outside, used for Google webhooks and PDF rendering
+  pub public_url: String,
+  pub domains: Vec<String>,
+  // IANA name used for calendar events
+  pub time_zone: String,
+  // Google OAuth client JSON, GOOGLE_CLIENT_ID and GOOGLE_CLIENT_SECRET or Docker secrets can be used instead
+  pub secrets_file: Option<String>,
+  pub users: Vec<(String, Role)>,
+  pub allowed_origins: Vec<String>,
+  pub inner_port: u16,
+  pub dev_port: u16,
+  pub http_port: Option<u16>,
+  pub storage: StorageKind,
+  pub backup_interval: u64,
+  pub backup_retention: usize,
+  // Archives are encrypted whenever it's set, it's also needed to read them back
+  pub backup_passphrase: Option<String>,
+  // Where backups are copied off-site, if anywhere
+  pub replica: Option<Replica>,
+  pub rate_limits: Limits,
+  // CSP, HSTS and the like, sent with every response
+  pub headers: headers::Config,
+  // Certificates are only requested when there's a directory to request them from
+  pub acme: Option<acme::Config>,
+  // Random bytes in access tokens, SSE tokens and OAuth hand-off codes
+  pub token_bytes: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+  production: Option<bool>,
+  data_dir: Option<String>,
+  public_url: Option<String>,
+  domains: Option<Vec<String>>,
+  time_zone: Option<String>,
+  secrets_file: Option<String>,
+  users: Option<Vec<String>>,
+  allowed_origins: Option<Vec<String>>,
+  token_bytes: Option<usize>,
+  server: ServerFile,
+  storage: StorageFile,
+  backup: BackupFile,
+  replica: ReplicaFile,
+  rate_limit: RateLimitFile,
+  headers: HeadersFile,
+  acme: AcmeFile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerFile {
+  port: Option<u16>,
+  dev_port: Option<u16>,
+  http_port: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageFile {
+  kind: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BackupFile {
+  // Minutes between backups
+  interval: Option<u64>,
+  retention: Option<usize>,
+  passphrase: Option<String>,
+}
+
+// A mounted directory or an S3-compatible bucket
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReplicaFile {
+  dir: Option<String>,
+  retention: Option<usize>,
+  s3_bucket: Option<String>,
+  s3_endpoint: Option<String>,
+  s3_region: Option<String>,
+  s3_prefix: Option<String>,
+  s3_access_key: Option<String>,
+  s3_secret_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitFile {
+  // Requests per minute for each IP on each route
+  requests: Option<u32>,
+  auth_requests: Option<u32>,
+  // Failed attempts per minute before an IP gets locked out
+  max_failures: Option<u32>,
+  lockout_minutes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeadersFile {
+  csp: Option<String>,
+  // Seconds, 0 turns HSTS off
+  hsts_max_age: Option<u64>,
+  frame_options: Option<String>,
+  referrer_policy: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AcmeFile {
+  directory: Option<String>,
+  email: Option<String>,
+  // Accepts a self-signed directory like Pebble's
+  insecure: Option<bool>,
+  renew_days: Option<u32>,
+}
+
+fn invalid(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// The environment variable wins over the file, which wins over the default
+fn pick<T: FromStr>(var: &str, file: Option<T>, default: T) -> io::Result<T>
+where
+  T::Err: std::fmt::Display,
+{
+  match env::var(var) {
+    Ok(value) => value.trim().parse().map_err(|err| invalid(format!("{} = {:?} is invalid: {}", var, value, err))),
+    Err(_) => Ok(file.unwrap_or(default)),
+  }
+}
+
+fn pick_optional(var: &str, file: Option<String>) -> Option<String> {
+  env::var(var).ok().or(file).filter(|value| !value.is_empty())
+}
+
+fn pick_list(var: &str, file: Option<Vec<String>>, default: &[&str]) -> Vec<String> {
+  match env::var(var) {
+    Ok(value) => value.split_whitespace().map(str::to_owned).collect(),
+    Err(_) => file.unwrap_or_else(|| default.iter().map(|item| item.to_string()).collect()),
+  }
+}
+
+// ACME_DIRECTORY turns it on
+fn acme(file: AcmeFile) -> io::Result<Option<acme::Config>> {
+  let directory = match pick_optional("ACME_DIRECTORY", file.directory) {
+    Some(directory) => directory,
+    None => return Ok(None),
+  };
+
+  Ok(Some(acme::Config {
+    directory,
+    email: pick_optional("ACME_EMAIL", file.email),
+    insecure: pick("ACME_INSECURE", file.insecure, false)?,
+    renew_days: pick("ACME_RENEW_DAYS", file.renew_days, 30)?,
+  }))
+}
+
+// REPLICA_DIR wins over REPLICA_S3_BUCKET, without either nothing is replicated
+fn replica(file: ReplicaFile) -> io::Result<Option<Replica>> {
+  let target = match (pick_optional("REPLICA_DIR", file.dir), pick_optional("REPLICA_S3_BUCKET", file.s3_bucket)) {
+    (Some(dir), _) => Target::Dir(dir),
+    (None, Some(bucket)) => Target::S3 {
+      endpoint: pick("REPLICA_S3_ENDPOINT", file.s3_endpoint, "https://s3.amazonaws.com".into())?.trim_end_matches('/').to_owned(),
+      region: pick("REPLICA_S3_REGION", file.s3_region, "us-east-1".into())?,
+      bucket,
+      prefix: pick("REPLICA_S3_PREFIX", file.s3_prefix, String::new())?,
+      access_key: pick("REPLICA_S3_ACCESS_KEY", file.s3_access_key, String::new())?,
+      secret_key: pick("REPLICA_S3_SECRET_KEY", file.s3_secret_key, String::new())?,
+    },
+    (None, None) => return Ok(None),
+  };
+
+  // 2 weeks of half-hourly backups
+  let retention = pick("REPLICA_RETENTION", file.retention, 24 * 2 * 14)?;
+  Ok(Some(Replica { target, retention }))
+}
+
+impl EnvVars {
+  pub fn load() -> io::Result<Self> {
+    let path = env::var("CONFIG").ok();
+    let file = match (&path, Path::new(path.as_deref().unwrap_or("config.toml"))) {
+      (_, file) if file.exists() => {
+        let contents = fs::read_to_string(file)?;
+        toml::from_str::<File>(&contents).map_err(|err| invalid(format!("{}: {}", file.display(), err)))?
+      },
+      (Some(path), _) => return Err(invalid(format!("CONFIG points to {}, which doesn't exist", path))),
+      (None, _) => File::default(),
+    };
+
+    Self::from_file(file)
+  }
+
+  fn from_file(file: File) -> io::Result<Self> {
+    let is_production = pick("PRODUCTION", file.production, false)?;
+    let users = pick_list("USERS", file.users, &[]).iter().map(|entry| match entry.split_once(':') {
+      Some((email, role)) => role.parse().map(|role| (email.to_owned(), role)).map_err(|err| invalid(format!("users: {}", err))),
+      None => Ok((entry.to_owned(), Role::Owner)),
+    }).collect::<io::Result<Vec<_>>>()?;
+
+    let env_vars = EnvVars {
+      is_production,
+      data_dir: pick("FS", file.data_dir, "/root/".into())?,
+      public_url: pick("PUBLIC_URL", file.public_url, "https://entitia.com".into())?.trim_end_matches('/').to_owned(),
+      domains: pick_list("DOMAINS", file.domains, &["entitia.com", "entitia.pl"]).iter().map(|domain| domain.to_lowercase()).collect(),
+      time_zone: pick("TIME_ZONE", file.time_zone, "Europe/Warsaw".into())?,
+      secrets_file: env::var("SECRETS_FILE").ok().or(file.secrets_file),
+      users,
+      allowed_origins: pick_list("ALLOWED_ORIGINS", file.allowed_origins, &[]),
+      inner_port: pick("INNER_PORT", file.server.port, 2137)?,
+      dev_port: pick("DEV_PORT", file.server.dev_port, 5173)?,
+      http_port: env::var("HTTP_PORT").ok().map(|port| port.parse().map_err(|err| invalid(format!("HTTP_PORT = {:?} is invalid: {}", port, err)))).transpose()?.or(file.server.http_port),
+      storage: pick("STORAGE", file.storage.kind, "fs".into())?.parse().map_err(invalid)?,
+      backup_interval: pick("BACKUP_INTERVAL", file.backup.interval, 30)?,
+      backup_retention: pick("BACKUP_RETENTION", file.backup.retention, 144)?, // 3 days of half-hourly backups
+      backup_passphrase: pick_optional("BACKUP_PASSPHRASE", file.backup.passphrase),
+      replica: replica(file.replica)?,
+      rate_limits: Limits {
+        requests: pick("RATE_LIMIT", file.rate_limit.requests, 300)?,
+        auth_requests: pick("AUTH_RATE_LIMIT", file.rate_limit.auth_requests, 20)?,
+        failures: pick("MAX_FAILURES", file.rate_limit.max_failures, 5)?,
+        lockout: pick("LOCKOUT_MINUTES", file.rate_limit.lockout_minutes, 15)? * 60,
+      },
+      headers: headers::Config {
+        csp: pick("CSP", file.headers.csp, headers::CSP.into())?,
+        hsts_max_age: pick("HSTS_MAX_AGE", file.headers.hsts_max_age, headers::HSTS_MAX_AGE)?,
+        frame_options: pick("FRAME_OPTIONS", file.headers.frame_options, "DENY".into())?,
+        referrer_policy: pick("REFERRER_POLICY", file.headers.referrer_policy, "no-referrer".into())?,
+      },
+      acme: acme(file.acme)?,
+      token_bytes: pick("TOKEN_BYTES", file.token_bytes, tokens::DEFAULT_BYTES)?,
+    };
+
+    env_vars.validate()
+  }
+
+  fn validate(mut self) -> io::Result<Self> {
+    if self.data_dir.trim().is_empty() {
+      return Err(invalid("data_dir can't be empty".into()));
+    }
+
+    if Path::new(&self.data_dir).exists() && !Path::new(&self.data_dir).is_dir() {
+      return Err(invalid(format!("data_dir {} isn't a directory", self.data_dir)));
+    }
+
+    if !self.data_dir.ends_with('/') {
+      self.data_dir.push('/');
+    }
+
+    if !self.public_url.starts_with("https://") && !self.public_url.starts_with("http://") {
+      return Err(invalid(format!("public_url must start with https:// or http://, got {:?}", self.public_url)));
+    }
+
+    if self.is_production && self.domains.is_empty() {
+      return Err(invalid("domains can't be empty in production, certificates are looked up by domain".into()));
+    }
+
+    if let Some(domain) = self.domains.iter().find(|domain| domain.is_empty() || domain.contains(['/', ':', ' '])) {
+      return Err(invalid(format!("domains must be bare host names, got {:?}", domain)));
+    }
+
+    // There's no time zone database to check against, but an obvious typo is better caught here than by Google
+    if self.time_zone != "UTC" && !self.time_zone.contains('/') {
+      return Err(invalid(format!("time_zone must be an IANA name like Europe/Warsaw, got {:?}", self.time_zone)));
+    }
+
+    if let Some(file) = &self.secrets_file && !Path::new(file).is_file() {
+      return Err(invalid(format!("secrets_file {} doesn't exist", file)));
+    }
+
+    if self.inner_port == 0 || self.dev_port == 0 || self.http_port == Some(0) {
+      return Err(invalid("ports must be between 1 and 65535".into()));
+    }
+
+    if self.http_port == Some(self.inner_port) {
+      return Err(invalid(format!("http_port and port can't both be {}", self.inner_port)));
+    }
+
+    SecurityHeaders::new(&self.headers, self.is_production)?;
+
+    if self.token_bytes < tokens::MIN_BYTES {
+      return Err(invalid(format!("token_bytes must be at least {}, got {}", tokens::MIN_BYTES, self.token_bytes)));
+    }
+
+    if let Some(acme) = &self.acme {
+      if !acme.directory.starts_with("https://") && !acme.directory.starts_with("http://") {
+        return Err(invalid(format!("acme.directory must be a URL, got {:?}", acme.directory)));
+      }
+
+      if acme.renew_days == 0 {
+        return Err(invalid("acme.renew_days must be at least 1".into()));
+      }
+    }
+
+    Ok(self)
+  }
+
+  // Where the server itself can be reached, the dev server talks to it on localhost
+  pub fn base_url(&self) -> String {
+    match self.is_production {
+      true => self.public_url.clone(),
+      false => format!("http://localhost:{}", self.inner_port),
+    }
+  }
+
+  // OAuth redirects go back to the domain the user came from, as long as it's one of ours
+  pub fn origin(&self, host: &str) -> String {
+    let host = host.strip_prefix("www.").unwrap_or(host).to_lowercase();
+    match self.is_production && self.domains.contains(&host) {
+      true => format!("https://{}", host),
+      false => self.base_url(),
+    }
+  }
+}
+
+// Built-in defaults plus whatever the environment sets, for tests that need a State
+#[cfg(test)]
+impl Default for EnvVars {
+  fn default() -> Self {
+    EnvVars::from_file(File::default()).unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn load(src: &str) -> io::Result<EnvVars> {
+    EnvVars::from_file(toml::from_str(src).unwrap())
+  }
+
+  fn rejected(src: &str) -> String {
+    load(src).unwrap_err().to_string()
+  }
+
+  #[test]
+  fn rejects_unknown_keys() {
+    let err = toml::from_str::<File>("[server]\nprot = 80").unwrap_err().to_string();
+    assert!(err.contains("unknown field `prot`"), "{}", err);
+  }
+
+  #[test]
+  fn reads_the_file() {
+    let env = load(r#"
+      data_dir = "/srv/second"
+      domains = ["Second.example"]
+      time_zone = "America/New_York"
+
+      [server]
+      port = 8443
+      http_port = 80
+    "#).unwrap();
+
+    assert_eq!(env.data_dir, "/srv/second/");
+    assert_eq!(env.domains, ["second.example"]);
+    assert_eq!(env.time_zone, "America/New_York");
+    assert_eq!((env.inner_port, env.http_port), (8443, Some(80)));
+  }
+
+  #[test]
+  fn defaults_to_warsaw() {
+    assert_eq!(load("").unwrap().time_zone, "Europe/Warsaw");
+  }
+
+  #[test]
+  fn rejects_bad_time_zones() {
+    assert!(rejected(r#"time_zone = "CEST""#).contains("time_zone"));
+    assert_eq!(load(r#"time_zone = "UTC""#).unwrap().time_zone, "UTC");
+  }
+
+  #[test]
+  fn rejects_bad_ports() {
+    assert!(rejected("[server]\nport = 0").contains("ports must be"));
+    assert!(rejected("[server]\nport = 80\nhttp_port = 80").contains("http_port and port"));
+    assert!(toml::from_str::<File>("[server]\nport = 70000").is_err());
+  }
+
+  #[test]
+  fn rejects_a_missing_data_dir() {
+    assert!(rejected(r#"data_dir = """#).contains("data_dir can't be empty"));
+    assert!(rejected(r#"data_dir = "Cargo.toml""#).contains("isn't a directory"));
+  }
+
+  #[test]
+  fn environment_wins_over_the_file() {
+    // A name nothing else reads, tests run in parallel
+    let var = "CONFIG_TEST_PRECEDENCE";
+    assert_eq!(pick(var, Some(2), 1).unwrap(), 2);
+    assert_eq!(pick(var, None, 1).unwrap(), 1);
+
+    env::set_var(var, " 3 ");
+    assert_eq!(pick(var, Some(2), 1).unwrap(), 3);
+    assert_eq!(pick_list(var, Some(vec!["a".into()]), &[]), ["3"]);
+
+    env::set_var(var, "three");
+    assert!(pick(var, Some(2), 1).unwrap_err().to_string().contains("CONFIG_TEST_PRECEDENCE = \"three\" is invalid"));
+    env::remove_var(var);
+  }
+}
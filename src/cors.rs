@@ -1,4 +1,5 @@
 use std::future::{ready, Ready};
+use std::sync::Arc;
 
 use actix_web::body::{EitherBody, MessageBody};
 use actix_web::http::{self, header};
@@ -6,11 +7,28 @@ use actix_web::{Error, HttpResponse};
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
 use futures_util::future::LocalBoxFuture;
 
-const METHODS: &str = "PUT, GET, OPTIONS, DELETE, POST, HEAD, TRACE, CONNECT, PATCH";
-const HEADERS: &str = "content-type, authorization";
+const METHODS: [&str; 5] = ["GET", "POST", "PUT", "PATCH", "DELETE"];
+const HEADERS: [&str; 3] = ["content-type", "authorization", "x-confirm-code"];
+const EXPOSED_HEADERS: &str = "retry-after";
 const MAX_AGE: &str = "3600";
 
-pub struct Cors;
+// Only origins on the list get CORS headers, everyone else is left to the browser's same-origin policy
+#[derive(Clone)]
+pub struct Cors {
+  origins: Arc<Vec<String>>,
+}
+
+impl Cors {
+  pub fn new(origins: Vec<String>) -> Self {
+    Cors {
+      origins: Arc::new(origins.iter().map(|origin| normalize(origin)).collect()),
+    }
+  }
+}
+
+fn normalize(origin: &str) -> String {
+  origin.trim().trim_end_matches('/').to_lowercase()
+}
 
 impl<S, B> Transform<S, ServiceRequest> for Cors
 where
@@ -26,12 +44,34 @@ where
   type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
   fn new_transform(&self, service: S) -> Self::Future {
-    ready(Ok(CorsMiddleware { service }))
+    ready(Ok(CorsMiddleware { service, origins: Arc::clone(&self.origins) }))
   }
 }
 
 pub struct CorsMiddleware<S> {
   service: S,
+  origins: Arc<Vec<String>>,
+}
+
+impl<S> CorsMiddleware<S> {
+  fn allowed_origin(&self, req: &ServiceRequest) -> Option<header::HeaderValue> {
+    let origin = req.headers().get(header::ORIGIN)?.to_str().ok()?;
+    match self.origins.contains(&normalize(origin)) {
+      true => header::HeaderValue::from_str(origin).ok(),
+      false => None,
+    }
+  }
+}
+
+// Every header the preflight asks for has to be on the list
+fn preflight_allowed(req: &ServiceRequest) -> bool {
+  let method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD).and_then(|method| method.to_str().ok()).unwrap_or_default();
+  let headers = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS).and_then(|headers| headers.to_str().ok()).unwrap_or_default();
+
+  METHODS.contains(&method) && headers.split(',')
+    .map(|header| header.trim().to_lowercase())
+    .filter(|header| !header.is_empty())
+    .all(|header| HEADERS.contains(&header.as_str()))
 }
 
 impl<S, B> Service<ServiceRequest> for CorsMiddleware<S>
@@ -48,18 +88,24 @@ where
   forward_ready!(service);
 
   fn call(&self, req: ServiceRequest) -> Self::Future {
-    let origin = req.headers().get("origin").map(|origin| origin.to_str().unwrap_or("")).unwrap_or("*");
-    let origin = origin.trim_end_matches('/').to_owned();
-
-    if req.method() == http::Method::OPTIONS {
-      let mut res = HttpResponse::Ok();
+    let origin = self.allowed_origin(&req);
+    let is_preflight = req.method() == http::Method::OPTIONS
+      && req.headers().contains_key(header::ORIGIN)
+      && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+    if is_preflight {
+      let res = match origin {
+        Some(origin) if preflight_allowed(&req) => HttpResponse::NoContent()
+          .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
+          .insert_header((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"))
+          .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, METHODS.join(", ")))
+          .insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, HEADERS.join(", ")))
+          .insert_header((header::ACCESS_CONTROL_MAX_AGE, MAX_AGE))
+          .insert_header((header::VARY, "Origin"))
+          .finish(),
+        _ => HttpResponse::Forbidden().insert_header((header::VARY, "Origin")).body("Forbidden"),
+      };
 
-      res.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, header::HeaderValue::from_str(&origin).unwrap()));
-      res.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, header::HeaderValue::from_static(METHODS)));
-      res.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, header::HeaderValue::from_static(HEADERS)));
-      res.insert_header((header::ACCESS_CONTROL_MAX_AGE, header::HeaderValue::from_static(MAX_AGE)));
-
-      let res = res.finish();
       return Box::pin(async move {
         Ok(req.into_response(res).map_into_right_body())
       });
@@ -71,12 +117,100 @@ where
       let mut res = fut.await?;
       let headers = res.headers_mut();
 
-      headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, header::HeaderValue::from_str(&origin).unwrap());
-      headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, header::HeaderValue::from_static(METHODS));
-      headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, header::HeaderValue::from_static(HEADERS));
-      headers.insert(header::ACCESS_CONTROL_MAX_AGE, header::HeaderValue::from_static(MAX_AGE));
+      // The response depends on the Origin header, caches must not hand it to another site
+      headers.append(header::VARY, header::HeaderValue::from_static("Origin"));
+      if let Some(origin) = origin {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, header::HeaderValue::from_static("true"));
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, header::HeaderValue::from_static(EXPOSED_HEADERS));
+      }
 
       Ok(res.map_into_left_body())
     })
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use actix_web::dev::ServiceResponse;
+  use actix_web::http::StatusCode;
+  use actix_web::{test, web, App};
+
+  const ALLOWED: &str = "https://entitia.com";
+
+  async fn call(req: test::TestRequest) -> ServiceResponse<impl MessageBody> {
+    let app = test::init_service(
+      App::new()
+        .route("/api/sessions", web::get().to(HttpResponse::Ok))
+        .wrap(Cors::new(vec![format!("{}/", ALLOWED), "http://localhost:5173".into()]))
+    ).await;
+
+    test::call_service(&app, req.to_request()).await
+  }
+
+  fn preflight(origin: &str, method: &str, headers: &str) -> test::TestRequest {
+    test::TestRequest::default()
+      .method(http::Method::OPTIONS)
+      .uri("/api/sessions")
+      .insert_header((header::ORIGIN, origin))
+      .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+      .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, headers))
+  }
+
+  fn header<B>(res: &ServiceResponse<B>, name: header::HeaderName) -> Option<&str> {
+    res.headers().get(name).map(|value| value.to_str().unwrap())
+  }
+
+  #[actix_web::test]
+  async fn preflight_from_an_allowed_origin() {
+    let res = call(preflight(ALLOWED, "DELETE", "Authorization, Content-Type, X-Confirm-Code")).await;
+
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some(ALLOWED));
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+    assert!(header(&res, header::ACCESS_CONTROL_ALLOW_METHODS).unwrap().contains("DELETE"));
+  }
+
+  #[actix_web::test]
+  async fn preflight_from_another_origin_is_rejected() {
+    let res = call(preflight("https://evil.example", "GET", "authorization")).await;
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+  }
+
+  #[actix_web::test]
+  async fn preflight_for_an_unlisted_method_or_header_is_rejected() {
+    assert_eq!(call(preflight(ALLOWED, "TRACE", "")).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(call(preflight(ALLOWED, "GET", "authorization, x-custom")).await.status(), StatusCode::FORBIDDEN);
+  }
+
+  #[actix_web::test]
+  async fn simple_request_from_an_allowed_origin() {
+    let res = call(test::TestRequest::get().uri("/api/sessions").insert_header((header::ORIGIN, "http://localhost:5173"))).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("http://localhost:5173"));
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+    assert_eq!(header(&res, header::VARY), Some("Origin"));
+  }
+
+  #[actix_web::test]
+  async fn simple_request_from_another_origin_gets_no_cors_headers() {
+    let res = call(test::TestRequest::get().uri("/api/sessions").insert_header((header::ORIGIN, "https://evil.example"))).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+  }
+
+  #[actix_web::test]
+  async fn same_origin_request_passes_through() {
+    let res = call(test::TestRequest::get().uri("/api/sessions")).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+  }
+}
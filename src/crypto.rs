@@ -0,0 +1,126 @@
+use std::io;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::XChaCha20Poly1305;
+
+// Sealed blobs look like MAGIC | version | m_cost | t_cost | p_cost | salt | nonce | ciphertext.
+// The KDF parameters travel with the data so they can be raised later without breaking old blobs,
+// and everything before the ciphertext is authenticated along with it.
+const MAGIC: &[u8; 4] = b"DBSL";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 * 3 + SALT_LEN + NONCE_LEN;
+
+// The header isn't authenticated until the key is derived, so a forged one could ask for any
+// amount of memory and time. Well above the defaults `seal` uses, so they can still be raised.
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+
+fn derive_key(passphrase: &str, salt: &[u8], params: Params) -> io::Result<[u8; 32]> {
+  let mut key = [0u8; 32];
+  Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+    .map_err(|err| io::Error::other(format!("Key derivation failed: {}", err)))?;
+
+  Ok(key)
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+  data.starts_with(MAGIC)
+}
+
+pub fn seal(passphrase: &str, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+  let params = Params::default();
+  let mut salt = [0u8; SALT_LEN];
+  OsRng.fill_bytes(&mut salt);
+  let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+  let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+  sealed.extend_from_slice(MAGIC);
+  sealed.push(VERSION);
+  sealed.extend_from_slice(&params.m_cost().to_le_bytes());
+  sealed.extend_from_slice(&params.t_cost().to_le_bytes());
+  sealed.extend_from_slice(&params.p_cost().to_le_bytes());
+  sealed.extend_from_slice(&salt);
+  sealed.extend_from_slice(&nonce);
+
+  let key = derive_key(passphrase, &salt, params)?;
+  let ciphertext = XChaCha20Poly1305::new(&key.into())
+    .encrypt(&nonce, Payload { msg: plaintext, aad: &sealed })
+    .map_err(|_| io::Error::other("Encryption failed"))?;
+
+  sealed.extend_from_slice(&ciphertext);
+  Ok(sealed)
+}
+
+pub fn open(passphrase: &str, sealed: &[u8]) -> io::Result<Vec<u8>> {
+  if !is_sealed(sealed) || sealed.len() < HEADER_LEN {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an encrypted blob"));
+  }
+
+  let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+  if header[4] != VERSION {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported encryption version {}", header[4])));
+  }
+
+  let u32_at = |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+  let (m_cost, t_cost, p_cost) = (u32_at(5), u32_at(9), u32_at(13));
+  if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Key derivation parameters are too expensive ({}, {}, {})", m_cost, t_cost, p_cost)));
+  }
+
+  let params = Params::new(m_cost, t_cost, p_cost, None)
+    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid key derivation parameters: {}", err)))?;
+
+  let salt = &header[17..17 + SALT_LEN];
+  let nonce = &header[17 + SALT_LEN..];
+
+  let key = derive_key(passphrase, salt, params)?;
+  XChaCha20Poly1305::new(&key.into())
+    .decrypt(nonce.into(), Payload { msg: ciphertext, aad: header })
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Decryption failed, wrong passphrase or corrupted data"))
+}
+
+// Records use a raw key instead of a passphrase, deriving one per record would make loading crawl
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+  let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+  let ciphertext = XChaCha20Poly1305::new(key.into())
+    .encrypt(&nonce, Payload { msg: plaintext, aad })
+    .map_err(|_| io::Error::other("Encryption failed"))?;
+
+  Ok((nonce.to_vec(), ciphertext))
+}
+
+pub fn decrypt(key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+  if nonce.len() != NONCE_LEN {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid nonce"));
+  }
+
+  XChaCha20Poly1305::new(key.into())
+    .decrypt(nonce.into(), Payload { msg: ciphertext, aad })
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Decryption failed, record is corrupted"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sealed_data_opens_with_the_passphrase() {
+    let sealed = seal("secret", b"backup").unwrap();
+    assert_eq!(open("secret", &sealed).unwrap(), b"backup");
+    assert!(open("wrong", &sealed).is_err());
+  }
+
+  #[test]
+  fn expensive_headers_are_refused_before_deriving() {
+    let mut sealed = seal("secret", b"backup").unwrap();
+    sealed[5..9].copy_from_slice(&u32::MAX.to_le_bytes());
+    let err = open("secret", &sealed).unwrap_err();
+    assert!(err.to_string().contains("too expensive"));
+  }
+}
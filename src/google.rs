@@ -2,9 +2,10 @@ use crate::logs::*;
 
 use std::sync::OnceLock;
 use std::error::Error;
+use std::fmt;
 
 use chrono::{TimeZone, Utc};
-use reqwest::{Client, ClientBuilder};
+use reqwest::{Client, ClientBuilder, Response};
 use serde::{Serialize, Deserialize};
 
 fn client() -> &'static Client {
@@ -24,7 +25,9 @@ pub struct RawCalendarEvent {
   pub description: Option<String>,
   pub summary: String,
   pub uuid: String,
-  pub colorId: Option<String>
+  pub colorId: Option<String>,
+  // The practice's time zone, so events keep their hour across DST changes
+  pub time_zone: String,
 }
 
 #[derive(Serialize, Debug)]
@@ -45,10 +48,37 @@ struct ErrorBody {
   message: String,
 }
 
-impl From<u64> for Time {
-  fn from(time: u64) -> Self {
+// Keeps the status of a failed call so the job queue can tell a rejected request from one worth retrying
+#[derive(Debug)]
+pub struct ApiError {
+  pub status: u16,
+  pub message: String,
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} ({})", self.message, self.status)
+  }
+}
+
+impl Error for ApiError {}
+
+async fn api_error(resp: Response, action: &str) -> Box<dyn Error> {
+  let status = resp.status().as_u16();
+  let text = match resp.text().await {
+    Ok(text) => text,
+    Err(err) => return err.into(),
+  };
+
+  let message = serde_json::from_str::<ErrorResponse>(&text).map_or(text, |error| error.error.message);
+  error!("Failed to {}: {}, {}", action, message, status);
+  ApiError { status, message }.into()
+}
+
+impl Time {
+  fn new(time: u64, time_zone: &str) -> Self {
     let utc = Utc.timestamp_millis_opt(time as i64 * 1000).unwrap();
-    Time { dateTime: utc.to_rfc3339(), timeZone: "Europe/Warsaw".into() }
+    Time { dateTime: utc.to_rfc3339(), timeZone: time_zone.to_owned() }
   }
 }
 
@@ -87,8 +117,8 @@ async fn add_events_batch(auth: &String, events: &[RawCalendarEvent]) -> Result<
 
     ids.push((event.uuid.clone(), event_id.clone()));
     let event = CreateEvent {
-      start: &event.start.into(),
-      end: &event.end.into(),
+      start: &Time::new(event.start, &event.time_zone),
+      end: &Time::new(event.end, &event.time_zone),
       description: &event.description,
       summary: &event.summary,
       id: &event_id,
@@ -137,12 +167,18 @@ pub async fn add_event(auth: &String, event: &RawCalendarEvent) -> Result<String
   let event_id = Utc::now().timestamp_micros();
   let event_id = format!("{:x}", event_id);
 
+  insert_event(auth, &event_id, event).await?;
+  Ok(event_id)
+}
+
+// Creating the same id twice fails with 409, which makes retries safe
+pub async fn insert_event(auth: &String, event_id: &String, event: &RawCalendarEvent) -> Result<(), Box<dyn Error>> {
   let event = CreateEvent {
-    start: &event.start.into(),
-    end: &event.end.into(),
+    start: &Time::new(event.start, &event.time_zone),
+    end: &Time::new(event.end, &event.time_zone),
     description: &event.description,
     summary: &event.summary,
-    id: &event_id,
+    id: event_id,
     colorId: &event.colorId
   };
 
@@ -156,16 +192,13 @@ pub async fn add_event(auth: &String, event: &RawCalendarEvent) -> Result<String
     .await?;
 
   if resp.status().is_success() {
-    return Ok(event_id);
+    return Ok(());
   }
 
-  let text = resp.text().await?;
-  let error: ErrorResponse = serde_json::from_str(&text)?;
-  error!("Failed to add event: {}, {}", error.error.message, error.error.code);
-  
-  Err(error.error.message.into())
+  Err(api_error(resp, "add event").await)
 }
 
+#[allow(dead_code)]
 pub async fn delete_events(auth: &String, events: &[String]) -> Result<(), Box<dyn Error>> {
   let batches = events.chunks(50);
   for batch in batches {
@@ -175,6 +208,7 @@ pub async fn delete_events(auth: &String, events: &[String]) -> Result<(), Box<d
   Ok(())
 }
 
+#[allow(dead_code)]
 async fn delete_events_batch(auth: &String, events: &[String]) -> Result<(), Box<dyn Error>> {
   let mut batch = String::new();
 
@@ -224,9 +258,28 @@ pub async fn delete_event(auth: &String, event: &String) -> Result<(), Box<dyn E
     return Ok(());
   }
 
+  Err(api_error(resp, "delete event").await)
+}
+
+// Stops Google from calling the webhook, the channel would otherwise live until it expires
+pub async fn stop_channel(auth: &String, id: &String, resource_id: &String) -> Result<(), Box<dyn Error>> {
+  let resp = client()
+    .post("https://www.googleapis.com/calendar/v3/channels/stop")
+    .bearer_auth(auth)
+    .json(&serde_json::json!({
+      "id": id,
+      "resourceId": resource_id,
+    }))
+    .send()
+    .await?;
+
+  if resp.status().is_success() {
+    return Ok(());
+  }
+
   let text = resp.text().await?;
   let error: ErrorResponse = serde_json::from_str(&text)?;
-  error!("Failed to delete event: {}, {}", error.error.message, error.error.code);
+  error!("Failed to stop channel: {}, {}", error.error.message, error.error.code);
   
   Err(error.error.message.into())
 }
@@ -238,9 +291,11 @@ pub struct EditEvent {
   pub description: Option<String>,
   pub summary: String,
   pub id: String,
-  pub colorId: Option<String>
+  pub colorId: Option<String>,
+  pub time_zone: String,
 }
 
+#[allow(dead_code)]
 pub async fn edit_events(auth: &String, events: &[EditEvent]) -> Result<(), Box<dyn Error>> {
   let batches = events.chunks(50);
   for batch in batches {
@@ -250,13 +305,14 @@ pub async fn edit_events(auth: &String, events: &[EditEvent]) -> Result<(), Box<
   Ok(())
 }
 
+#[allow(dead_code)]
 async fn edit_events_batch(auth: &String, events: &[EditEvent]) -> Result<(), Box<dyn Error>> {
   let mut batch = String::new();
 
   for (idx, (session, event)) in events.iter().zip(events.iter()).enumerate() {
     let event = CreateEvent {
-      start: &event.start.into(),
-      end: &event.end.into(),
+      start: &Time::new(event.start, &event.time_zone),
+      end: &Time::new(event.end, &event.time_zone),
       description: &event.description,
       summary: &event.summary,
       id: &event.id,
@@ -304,8 +360,8 @@ async fn edit_events_batch(auth: &String, events: &[EditEvent]) -> Result<(), Bo
 
 pub async fn edit_event(auth: &String, event: &EditEvent) -> Result<(), Box<dyn Error>> {
   let create_event = CreateEvent {
-    start: &event.start.into(),
-    end: &event.end.into(),
+    start: &Time::new(event.start, &event.time_zone),
+    end: &Time::new(event.end, &event.time_zone),
     description: &event.description,
     summary: &event.summary,
     id: &event.id,
@@ -325,11 +381,7 @@ pub async fn edit_event(auth: &String, event: &EditEvent) -> Result<(), Box<dyn
     return Ok(());
   }
 
-  let text = resp.text().await?;
-  let error: ErrorResponse = serde_json::from_str(&text)?;
-  error!("Failed to edit event: {}, {}", error.error.message, error.error.code);
-  
-  Err(error.error.message.into())
+  Err(api_error(resp, "edit event").await)
 }
 
 fn parse_multipart_body(body: &str) -> Vec<u16> {
@@ -0,0 +1,184 @@
+use std::future::{ready, Ready};
+use std::io;
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::Error;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use futures_util::future::LocalBoxFuture;
+
+// The report template carries its styles inline and user pictures come from Google
+pub const CSP: &str = "default-src 'self'; script-src 'self'; style-src 'self' 'unsafe-inline'; img-src 'self' data: https://*.googleusercontent.com; connect-src 'self'; object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'none'";
+pub const HSTS_MAX_AGE: u64 = 2 * 365 * 24 * 60 * 60;
+
+// The header values are checked when the config loads
+#[derive(Debug, Clone)]
+pub struct Config {
+  pub csp: String,
+  // 0 turns HSTS off
+  pub hsts_max_age: u64,
+  pub frame_options: String,
+  pub referrer_policy: String,
+}
+
+struct Policy {
+  csp: HeaderValue,
+  hsts: Option<HeaderValue>,
+  frame_options: HeaderValue,
+  referrer_policy: HeaderValue,
+}
+
+#[derive(Clone)]
+pub struct SecurityHeaders {
+  policy: Arc<Policy>,
+}
+
+impl SecurityHeaders {
+  pub fn new(config: &Config, is_production: bool) -> io::Result<Self> {
+    let header = |name: &str, value: &str| HeaderValue::from_str(value).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid header value: {:?}", name, value)));
+
+    // Browsers remember HSTS for the whole host, so it's never sent while developing on localhost
+    let hsts = (is_production && config.hsts_max_age > 0).then(|| format!("max-age={}; includeSubDomains", config.hsts_max_age));
+
+    Ok(SecurityHeaders {
+      policy: Arc::new(Policy {
+        csp: header("csp", &config.csp)?,
+        hsts: hsts.map(|hsts| header("hsts_max_age", &hsts)).transpose()?,
+        frame_options: header("frame_options", &config.frame_options)?,
+        referrer_policy: header("referrer_policy", &config.referrer_policy)?,
+      }),
+    })
+  }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SecurityHeaders
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+  S::Future: 'static,
+
+  B: MessageBody + 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type InitError = ();
+  type Transform = SecurityHeadersMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(SecurityHeadersMiddleware { service, policy: Arc::clone(&self.policy) }))
+  }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+  service: S,
+  policy: Arc<Policy>,
+}
+
+impl<S, B> Service<ServiceRequest> for SecurityHeadersMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+  S::Future: 'static,
+
+  B: MessageBody + 'static,
+{
+  type Response = ServiceResponse<B>;
+  type Error = Error;
+  type Future = LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let policy = Arc::clone(&self.policy);
+    let fut = self.service.call(req);
+
+    Box::pin(async move {
+      let mut res = fut.await?;
+      let headers = res.headers_mut();
+
+      headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+      if let Some(hsts) = &policy.hsts {
+        headers.insert(header::STRICT_TRANSPORT_SECURITY, hsts.clone());
+      }
+
+      let is_html = headers.get(header::CONTENT_TYPE).and_then(|content_type| content_type.to_str().ok()).is_some_and(|content_type| content_type.starts_with("text/html"));
+      if is_html {
+        headers.insert(header::CONTENT_SECURITY_POLICY, policy.csp.clone());
+        headers.insert(header::X_FRAME_OPTIONS, policy.frame_options.clone());
+        headers.insert(header::REFERRER_POLICY, policy.referrer_policy.clone());
+      }
+
+      // Anything that didn't opt into caching may carry patient data, so it must not be kept by the browser or a proxy
+      if !headers.contains_key(header::CACHE_CONTROL) {
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+      }
+
+      Ok(res)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use actix_web::{web, App, HttpResponse};
+  use actix_web::test::{call_service, init_service, TestRequest};
+
+  fn config() -> Config {
+    Config { csp: CSP.into(), hsts_max_age: HSTS_MAX_AGE, frame_options: "DENY".into(), referrer_policy: "no-referrer".into() }
+  }
+
+  fn value(res: &ServiceResponse, name: header::HeaderName) -> &str {
+    res.headers().get(name).unwrap().to_str().unwrap()
+  }
+
+  async fn get(config: &Config, is_production: bool, path: &str) -> ServiceResponse {
+    let app = init_service(App::new()
+      .wrap(SecurityHeaders::new(config, is_production).unwrap())
+      .route("/page", web::get().to(|| async { HttpResponse::Ok().content_type("text/html; charset=utf-8").body("<p>") }))
+      .route("/data", web::get().to(|| async { HttpResponse::Ok().json(["patient"]) }))
+      .route("/asset", web::get().to(|| async { HttpResponse::Ok().insert_header((header::CACHE_CONTROL, "public, max-age=60")).body("") })),
+    ).await;
+
+    call_service(&app, TestRequest::get().uri(path).to_request()).await
+  }
+
+  #[actix_web::test]
+  async fn pages_get_the_full_policy() {
+    let res = get(&config(), true, "/page").await;
+
+    assert_eq!(value(&res, header::CONTENT_SECURITY_POLICY), CSP);
+    assert_eq!(value(&res, header::X_FRAME_OPTIONS), "DENY");
+    assert_eq!(value(&res, header::REFERRER_POLICY), "no-referrer");
+    assert_eq!(value(&res, header::X_CONTENT_TYPE_OPTIONS), "nosniff");
+    assert_eq!(value(&res, header::STRICT_TRANSPORT_SECURITY), format!("max-age={}; includeSubDomains", HSTS_MAX_AGE));
+    assert_eq!(value(&res, header::CACHE_CONTROL), "no-store");
+  }
+
+  #[actix_web::test]
+  async fn other_responses_only_get_what_applies_to_them() {
+    let res = get(&config(), true, "/data").await;
+    assert!(!res.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+    assert_eq!(value(&res, header::X_CONTENT_TYPE_OPTIONS), "nosniff");
+    assert_eq!(value(&res, header::CACHE_CONTROL), "no-store");
+
+    // Responses that chose how to be cached keep it
+    let res = get(&config(), true, "/asset").await;
+    assert_eq!(value(&res, header::CACHE_CONTROL), "public, max-age=60");
+  }
+
+  #[actix_web::test]
+  async fn hsts_is_only_sent_in_production() {
+    assert!(!get(&config(), false, "/page").await.headers().contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+    let off = Config { hsts_max_age: 0, ..config() };
+    assert!(!get(&off, true, "/page").await.headers().contains_key(header::STRICT_TRANSPORT_SECURITY));
+  }
+
+  #[test]
+  fn rejects_values_that_cant_be_headers() {
+    let err = SecurityHeaders::new(&Config { frame_options: "DENY\nX-Injected: 1".into(), ..config() }, true).err().unwrap();
+    assert!(err.to_string().starts_with("frame_options is not a valid header value"), "{}", err);
+  }
+}
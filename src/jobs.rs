@@ -0,0 +1,444 @@
+use crate::google::{self, ApiError, EditEvent, RawCalendarEvent};
+use crate::state::patient::Patient;
+use crate::state::session::Session;
+use crate::state::state::{SseEvent, State};
+use crate::state::user::User;
+use crate::storage::{self, Storage};
+use crate::{shutdown, AppState};
+use crate::logs::*;
+
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, RwLockReadGuard};
+use tokio::time;
+use uuid::Uuid;
+
+const MAX_ATTEMPTS: u32 = 8;
+const BASE_DELAY: u64 = 30;
+const MAX_DELAY: u64 = 60 * 60;
+const IDLE: Duration = Duration::from_secs(60);
+
+// Create and Edit send the session as it is when the job runs, so they never carry stale data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+  Create { session: String },
+  Edit { session: String },
+  Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+  pub id: String,
+  // Idempotency key, there's at most one job per calendar event and queuing another change merges into it
+  pub key: String,
+  pub email: String,
+  pub event_id: String,
+  pub action: Action,
+  pub attempts: u32,
+  pub next_attempt: u64,
+  pub created_at: u64,
+  pub last_error: Option<String>,
+  // Set once the job gave up, it stays in storage until someone retries it
+  pub failed_at: Option<u64>,
+
+  // Bumped whenever the job changes, a run that raced with a change has to go again
+  #[serde(skip)]
+  revision: u32,
+}
+
+static QUEUE: OnceLock<Mutex<Vec<Job>>> = OnceLock::new();
+static WAKE: Notify = Notify::const_new();
+
+fn queue() -> &'static Mutex<Vec<Job>> {
+  QUEUE.get().expect("Jobs not initialized")
+}
+
+pub fn init(storage: &dyn Storage) -> io::Result<()> {
+  let jobs = storage.load_jobs()?;
+  let failed = jobs.iter().filter(|job| job.failed_at.is_some()).count();
+  info!("Loaded {} calendar jobs, {} of them failed", jobs.len(), failed);
+
+  QUEUE.set(Mutex::new(jobs)).map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "Jobs already initialized"))
+}
+
+// Writes every queued job again, so they end up under the current RECORD_KEY
+pub fn rewrite(storage: &Arc<dyn Storage>) -> usize {
+  let queue = queue().lock().unwrap();
+  for job in queue.iter() {
+    save(storage, job.clone());
+  }
+
+  queue.len()
+}
+
+// The id the event gets in the user's calendar is picked up front, so it can go into calendar_ids
+// (and the audit log) before the job that creates the event is queued
+pub fn event_id() -> String {
+  Uuid::new_v4().simple().to_string()
+}
+
+pub fn create_event(storage: &Arc<dyn Storage>, email: &str, session: &str, event_id: &str) {
+  enqueue(storage, email, event_id, Action::Create { session: session.to_owned() });
+}
+
+pub fn edit_event(storage: &Arc<dyn Storage>, email: &str, session: &str, event_id: &str) {
+  enqueue(storage, email, event_id, Action::Edit { session: session.to_owned() });
+}
+
+pub fn delete_event(storage: &Arc<dyn Storage>, email: &str, event_id: &str) {
+  enqueue(storage, email, event_id, Action::Delete);
+}
+
+fn enqueue(storage: &Arc<dyn Storage>, email: &str, event_id: &str, action: Action) {
+  let key = format!("{}:{}", email, event_id);
+  let now = Utc::now().timestamp() as u64;
+  let mut queue = queue().lock().unwrap();
+
+  let job = match queue.iter_mut().find(|job| job.key == key) {
+    // The event is on its way out, there's nothing left to edit
+    Some(job) if job.action == Action::Delete => return,
+    Some(job) => {
+      if action == Action::Delete {
+        job.action = action;
+      }
+
+      job.attempts = 0;
+      job.next_attempt = now;
+      job.failed_at = None;
+      job.revision += 1;
+      job.clone()
+    },
+    None => {
+      let job = Job {
+        id: Uuid::new_v4().to_string(),
+        key,
+        email: email.to_owned(),
+        event_id: event_id.to_owned(),
+        action,
+        attempts: 0,
+        next_attempt: now,
+        created_at: now,
+        last_error: None,
+        failed_at: None,
+        revision: 0,
+      };
+
+      queue.push(job.clone());
+      job
+    },
+  };
+
+  save(storage, job);
+  drop(queue);
+  WAKE.notify_one();
+}
+
+// Saved while the queue is still locked, so the writes reach storage in the order the changes were made
+fn save(storage: &Arc<dyn Storage>, job: Job) {
+  let storage = Arc::clone(storage);
+  storage::spawn_write(move || {
+    if let Err(err) = storage.save_job(&job) {
+      error!("Couldn't save calendar job {}: {}", job.id, err);
+    }
+  });
+}
+
+pub fn failed() -> Vec<Job> {
+  queue().lock().unwrap().iter().filter(|job| job.failed_at.is_some()).cloned().collect()
+}
+
+// Puts a failed job back in line with a fresh set of attempts
+pub fn retry(storage: &Arc<dyn Storage>, id: &str) -> Option<Job> {
+  let mut queue = queue().lock().unwrap();
+  let job = queue.iter_mut().find(|job| job.id == id && job.failed_at.is_some())?;
+
+  job.attempts = 0;
+  job.next_attempt = Utc::now().timestamp() as u64;
+  job.failed_at = None;
+  job.revision += 1;
+
+  let job = job.clone();
+  save(storage, job.clone());
+  drop(queue);
+  WAKE.notify_one();
+  Some(job)
+}
+
+// The pending job that's due first, or how long to wait for it
+fn next_due() -> Result<Job, Duration> {
+  let now = Utc::now().timestamp() as u64;
+  let queue = queue().lock().unwrap();
+
+  match queue.iter().filter(|job| job.failed_at.is_none()).min_by_key(|job| (job.next_attempt, job.created_at)) {
+    Some(job) if job.next_attempt <= now => Ok(job.clone()),
+    Some(job) => Err(Duration::from_secs(job.next_attempt - now).min(IDLE)),
+    None => Err(IDLE),
+  }
+}
+
+fn backoff(attempts: u32) -> u64 {
+  (BASE_DELAY << attempts.saturating_sub(1).min(16)).min(MAX_DELAY)
+}
+
+pub fn start_worker(state: AppState) {
+  tokio::spawn(async move {
+    info!("Starting calendar job worker...");
+    loop {
+      match next_due() {
+        Ok(job) => run(&state, job).await,
+        Err(wait) => {
+          let _ = time::timeout(wait, WAKE.notified()).await;
+        },
+      }
+    }
+  });
+}
+
+async fn run(state: &AppState, job: Job) {
+  // Shutdown lets the call finish, whatever is still queued is picked up after the restart
+  let _job = shutdown::job();
+  let result = execute(state, &job).await.map_err(|err| (is_retryable(err.as_ref()), err.to_string()));
+
+  let storage = Arc::clone(&state.read().await.storage);
+  if let Some(job) = finish(&storage, &job, result) {
+    state.read().await.broadcast(SseEvent::JobFailed(&job)).await;
+  }
+}
+
+// Records the outcome of a run, returns the job if it just gave up for good
+fn finish(storage: &Arc<dyn Storage>, job: &Job, result: Result<(), (bool, String)>) -> Option<Job> {
+  let mut queue = queue().lock().unwrap();
+  let index = queue.iter().position(|queued| queued.id == job.id)?;
+  if queue[index].revision != job.revision {
+    return None;
+  }
+
+  let (retryable, err) = match result {
+    Ok(()) => {
+      queue.remove(index);
+      let (storage, id) = (Arc::clone(storage), job.id.clone());
+      storage::spawn_write(move || {
+        if let Err(err) = storage.delete_job(&id) {
+          error!("Couldn't remove calendar job {}: {}", id, err);
+        }
+      });
+
+      return None;
+    },
+    Err(err) => err,
+  };
+
+  let now = Utc::now().timestamp() as u64;
+  let queued = &mut queue[index];
+  queued.attempts += 1;
+
+  let failed = !retryable || queued.attempts >= MAX_ATTEMPTS;
+  if failed {
+    queued.failed_at = Some(now);
+    error!("Calendar job {} for {} failed for good after {} attempts: {}", queued.id, queued.email, queued.attempts, err);
+  } else {
+    queued.next_attempt = now + backoff(queued.attempts);
+    warning!("Calendar job {} for {} failed, retrying in {}s: {}", queued.id, queued.email, queued.next_attempt - now, err);
+  }
+
+  queued.last_error = Some(err);
+  save(storage, queued.clone());
+
+  failed.then(|| queued.clone())
+}
+
+// Dropped connections, expired tokens, rate limits and Google's own errors are worth another try, anything else was rejected
+fn is_retryable(err: &(dyn Error + 'static)) -> bool {
+  match err.downcast_ref::<ApiError>() {
+    Some(err) => matches!(err.status, 401 | 408 | 429) || err.status >= 500,
+    None => err.is::<reqwest::Error>(),
+  }
+}
+
+fn status(err: &(dyn Error + 'static)) -> Option<u16> {
+  err.downcast_ref::<ApiError>().map(|err| err.status)
+}
+
+async fn execute(state: &AppState, job: &Job) -> Result<(), Box<dyn Error>> {
+  let app_state = state.read().await;
+  let user = match find_user(&app_state, &job.email).await {
+    Some(user) => user,
+    None => return Err(format!("{} is no longer a user", job.email).into()),
+  };
+  let token = user.access_token.clone();
+
+  // A session that's gone was deleted in the meantime, the delete job takes care of its event.
+  // Neither is anything put in the calendar of someone who can't see the patient (anymore).
+  let event = match &job.action {
+    Action::Create { session } | Action::Edit { session } => match app_state.sessions.iter().find(|s| &s.uuid == session) {
+      Some(session) => match app_state.patients.iter().find(|p| p.uuid == session.patient_uuid) {
+        Some(patient) if user.can_see(patient) => Some(calendar_event(patient, session, &app_state.env.time_zone, user.role.can_read_notes())),
+        _ => return Ok(()),
+      },
+      None => return Ok(()),
+    },
+    Action::Delete => None,
+  };
+
+  drop(user);
+  drop(app_state);
+  match (&job.action, event) {
+    (Action::Create { .. }, Some(event)) => match google::insert_event(&token, &job.event_id, &event).await {
+      // An earlier attempt went through but its response got lost
+      Err(err) if status(err.as_ref()) == Some(409) => Ok(()),
+      res => res,
+    },
+    (Action::Edit { .. }, Some(event)) => google::edit_event(&token, &EditEvent {
+      start: event.start,
+      end: event.end,
+      description: event.description,
+      summary: event.summary,
+      id: job.event_id.clone(),
+      colorId: event.colorId,
+      time_zone: event.time_zone,
+    }).await,
+    _ => match google::delete_event(&token, &job.event_id).await {
+      Err(err) if matches!(status(err.as_ref()), Some(404 | 410)) => Ok(()),
+      res => res,
+    },
+  }
+}
+
+async fn find_user<'a>(state: &'a State, email: &str) -> Option<RwLockReadGuard<'a, User>> {
+  for user in state.users.values() {
+    let user = user.read().await;
+    if user.user_info.email == email {
+      return Some(user);
+    }
+  }
+
+  None
+}
+
+// The description holds the therapy notes, so it's left out of calendars of roles that can't read them
+pub fn calendar_event(patient: &Patient, session: &Session, time_zone: &str, with_notes: bool) -> RawCalendarEvent {
+  RawCalendarEvent {
+    start: session.start,
+    end: session.end,
+    description: with_notes.then(|| patient.description.to_owned()),
+    summary: format!("S. {}", if patient.name.is_empty() { "<Pacjent bez nazwy>" } else { patient.name.as_str() }),
+    uuid: session.uuid.clone(),
+    colorId: None,
+    time_zone: time_zone.to_owned(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::{encryption, StorageKind};
+
+  // The queue is shared by every test, each one works on calendar events of its own user
+  fn setup() -> (Arc<dyn Storage>, String) {
+    let _ = encryption::init();
+    let _ = QUEUE.set(Mutex::new(Vec::new()));
+    (storage::open(StorageKind::Memory, "").unwrap(), format!("{}@b.c", Uuid::new_v4()))
+  }
+
+  fn queued(email: &str) -> Vec<Job> {
+    queue().lock().unwrap().iter().filter(|job| job.email == email).cloned().collect()
+  }
+
+  async fn stored(storage: &Arc<dyn Storage>, email: &str) -> Vec<Job> {
+    let storage = Arc::clone(storage);
+    let jobs = storage::run(move || storage.load_jobs()).await.unwrap();
+    jobs.into_iter().filter(|job| job.email == email).collect()
+  }
+
+  #[actix_web::test]
+  async fn jobs_survive_a_restart() {
+    let (storage, email) = setup();
+    create_event(&storage, &email, "s1", "e1");
+    delete_event(&storage, &email, "e2");
+
+    let mut jobs = stored(&storage, &email).await;
+    jobs.sort_by(|a, b| a.event_id.cmp(&b.event_id));
+    assert_eq!(jobs.iter().map(|job| (job.event_id.as_str(), job.action.clone())).collect::<Vec<_>>(), [
+      ("e1", Action::Create { session: "s1".into() }),
+      ("e2", Action::Delete),
+    ]);
+  }
+
+  #[actix_web::test]
+  async fn changes_to_an_event_merge_into_one_job() {
+    let (storage, email) = setup();
+    create_event(&storage, &email, "s1", "e1");
+    edit_event(&storage, &email, "s1", "e1");
+    assert_eq!(queued(&email).iter().map(|job| job.action.clone()).collect::<Vec<_>>(), [Action::Create { session: "s1".into() }]);
+
+    delete_event(&storage, &email, "e1");
+    edit_event(&storage, &email, "s1", "e1");
+    assert_eq!(queued(&email).iter().map(|job| job.action.clone()).collect::<Vec<_>>(), [Action::Delete]);
+    assert_eq!(stored(&storage, &email).await.iter().map(|job| job.action.clone()).collect::<Vec<_>>(), [Action::Delete]);
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_an_hour() {
+    assert_eq!((1..=9).map(backoff).collect::<Vec<_>>(), [30, 60, 120, 240, 480, 960, 1920, 3600, 3600]);
+    assert_eq!(backoff(u32::MAX), MAX_DELAY);
+  }
+
+  #[actix_web::test]
+  async fn failed_jobs_wait_then_give_up_and_can_be_retried() {
+    let (storage, email) = setup();
+    delete_event(&storage, &email, "e1");
+    let job = queued(&email).remove(0);
+
+    for attempt in 1..MAX_ATTEMPTS {
+      let before = Utc::now().timestamp() as u64;
+      assert!(finish(&storage, &job, Err((true, "timeout".into()))).is_none());
+
+      let queued = queued(&email).remove(0);
+      assert_eq!(queued.attempts, attempt);
+      assert!(queued.next_attempt >= before + backoff(attempt));
+      assert!(queued.failed_at.is_none());
+    }
+
+    let failed_job = finish(&storage, &job, Err((true, "timeout".into()))).unwrap();
+    assert_eq!(failed_job.attempts, MAX_ATTEMPTS);
+    assert!(failed().iter().any(|job| job.id == failed_job.id));
+    assert!(stored(&storage, &email).await[0].failed_at.is_some());
+
+    let retried = retry(&storage, &failed_job.id).unwrap();
+    assert_eq!((retried.attempts, retried.failed_at), (0, None));
+    assert!(!failed().iter().any(|job| job.id == failed_job.id));
+    assert!(retry(&storage, &failed_job.id).is_none());
+
+    assert!(finish(&storage, &retried, Ok(())).is_none());
+    assert!(queued(&email).is_empty());
+    assert!(stored(&storage, &email).await.is_empty());
+  }
+
+  #[actix_web::test]
+  async fn rejected_jobs_fail_right_away() {
+    let (storage, email) = setup();
+    create_event(&storage, &email, "s1", "e1");
+    let job = queued(&email).remove(0);
+
+    let failed_job = finish(&storage, &job, Err((false, "forbidden".into()))).unwrap();
+    assert_eq!(failed_job.attempts, 1);
+    assert_eq!(failed_job.last_error.as_deref(), Some("forbidden"));
+  }
+
+  #[actix_web::test]
+  async fn runs_that_raced_with_a_change_go_again() {
+    let (storage, email) = setup();
+    create_event(&storage, &email, "s1", "e1");
+    let job = queued(&email).remove(0);
+    edit_event(&storage, &email, "s1", "e1");
+
+    assert!(finish(&storage, &job, Ok(())).is_none());
+    assert_eq!(queued(&email).len(), 1);
+  }
+}
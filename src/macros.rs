@@ -1,37 +1,36 @@
 use std::sync::{OnceLock, Mutex};
 use std::collections::VecDeque;
 use std::iter::once;
-use std::env;
 use std::fs;
 
-pub fn path() -> &'static String {
-  static PATH: OnceLock<String> = OnceLock::new();
-  PATH.get_or_init(|| {
-    let is_prod = env::var("PRODUCTION").map_or(false, |production| production == "true");
-    let path = if is_prod { "/root/".into() } else { env::var("FS").unwrap_or("/root/".into()) };
+static LOG_FILE: OnceLock<String> = OnceLock::new();
 
-    fs::create_dir_all(&path).unwrap();
-    path
-  })
+// Set once from the data dir before anything is logged, until then lines are only kept in memory
+pub fn init(data_dir: &str) -> std::io::Result<()> {
+  fs::create_dir_all(data_dir)?;
+  LOG_FILE.set(format!("{}logs.txt", data_dir)).map_err(|_| std::io::Error::new(std::io::ErrorKind::AlreadyExists, "Log file already set"))
 }
 
 fn lines() -> &'static Mutex<VecDeque<String>> {
   static LINES: OnceLock<Mutex<VecDeque<String>>> = OnceLock::new();
   LINES.get_or_init(|| {
-    let path = format!("{}logs.txt", path());
-    match fs::read_to_string(path.as_str()) {
-      Ok(s) => Mutex::new(s.trim().split('\n').map(|s| s.to_owned()).collect::<VecDeque<_>>()),
-      Err(_) => Mutex::new(VecDeque::new()),
+    match LOG_FILE.get().map(fs::read_to_string) {
+      Some(Ok(s)) => Mutex::new(s.trim().split('\n').map(|s| s.to_owned()).collect::<VecDeque<_>>()),
+      _ => Mutex::new(VecDeque::new()),
     }
   })
 }
 
+fn save(contents: String) {
+  if let Some(path) = LOG_FILE.get() {
+    fs::write(path, contents).unwrap();
+  }
+}
+
 const SIZE: usize = 1024;
 pub fn write(level: impl AsRef<str>, source_file: impl AsRef<str>, line: impl AsRef<str>) {
   let date = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
   let line = format!("[{: <7} {}] {: <25} {}", level.as_ref(), date, &source_file.as_ref()[4..], line.as_ref());
-  let path = format!("{}logs.txt", path());
-
   let mut lines = lines().lock().unwrap();
   lines.push_back(line);
 
@@ -39,14 +38,12 @@ pub fn write(level: impl AsRef<str>, source_file: impl AsRef<str>, line: impl As
     lines.pop_front();
   }
 
-  fs::write(path.as_str(), lines.iter().chain(once(&"".into())).map(|s| s.as_str()).collect::<Vec<_>>().join("\n")).unwrap();
+  save(lines.iter().chain(once(&"".into())).map(|s| s.as_str()).collect::<Vec<_>>().join("\n"));
 }
 
 pub fn first(is_prod: bool) {
   let date = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
   let line = format!("========================[ RUNNING IN {}; {} ]========================", if is_prod { "PRODUCTION" } else { "DEVELOPMENT" }, date);
-  let path = format!("{}logs.txt", path());
-  
   let mut lines = lines().lock().unwrap();
   if lines.len() > 0 {
     lines.push_back("".into());
@@ -59,17 +56,11 @@ pub fn first(is_prod: bool) {
     lines.pop_front();
   }
 
-  fs::write(path.as_str(), lines.iter().map(|s| s.as_str()).collect::<Vec<_>>().join("\n")).unwrap();
+  save(lines.iter().map(|s| s.as_str()).collect::<Vec<_>>().join("\n"));
 }
 
 #[allow(clippy::module_inception)]
 pub mod macros {
-  macro_rules! fspath {
-    () => {
-      crate::macros::path()
-    };
-  }
-
   macro_rules! info {
     ($($arg:tt)*) => {
       {
@@ -97,5 +88,5 @@ pub mod macros {
     }
   }
 
-  pub(crate) use { fspath, info, warning, error };
+  pub(crate) use { info, warning, error };
 }
\ No newline at end of file
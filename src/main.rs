@@ -1,131 +1,106 @@
 #![feature(async_closure, let_chains)]
 
 use crate::state::state::State;
-use crate::macros::path;
 
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::collections::HashMap;
-use std::fs::File;
 use std::{env, io, fs};
 
-use actix_web::{HttpResponse, HttpServer, App, web, Responder};
+use actix_web::{HttpRequest, HttpResponse, HttpServer, App, web, Responder};
+use actix_web::http::header;
 use actix_web_lab::middleware::{from_fn, redirect_to_non_www};
 use actix_web::web::Data;
 use tokio::sync::{RwLock, mpsc};
 use include_dir::{include_dir, Dir};
-use rustls::{ServerConfig, Certificate, PrivateKey};
-use rustls::server::{ResolvesServerCert, ClientHello};
-use rustls::sign::{CertifiedKey, any_supported_type};
+use rustls::ServerConfig;
+use sha2::{Digest, Sha256};
 
+mod config;
 mod state;
 mod routes;
 mod macros;
 mod consts;
 mod google;
 mod backup;
-mod filestreamer;
 mod cors;
+mod headers;
+mod ratelimit;
+mod storage;
+mod audit;
+mod crypto;
+mod tokens;
+mod totp;
+mod replication;
+mod tls;
+mod acme;
+mod secrets;
+mod shutdown;
+mod jobs;
 
 pub use macros::macros as logs;
+pub use config::EnvVars;
 pub type AppState = Arc<RwLock<State>>;
 pub type User = Arc<RwLock<state::user::User>>;
 
 static DIST: Dir<'_> = include_dir!("./dist/assets");
 static INDEX: &str = include_str!("../dist/index.html");
 
-struct MultiDomainResolver (HashMap<String, Arc<CertifiedKey>>);
-
-impl ResolvesServerCert for MultiDomainResolver {
-  fn resolve(&self, dns_name: ClientHello) -> Option<Arc<CertifiedKey>> {
-    dns_name.server_name().and_then(|name| {
-      let name = if let Some(name) = name.strip_prefix("www.") { name } else { name };
-      self.0.get(name).cloned()
-    })
-  }
-}
-
-const DOMAIN: &str = "entitia";
-impl MultiDomainResolver {
-  pub fn add_cert(&mut self, path: &String, top_domain: &str) -> io::Result<()> {
-    let cert = format!("{}{}/certificate.pem", path, top_domain);
-    let key = format!("{}{}/private.pem", path, top_domain);
-
-    let cert_file = File::open(cert)?;
-    let mut cert_reader = io::BufReader::new(cert_file);
-    let certs = rustls_pemfile::certs(&mut cert_reader)
-      .map(|cert|cert.map(|cert| Certificate(cert.iter().map(|byte| byte.to_owned()).collect())))
-      .collect::<Result<Vec<_>, _>>()?;
-
-    let key_file = File::open(key)?;
-    let mut key_reader = io::BufReader::new(key_file);
-    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_reader).collect::<Result<Vec<_>, _>>()?;
-
-    let key = match keys.len() {
-      1 => PrivateKey(keys.remove(0).secret_pkcs8_der().to_vec()),
-      0 => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No keys found")),
-      _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Multiple keys found")),
-    };
-
-    let key = match any_supported_type(&key) {
-      Ok(key) => key,
-      Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid key type")),
-    };
-
-    let cert = CertifiedKey::new(certs, key);
-    self.0.insert(format!("{}.{}", DOMAIN, top_domain), Arc::new(cert));
-
-    Ok(())
-  }
-}
-
-#[derive(Clone)]
-pub struct EnvVars {
-  is_production: bool,
-  inner_port: u16,
-  dev_port: u16,
-  users: Vec<String>,
-}
-
 #[tokio::main]
 async fn main() -> io::Result<()> {
   dotenv::dotenv().ok();
 
-  let is_production = env::var("PRODUCTION").map_or(false, |production| production == "true");
-  let users = env::var("USERS").unwrap_or("".into()).split_whitespace().map(|s| s.to_owned()).collect::<Vec<_>>();
-
-  let inner_port = env::var("INNER_PORT").map_or(2137, |port| port.parse().unwrap_or(2137));
-  let dev_port = env::var("DEV_PORT").map_or(5173, |port| port.parse().unwrap_or(5173));
+  let env_vars = EnvVars::load()?;
+  let is_production = env_vars.is_production;
+  let inner_port = env_vars.inner_port;
+  // Plain HTTP only answers ACME challenges and redirects to HTTPS, it's on by default when ACME is
+  let http_port = env_vars.http_port.or(env_vars.acme.is_some().then_some(80));
 
-  let path = if is_production { "/root/".into() } else { env::var("FS").unwrap_or("/root/".into()) };
+  // Sites allowed to call the API from the browser, the dev server is always allowed outside production
+  let mut origins = env_vars.allowed_origins.clone();
+  if !is_production {
+    origins.push(format!("http://localhost:{}", env_vars.dev_port));
+  }
 
-  let env_vars = EnvVars {
-    is_production,
-    inner_port,
-    dev_port,
-    users,
-  };
+  let path = env_vars.data_dir.clone();
+  macros::init(&path)?;
 
   macros::first(is_production);
   env::set_var("RUST_LOG", "INFO");
   env_logger::init();
 
+  logs::info!("Using {:?} storage backend", env_vars.storage);
+  storage::encryption::init()?;
+  let storage = storage::open(env_vars.storage, &path)?;
+  jobs::init(&*storage)?;
+
+  secrets::init(env_vars.secrets_file.as_deref())?;
+  secrets::start_reload_on_sighup()?;
+
   let (write_tx, write_rx) = mpsc::channel(1);
-  let state = State::new(write_tx, path.clone())?;
+  let state = State::new(Arc::clone(&storage), write_tx, &env_vars)?;
   
   let state = Arc::new(RwLock::new(state));
 
   State::schedule_webhook_refresh(&state).await;
   State::start_write_loop(Arc::clone(&state), write_rx);
   State::spawn_ping_loop(Arc::clone(&state));
+  State::start_key_rotation(Arc::clone(&state));
+  state::trash::start_purge_loop(Arc::clone(&state));
+  jobs::start_worker(Arc::clone(&state));
   
   if env_vars.is_production {
     State::init_google_webhooks(&state).await;
   }
 
   logs::info!("Starting server on inner port {}...", inner_port);
-  backup::start_backup_loop(&path);
+  backup::start_backup_loop(Arc::clone(&storage), &env_vars);
 
   fs::create_dir_all(format!("{}pdf", path)).unwrap();
+  let cors = cors::Cors::new(origins);
+  let domains = env_vars.domains.clone();
+  let security_headers = headers::SecurityHeaders::new(&env_vars.headers, is_production)?;
+  let app_state = Arc::clone(&state);
+  let env = env_vars.clone();
   let server = HttpServer::new(move || {
     App::new()
       .app_data(Data::new(state.clone()))
@@ -137,53 +112,141 @@ async fn main() -> io::Result<()> {
       .default_service(web::get().to(index))
       .service(routes::get_routes())
       .wrap(from_fn(redirect_to_non_www))
-      .wrap(cors::Cors)
-  });
+      .wrap(ratelimit::RateLimit(env_vars.rate_limits))
+      .wrap(security_headers.clone())
+      .wrap(cors.clone())
+  })
+    .disable_signals()
+    .shutdown_timeout(shutdown::TIMEOUT.as_secs());
 
   if !is_production {
-    return server
+    let server = server
       .bind(("0.0.0.0", inner_port))?
-      .run()
-      .await
+      .run();
+
+    return shutdown::serve(app_state, vec![server], &env).await;
   }
 
-  let mut resolver = MultiDomainResolver(HashMap::new());
-  resolver.add_cert(&path, "com")?;
-  resolver.add_cert(&path, "pl")?;
+  let resolver = tls::CertResolver::new(&path, domains);
+  tls::start_watch_loop(Arc::clone(&resolver));
+  if let Some(config) = env.acme.clone() {
+    acme::start_renewal_loop(Arc::clone(&resolver), config);
+  }
 
   let cfg = ServerConfig::builder()
     .with_safe_defaults()
     .with_no_client_auth()
-    .with_cert_resolver(Arc::new(resolver));
+    .with_cert_resolver(resolver);
   
-  server
+  let server = server
     .bind_rustls_021(format!("0.0.0.0:{}", inner_port), cfg)?
-    .run()
-    .await
+    .run();
+
+  let http_port = match http_port {
+    Some(port) => port,
+    None => return shutdown::serve(app_state, vec![server], &env).await,
+  };
+
+  logs::info!("Answering ACME challenges and redirecting to HTTPS on port {}...", http_port);
+  let http = HttpServer::new(|| {
+    App::new()
+      .route("/.well-known/acme-challenge/{token}", web::get().to(acme::challenge))
+      .default_service(web::to(redirect_to_https))
+  })
+    .disable_signals()
+    .bind(("0.0.0.0", http_port))?
+    .run();
+
+  shutdown::serve(app_state, vec![server, http], &env).await
 }
 
-async fn asset(path: web::Path<String>) -> impl Responder {
+async fn redirect_to_https(req: HttpRequest) -> HttpResponse {
+  let info = req.connection_info();
+  let host = info.host().split(':').next().unwrap_or_default();
+
+  HttpResponse::MovedPermanently()
+    .insert_header((header::LOCATION, format!("https://{}{}", host, req.uri())))
+    .finish()
+}
+
+// Vite puts a content hash in every asset name, so a file never changes under the same path
+async fn asset(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
   let path = path.into_inner();
-  let asset = DIST.get_file(&path).unwrap_or_else(|| panic!("Failed to get asset: {}", path)).contents();
-  
-  if !path.ends_with(".js") {
-    return HttpResponse::Ok().body(asset);
+  let (file, etag) = match (DIST.get_file(&path), etags().get(path.as_str())) {
+    (Some(file), Some(etag)) => (file, etag.as_str()),
+    _ => return HttpResponse::NotFound().finish(),
+  };
+
+  let is_cached = is_cached(&req, etag);
+  let mut res = if is_cached { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+  res
+    .insert_header((header::ETAG, etag))
+    .insert_header((header::CACHE_CONTROL, "public, max-age=31536000, immutable"));
+
+  match is_cached {
+    true => res.finish(),
+    false => res.content_type(content_type(&path)).body(file.contents()),
   }
+}
 
-  HttpResponse::Ok()
-    .content_type("application/javascript")
-    .body(asset)
+// If-None-Match can list several tags, or * for whatever the current one is
+fn is_cached(req: &HttpRequest, etag: &str) -> bool {
+  req.headers().get(header::IF_NONE_MATCH)
+    .and_then(|tags| tags.to_str().ok())
+    .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"))
 }
 
+fn etags() -> &'static HashMap<&'static str, String> {
+  static ETAGS: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+  ETAGS.get_or_init(|| {
+    fn walk(dir: &'static Dir<'static>, etags: &mut HashMap<&'static str, String>) {
+      for file in dir.files() {
+        if let Some(path) = file.path().to_str() {
+          etags.insert(path, format!("\"{}\"", &format!("{:x}", Sha256::digest(file.contents()))[..32]));
+        }
+      }
+
+      dir.dirs().for_each(|dir| walk(dir, etags));
+    }
+
+    let mut etags = HashMap::new();
+    walk(&DIST, &mut etags);
+    etags
+  })
+}
+
+fn content_type(path: &str) -> &'static str {
+  match path.rsplit_once('.').map(|(_, extension)| extension) {
+    Some("js" | "mjs") => "application/javascript; charset=utf-8",
+    Some("css") => "text/css; charset=utf-8",
+    Some("html") => "text/html; charset=utf-8",
+    Some("json" | "map") => "application/json",
+    Some("svg") => "image/svg+xml",
+    Some("png") => "image/png",
+    Some("jpg" | "jpeg") => "image/jpeg",
+    Some("webp") => "image/webp",
+    Some("ico") => "image/x-icon",
+    Some("woff2") => "font/woff2",
+    Some("woff") => "font/woff",
+    Some("ttf") => "font/ttf",
+    Some("txt") => "text/plain; charset=utf-8",
+    _ => "application/octet-stream",
+  }
+}
+
+// Revalidated on every load so a deploy is picked up right away
 async fn index() -> impl Responder {
-  HttpResponse::Ok().body(INDEX)
+  HttpResponse::Ok()
+    .content_type("text/html; charset=utf-8")
+    .insert_header((header::CACHE_CONTROL, "no-cache"))
+    .body(INDEX)
 }
 
 
 // #[get("/{uuid}/pdf")]
-pub async fn get_pdf(pdf: web::Path<String>) -> HttpResponse {
+pub async fn get_pdf(pdf: web::Path<String>, env: web::Data<EnvVars>) -> HttpResponse {
   let pdf = pdf.into_inner();
-  let path = format!("{}pdf/{}.pdf", path(), pdf);
+  let path = format!("{}pdf/{}.pdf", env.data_dir, pdf);
 
   match fs::read(path) {
     Ok(pdf) => HttpResponse::Ok().content_type("application/pdf").body(pdf),
@@ -191,12 +254,32 @@ pub async fn get_pdf(pdf: web::Path<String>) -> HttpResponse {
   }
 }
 
-pub async fn get_html(html: web::Path<String>) -> HttpResponse {
+pub async fn get_html(html: web::Path<String>, env: web::Data<EnvVars>) -> HttpResponse {
   let html = html.into_inner();
-  let path = format!("{}pdf/{}.html", path(), html);
+  let path = format!("{}pdf/{}.html", env.data_dir, html);
 
   match fs::read(path) {
     Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
     Err(_) => HttpResponse::NotFound().finish(),
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use actix_web::test::TestRequest;
+
+  #[test]
+  fn matches_any_listed_etag() {
+    let etag = "\"abc\"";
+    let req = |tags: &str| TestRequest::default().insert_header((header::IF_NONE_MATCH, tags)).to_http_request();
+
+    assert!(is_cached(&req("\"abc\""), etag));
+    assert!(is_cached(&req("\"old\", \"abc\""), etag));
+    assert!(is_cached(&req("*"), etag));
+    assert!(!is_cached(&req("\"old\""), etag));
+    // Tags are compared with their quotes
+    assert!(!is_cached(&req("abc"), etag));
+    assert!(!is_cached(&TestRequest::default().to_http_request(), etag));
+  }
+}
@@ -0,0 +1,296 @@
+use crate::logs::*;
+
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Mutex, OnceLock};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::http::{header, StatusCode};
+use actix_web::{Error, HttpResponse};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use chrono::Utc;
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+
+const WINDOW: u64 = 60;
+// Routes that take something guessable: the OAuth hand-off code, the SSE token, the socket ticket and TOTP codes
+const AUTH_ROUTES: [&str; 6] = [
+  "/api/auth",
+  "/api/sse/stream",
+  "/api/sessions/{session}/stream",
+  "/api/2fa/verify",
+  "/api/2fa/backup-codes",
+  "/api/2fa",
+];
+// Requests that match no route share one bucket per IP, so made-up paths can't grow the map
+const UNMATCHED: &str = "<unmatched>";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+  // Requests per minute for each IP on each route
+  pub requests: u32,
+  pub auth_requests: u32,
+  // Failed attempts per minute before an IP gets locked out
+  pub failures: u32,
+  // Seconds
+  pub lockout: u64,
+}
+
+#[derive(Default)]
+struct Window {
+  started: u64,
+  count: u32,
+}
+
+impl Window {
+  // Counts one more hit and returns how many there were in the current window
+  fn hit(&mut self, now: u64) -> u32 {
+    if self.started + WINDOW <= now {
+      self.started = now;
+      self.count = 0;
+    }
+
+    self.count += 1;
+    self.count
+  }
+}
+
+#[derive(Default)]
+struct Limiter {
+  // <(IP, route), Window>
+  requests: HashMap<(IpAddr, String), Window>,
+  failures: HashMap<IpAddr, Window>,
+  // <IP, (locked until, failures that caused it)>
+  lockouts: HashMap<IpAddr, (u64, u32)>,
+  pruned_at: u64,
+}
+
+impl Limiter {
+  fn prune(&mut self, now: u64) {
+    self.requests.retain(|_, window| window.started + WINDOW > now);
+    self.failures.retain(|_, window| window.started + WINDOW > now);
+    self.lockouts.retain(|_, (until, _)| *until > now);
+    self.pruned_at = now;
+  }
+
+  // Returns for how many more seconds the request should be refused
+  fn check(&mut self, limits: &Limits, ip: IpAddr, route: &str, now: u64) -> Option<u64> {
+    // Expired entries are swept once a window instead of on every request
+    if self.pruned_at + WINDOW <= now {
+      self.prune(now);
+    }
+
+    if let Some((until, _)) = self.lockouts.get(&ip) && *until > now {
+      return Some(until - now);
+    }
+
+    let limit = if AUTH_ROUTES.contains(&route) { limits.auth_requests } else { limits.requests };
+    let window = self.requests.entry((ip, route.to_owned())).or_default();
+    let count = window.hit(now);
+    if count <= limit {
+      return None;
+    }
+
+    if count == limit + 1 {
+      warning!("Rate limiting {} on {}, over {} requests per minute", ip, route, limit);
+    }
+
+    Some(window.started + WINDOW - now)
+  }
+
+  fn fail(&mut self, limits: &Limits, ip: IpAddr, route: &str, now: u64) {
+    let failures = self.failures.entry(ip).or_default().hit(now);
+    if failures < limits.failures {
+      return;
+    }
+
+    warning!("Locking out {} for {} minutes after {} failed attempts, the last on {}", ip, limits.lockout / 60, failures, route);
+    self.failures.remove(&ip);
+    self.lockouts.insert(ip, (now + limits.lockout, failures));
+  }
+
+  fn unlock(&mut self, ip: IpAddr) -> bool {
+    self.failures.remove(&ip);
+    self.lockouts.remove(&ip).is_some()
+  }
+}
+
+fn limiter() -> &'static Mutex<Limiter> {
+  static LIMITER: OnceLock<Mutex<Limiter>> = OnceLock::new();
+  LIMITER.get_or_init(Default::default)
+}
+
+#[derive(Serialize)]
+pub struct Lockout {
+  pub ip: IpAddr,
+  pub until: u64,
+  pub failures: u32,
+}
+
+pub fn lockouts() -> Vec<Lockout> {
+  let mut limiter = limiter().lock().unwrap();
+  limiter.prune(Utc::now().timestamp() as u64);
+
+  let mut lockouts = limiter.lockouts.iter().map(|(ip, (until, failures))| Lockout { ip: *ip, until: *until, failures: *failures }).collect::<Vec<_>>();
+  lockouts.sort_by_key(|lockout| lockout.until);
+  lockouts
+}
+
+pub fn unlock(ip: IpAddr) -> bool {
+  limiter().lock().unwrap().unlock(ip)
+}
+
+// Only guesses count: rejected hand-off codes, SSE tokens, socket tickets, TOTP codes and step-up codes.
+// A 401 elsewhere is usually an expired token, which would otherwise lock out everyone behind a shared IP.
+fn is_failure(route: &str, confirming: bool, status: StatusCode) -> bool {
+  (confirming && status == StatusCode::FORBIDDEN)
+    || (AUTH_ROUTES.contains(&route) && status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS)
+}
+
+pub struct RateLimit(pub Limits);
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+  S::Future: 'static,
+
+  B: MessageBody + 'static,
+{
+  type Response = ServiceResponse<EitherBody<B>>;
+  type Error = Error;
+  type InitError = ();
+  type Transform = RateLimitMiddleware<S>;
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RateLimitMiddleware { service, limits: self.0 }))
+  }
+}
+
+pub struct RateLimitMiddleware<S> {
+  service: S,
+  limits: Limits,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+  S::Future: 'static,
+
+  B: MessageBody + 'static,
+{
+  type Response = ServiceResponse<EitherBody<B>>;
+  type Error = Error;
+  type Future = LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    // The server is exposed directly, so the peer address is the client and forwarded headers can't be trusted
+    let ip = req.peer_addr().map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |addr| addr.ip());
+    let route = req.match_pattern().unwrap_or_else(|| UNMATCHED.to_owned());
+    let confirming = req.headers().contains_key("X-Confirm-Code");
+    let now = Utc::now().timestamp() as u64;
+
+    if let Some(retry_after) = limiter().lock().unwrap().check(&self.limits, ip, &route, now) {
+      let res = HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+        .body("Too Many Requests");
+
+      return Box::pin(async move {
+        Ok(req.into_response(res).map_into_right_body())
+      });
+    }
+
+    let limits = self.limits;
+    let fut = self.service.call(req);
+
+    Box::pin(async move {
+      let res = fut.await?;
+      if is_failure(&route, confirming, res.status()) {
+        limiter().lock().unwrap().fail(&limits, ip, &route, now);
+      }
+
+      Ok(res.map_into_left_body())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LIMITS: Limits = Limits { requests: 3, auth_requests: 1, failures: 2, lockout: 600 };
+  const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+  const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+  #[test]
+  fn limits_requests_per_route_and_window() {
+    let mut limiter = Limiter::default();
+    for _ in 0..3 {
+      assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1000), None);
+    }
+
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1010), Some(50));
+    assert_eq!(limiter.check(&LIMITS, OTHER, "/api/patients", 1010), None);
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/sessions", 1010), None);
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1060), None);
+
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/auth", 1000), None);
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/auth", 1000), Some(60));
+  }
+
+  #[test]
+  fn lockouts_expire() {
+    let mut limiter = Limiter::default();
+    limiter.fail(&LIMITS, IP, "/api/auth", 1000);
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1000), None);
+
+    limiter.fail(&LIMITS, IP, "/api/auth", 1001);
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1001), Some(600));
+    assert_eq!(limiter.check(&LIMITS, OTHER, "/api/patients", 1001), None);
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1601), None);
+  }
+
+  #[test]
+  fn failures_outside_the_window_dont_add_up() {
+    let mut limiter = Limiter::default();
+    limiter.fail(&LIMITS, IP, "/api/auth", 1000);
+    limiter.fail(&LIMITS, IP, "/api/auth", 1060);
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1060), None);
+  }
+
+  #[test]
+  fn unlock_lifts_the_lockout() {
+    let mut limiter = Limiter::default();
+    limiter.fail(&LIMITS, IP, "/api/auth", 1000);
+    limiter.fail(&LIMITS, IP, "/api/auth", 1000);
+
+    assert!(limiter.unlock(IP));
+    assert_eq!(limiter.check(&LIMITS, IP, "/api/patients", 1000), None);
+    assert!(!limiter.unlock(IP));
+  }
+
+  #[test]
+  fn prunes_once_a_window() {
+    let mut limiter = Limiter::default();
+    limiter.check(&LIMITS, IP, "/api/patients", 1000);
+    limiter.check(&LIMITS, OTHER, "/api/patients", 1030);
+    assert_eq!(limiter.requests.len(), 2);
+
+    limiter.check(&LIMITS, OTHER, "/api/sessions", 1070);
+    assert_eq!(limiter.requests.len(), 2);
+    assert!(!limiter.requests.contains_key(&(IP, "/api/patients".to_owned())));
+  }
+
+  #[test]
+  fn only_guesses_count_as_failures() {
+    assert!(is_failure("/api/auth", false, StatusCode::UNAUTHORIZED));
+    assert!(is_failure("/api/sse/stream", false, StatusCode::BAD_REQUEST));
+    assert!(is_failure("/api/patients/{uuid}", true, StatusCode::FORBIDDEN));
+    assert!(!is_failure("/api/patients", false, StatusCode::UNAUTHORIZED));
+    assert!(!is_failure("/api/patients/{uuid}", false, StatusCode::FORBIDDEN));
+    assert!(!is_failure("/api/auth", false, StatusCode::TOO_MANY_REQUESTS));
+  }
+}
@@ -0,0 +1,442 @@
+use crate::audit;
+use crate::backup::{self, Snapshot};
+use crate::config::EnvVars;
+use crate::{ratelimit, replication};
+use crate::state::state::State;
+use crate::state::user::{Access, Invitation, Role};
+use crate::AppState;
+use crate::logs::*;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use actix_web::{delete, get, patch, post, web, Error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+// Everything under /admin reaches across all patients, so it's limited to owners
+async fn owner(state: &AppState, req: HttpRequest) -> Result<Access, Error> {
+  let access = state.read().await.access(req).await?;
+  access.require(access.role == Role::Owner)?;
+  Ok(access)
+}
+
+#[get("/admin/quarantine")]
+pub async fn quarantine(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  owner(&state, req).await?;
+
+  match state.read().await.storage.quarantined() {
+    Ok(records) => Ok(HttpResponse::Ok().json(records)),
+    Err(err) => {
+      error!("Couldn't list quarantined records: {}", err);
+      Ok(HttpResponse::InternalServerError().finish())
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupQuery {
+  patient: Option<String>,
+}
+
+fn backup_error(name: &str, err: io::Error) -> HttpResponse {
+  match err.kind() {
+    io::ErrorKind::NotFound => HttpResponse::NotFound().body("Backup not found"),
+    io::ErrorKind::InvalidInput => HttpResponse::BadRequest().body("Invalid backup name"),
+    io::ErrorKind::InvalidData => HttpResponse::UnprocessableEntity().body(err.to_string()),
+    _ => {
+      error!("Couldn't read backup {}: {}", name, err);
+      HttpResponse::InternalServerError().finish()
+    }
+  }
+}
+
+async fn load_backup(env: &web::Data<EnvVars>, name: &str, patient: Option<&str>) -> Result<Snapshot, HttpResponse> {
+  let (env, owned) = (env.clone(), name.to_owned());
+  let snapshot = match web::block(move || backup::load_backup(&env, &owned)).await {
+    Ok(Ok(snapshot)) => snapshot,
+    Ok(Err(err)) => return Err(backup_error(name, err)),
+    Err(err) => {
+      error!("Couldn't read backup {}: {}", name, err);
+      return Err(HttpResponse::InternalServerError().finish());
+    }
+  };
+
+  match patient {
+    Some(patient) if !snapshot.patients.iter().any(|p| p.uuid == patient) => Err(HttpResponse::NotFound().body("Patient not found in the backup")),
+    Some(patient) => Ok(snapshot.only(patient)),
+    None => Ok(snapshot),
+  }
+}
+
+#[get("/admin/replication")]
+pub async fn replication_status(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>) -> Result<HttpResponse, Error> {
+  owner(&state, req).await?;
+  Ok(HttpResponse::Ok().json(replication::current_status(env.replica.as_ref())))
+}
+
+#[get("/admin/lockouts")]
+pub async fn lockouts(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  owner(&state, req).await?;
+  Ok(HttpResponse::Ok().json(ratelimit::lockouts()))
+}
+
+#[delete("/admin/lockouts/{ip}")]
+pub async fn unlock(req: HttpRequest, state: web::Data<AppState>, ip: web::Path<String>) -> Result<HttpResponse, Error> {
+  let access = owner(&state, req).await?;
+  let ip = match ip.parse::<IpAddr>() {
+    Ok(ip) => ip,
+    Err(_) => return Ok(HttpResponse::BadRequest().body("Invalid IP address")),
+  };
+
+  if !ratelimit::unlock(ip) {
+    return Ok(HttpResponse::NotFound().body("No lockout for this address"));
+  }
+
+  info!("{} lifted the lockout of {}", access.email, ip);
+  Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/admin/backups")]
+pub async fn backups(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>) -> Result<HttpResponse, Error> {
+  owner(&state, req).await?;
+
+  match web::block(move || backup::list_backups(&env)).await {
+    Ok(Ok(backups)) => Ok(HttpResponse::Ok().json(backups)),
+    Ok(Err(err)) => {
+      error!("Couldn't list backups: {}", err);
+      Ok(HttpResponse::InternalServerError().finish())
+    },
+    Err(err) => {
+      error!("Couldn't list backups: {}", err);
+      Ok(HttpResponse::InternalServerError().finish())
+    }
+  }
+}
+
+// Decrypts the archive if needed and checks every file against its manifest
+#[get("/admin/backups/{name}/verify")]
+pub async fn backup_verify(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>, name: web::Path<String>) -> Result<HttpResponse, Error> {
+  owner(&state, req).await?;
+
+  let owned = name.clone();
+  let result = web::block(move || backup::archive_path(&env.data_dir, &owned).and_then(|archive| backup::verify(&env, &archive))).await;
+  match result {
+    Ok(Ok(manifest)) => Ok(HttpResponse::Ok().json(manifest)),
+    Ok(Err(err)) => Ok(backup_error(&name, err)),
+    Err(err) => {
+      error!("Couldn't verify backup {}: {}", name, err);
+      Ok(HttpResponse::InternalServerError().finish())
+    }
+  }
+}
+
+#[get("/admin/backups/{name}/diff")]
+pub async fn backup_diff(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>, name: web::Path<String>, query: web::Query<BackupQuery>) -> Result<HttpResponse, Error> {
+  owner(&state, req).await?;
+
+  let snapshot = match load_backup(&env, &name, query.patient.as_deref()).await {
+    Ok(snapshot) => snapshot,
+    Err(response) => return Ok(response),
+  };
+
+  let app_state = state.read().await;
+  Ok(HttpResponse::Ok().json(backup::diff(&app_state, &snapshot, query.patient.as_deref())))
+}
+
+#[post("/admin/backups/{name}/restore")]
+pub async fn backup_restore(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>, name: web::Path<String>, query: web::Query<BackupQuery>) -> Result<HttpResponse, Error> {
+  let access = owner(&state, req.clone()).await?;
+  state.read().await.step_up(&access, &req).await?;
+
+  let snapshot = match load_backup(&env, &name, query.patient.as_deref()).await {
+    Ok(snapshot) => snapshot,
+    Err(response) => return Ok(response),
+  };
+
+  let mut app_state = state.write().await;
+  let actor = access.email;
+
+  if let Some(patient) = snapshot.patients.first() {
+    let name = patient.name.to_lowercase();
+    if query.patient.is_some() && app_state.patients.iter().any(|p| p.uuid != patient.uuid && p.name.to_lowercase() == name) {
+      return Ok(HttpResponse::Conflict().body("Patient already exists"));
+    }
+  }
+
+  // Take a fresh backup first so the restore itself can be undone
+  let paths = app_state.storage.backup_paths();
+  match web::block(move || backup::create_backup(&env, &paths)).await {
+    Ok(Ok(Some(name))) => info!("Created backup {} before restoring", name),
+    Ok(Ok(None)) => info!("Latest backup already matches the live data"),
+    Ok(Err(err)) => {
+      error!("Failed to create backup before restoring: {}", err);
+      return Ok(HttpResponse::InternalServerError().finish());
+    },
+    Err(err) => {
+      error!("Failed to create backup before restoring: {}", err);
+      return Ok(HttpResponse::InternalServerError().finish());
+    }
+  }
+
+  backup::restore(&mut app_state, &actor, snapshot, query.patient.as_deref()).await.map_err(audit::failed)?;
+
+  match &query.patient {
+    Some(patient) => info!("Restored patient {} from backup {}", patient, name),
+    None => info!("Restored all records from backup {}", name),
+  }
+
+  Ok(HttpResponse::Ok().body("Restored"))
+}
+
+
+#[derive(Serialize)]
+struct UserSummary {
+  email: String,
+  name: String,
+  picture: String,
+  role: Role,
+}
+
+#[derive(Serialize)]
+struct Users<'a> {
+  users: Vec<UserSummary>,
+  invitations: &'a HashMap<String, Invitation>,
+}
+
+// Token of the user with the given email and how many owners there are
+async fn find_user(app_state: &State, email: &str) -> (Option<String>, usize) {
+  let mut token = None;
+  let mut owners = 0;
+  for (key, user) in &app_state.users {
+    let user = user.read().await;
+    if user.role == Role::Owner {
+      owners += 1;
+    }
+
+    if user.user_info.email.eq_ignore_ascii_case(email) {
+      token = Some(key.clone());
+    }
+  }
+
+  (token, owners)
+}
+
+#[get("/admin/users")]
+pub async fn users(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  owner(&state, req).await?;
+  let app_state = state.read().await;
+
+  let mut users = Vec::new();
+  for user in app_state.users.values() {
+    let user = user.read().await;
+    users.push(UserSummary {
+      email: user.user_info.email.clone(),
+      name: user.user_info.name.clone(),
+      picture: user.user_info.picture.clone(),
+      role: user.role,
+    });
+  }
+
+  users.sort_by(|a, b| a.email.cmp(&b.email));
+  Ok(HttpResponse::Ok().json(Users { users, invitations: &app_state.invitations }))
+}
+
+#[derive(Debug, Deserialize)]
+struct NewInvitation {
+  email: String,
+  role: Role,
+}
+
+#[post("/admin/users")]
+pub async fn invite_user(req: HttpRequest, state: web::Data<AppState>, body: web::Json<NewInvitation>) -> Result<HttpResponse, Error> {
+  let access = owner(&state, req).await?;
+  let mut app_state = state.write().await;
+
+  let NewInvitation { email, role } = body.into_inner();
+  let email = email.trim().to_lowercase();
+  if !email.contains('@') {
+    return Ok(HttpResponse::BadRequest().body("Invalid email"));
+  }
+
+  if find_user(&app_state, &email).await.0.is_some() || app_state.invitations.contains_key(&email) {
+    return Ok(HttpResponse::Conflict().body("User already exists"));
+  }
+
+  app_state.invitations.insert(email.clone(), Invitation {
+    role,
+    invited_by: access.email.clone(),
+    invited_at: chrono::Utc::now().timestamp() as u64,
+  });
+  app_state.write();
+
+  info!("{} invited {} as {:?}", access.email, email, role);
+  Ok(HttpResponse::Ok().body("User invited"))
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateUser {
+  role: Role,
+}
+
+#[patch("/admin/users/{email}")]
+pub async fn update_user(req: HttpRequest, state: web::Data<AppState>, email: web::Path<String>, body: web::Json<UpdateUser>) -> Result<HttpResponse, Error> {
+  let access = owner(&state, req).await?;
+  let mut app_state = state.write().await;
+  let email = email.into_inner().to_lowercase();
+  let role = body.role;
+
+  let (token, owners) = find_user(&app_state, &email).await;
+  let token = match token {
+    Some(token) => token,
+    None => match app_state.invitations.get_mut(&email) {
+      Some(invitation) => {
+        invitation.role = role;
+        app_state.write();
+
+        info!("{} changed the invitation of {} to {:?}", access.email, email, role);
+        return Ok(HttpResponse::Ok().body("Invitation updated"));
+      },
+      None => return Ok(HttpResponse::NotFound().body("User not found")),
+    },
+  };
+
+  let user = Arc::clone(&app_state.users[&token]);
+  let mut user = user.write().await;
+  if user.role == Role::Owner && role != Role::Owner && owners == 1 {
+    return Ok(HttpResponse::Conflict().body("Can't demote the last owner"));
+  }
+
+  user.role = role;
+  drop(user);
+
+  // Open streams keep their access so later broadcasts are filtered for the new role
+  app_state.sse.iter_mut().filter(|(client, _)| client.token == token).for_each(|(client, _)| client.role = role);
+  app_state.write();
+
+  info!("{} changed the role of {} to {:?}", access.email, email, role);
+  Ok(HttpResponse::Ok().body("User updated"))
+}
+
+#[delete("/admin/users/{email}")]
+pub async fn revoke_user(req: HttpRequest, state: web::Data<AppState>, email: web::Path<String>) -> Result<HttpResponse, Error> {
+  let access = owner(&state, req).await?;
+  let mut app_state = state.write().await;
+  let email = email.into_inner().to_lowercase();
+
+  if app_state.invitations.remove(&email).is_some() {
+    app_state.write();
+    info!("{} withdrew the invitation of {}", access.email, email);
+    return Ok(HttpResponse::Ok().body("Invitation revoked"));
+  }
+
+  let (token, owners) = find_user(&app_state, &email).await;
+  let token = match token {
+    Some(token) => token,
+    None => return Ok(HttpResponse::NotFound().body("User not found")),
+  };
+
+  if access.email.eq_ignore_ascii_case(&email) {
+    return Ok(HttpResponse::Conflict().body("Can't revoke yourself"));
+  }
+
+  if app_state.users[&token].read().await.role == Role::Owner && owners == 1 {
+    return Ok(HttpResponse::Conflict().body("Can't revoke the last owner"));
+  }
+
+  app_state.revoke_user(&token).await;
+
+  info!("{} revoked {}", access.email, email);
+  Ok(HttpResponse::Ok().body("User revoked"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::user::UserInfo;
+  use crate::storage::{self, encryption, StorageKind};
+
+  use actix_web::http::StatusCode;
+  use actix_web::test::{call_service, init_service, read_body, TestRequest};
+  use actix_web::App;
+  use serde_json::json;
+
+  // An owner signed in to a fresh state, along with their device token
+  async fn setup() -> (AppState, String) {
+    let _ = encryption::init();
+    let state = State::for_tests(storage::open(StorageKind::Memory, "").unwrap()).unwrap();
+    let token = state.write().await.sign_in_for_tests("owner@b.c", Role::Owner);
+    (state, token)
+  }
+
+  async fn call(state: &AppState, req: TestRequest) -> (StatusCode, String) {
+    let app = init_service(App::new().app_data(web::Data::new(Arc::clone(state))).service(invite_user).service(revoke_user)).await;
+    let res = call_service(&app, req.to_request()).await;
+    let status = res.status();
+    (status, String::from_utf8(read_body(res).await.to_vec()).unwrap())
+  }
+
+  fn invite(token: &str, email: &str, role: &str) -> TestRequest {
+    TestRequest::post().uri("/admin/users").insert_header(("Authorization", token)).set_json(json!({ "email": email, "role": role }))
+  }
+
+  fn revoke(token: &str, email: &str) -> TestRequest {
+    TestRequest::delete().uri(&format!("/admin/users/{}", email)).insert_header(("Authorization", token))
+  }
+
+  fn accept(app_state: &mut State, email: &str) -> Option<String> {
+    app_state.accept_invitation(UserInfo::for_tests(email), String::new(), String::new(), chrono::Utc::now().timestamp() as u64 + 3600)
+  }
+
+  #[actix_web::test]
+  async fn invitations_are_accepted_once_with_their_role() {
+    let (state, token) = setup().await;
+
+    assert_eq!(call(&state, invite(&token, " New@B.c ", "therapist")).await.0, StatusCode::OK);
+    assert_eq!(call(&state, invite(&token, "new@b.c", "owner")).await.0, StatusCode::CONFLICT);
+    assert_eq!(call(&state, invite(&token, "nobody", "therapist")).await.0, StatusCode::BAD_REQUEST);
+
+    let mut app_state = state.write().await;
+    assert_eq!(app_state.invitations["new@b.c"].invited_by, "owner@b.c");
+    assert!(accept(&mut app_state, "stranger@b.c").is_none());
+
+    // Google reports the address however the account spells it
+    let key = accept(&mut app_state, "NEW@b.c").unwrap();
+    assert_eq!(app_state.users[&key].read().await.role, Role::Therapist);
+    assert!(app_state.invitations.is_empty());
+    assert!(accept(&mut app_state, "new@b.c").is_none());
+    drop(app_state);
+
+    // Once accepted the invitation is a user, inviting them again is a conflict
+    assert_eq!(call(&state, invite(&token, "new@b.c", "therapist")).await.0, StatusCode::CONFLICT);
+  }
+
+  #[actix_web::test]
+  async fn revoked_invitations_cant_be_accepted() {
+    let (state, token) = setup().await;
+    call(&state, invite(&token, "new@b.c", "therapist")).await;
+
+    assert_eq!(call(&state, revoke(&token, "NEW@b.c")).await, (StatusCode::OK, "Invitation revoked".into()));
+    assert!(accept(&mut *state.write().await, "new@b.c").is_none());
+    assert_eq!(call(&state, revoke(&token, "new@b.c")).await.0, StatusCode::NOT_FOUND);
+  }
+
+  #[actix_web::test]
+  async fn revoking_a_user_signs_out_their_devices() {
+    let (state, token) = setup().await;
+    let other = state.write().await.sign_in_for_tests("other@b.c", Role::Therapist);
+
+    assert_eq!(call(&state, revoke(&token, "owner@b.c")).await, (StatusCode::CONFLICT, "Can't revoke yourself".into()));
+    // Only owners manage users
+    assert_eq!(call(&state, revoke(&other, "owner@b.c")).await.0, StatusCode::FORBIDDEN);
+
+    assert_eq!(call(&state, revoke(&token, "other@b.c")).await, (StatusCode::OK, "User revoked".into()));
+    let app_state = state.read().await;
+    assert_eq!(app_state.users.len(), 1);
+    assert!(!app_state.devices.contains_key(&other));
+    drop(app_state);
+
+    assert_eq!(call(&state, invite(&other, "new@b.c", "owner")).await.0, StatusCode::UNAUTHORIZED);
+  }
+}
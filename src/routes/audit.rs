@@ -0,0 +1,55 @@
+use crate::audit::EntityKind;
+use crate::state::user::Role;
+use crate::AppState;
+use crate::logs::*;
+
+use std::sync::Arc;
+
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct AuditQuery {
+  patient: Option<String>,
+  session: Option<String>,
+  user: Option<String>,
+  from: Option<u64>,
+  to: Option<u64>,
+  // Cursor from the previous page
+  before: Option<u64>,
+  limit: Option<usize>,
+}
+
+const PAGE: usize = 100;
+const MAX_PAGE: usize = 500;
+
+#[get("/audit")]
+pub async fn index(req: HttpRequest, state: web::Data<AppState>, query: web::Query<AuditQuery>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let access = app_state.access(req).await?;
+  access.require(access.role == Role::Owner)?;
+  let storage = Arc::clone(&app_state.storage);
+  drop(app_state);
+
+  let query = query.into_inner();
+  let limit = query.limit.unwrap_or(PAGE).clamp(1, MAX_PAGE);
+  let page = web::block(move || storage.load_audit(query.before, limit, &|entry| {
+    query.patient.as_ref().is_none_or(|patient| &entry.patient_uuid == patient)
+      && query.session.as_ref().is_none_or(|session| entry.entity == EntityKind::Session && &entry.uuid == session)
+      && query.user.as_ref().is_none_or(|user| &entry.actor == user)
+      && query.from.is_none_or(|from| entry.timestamp >= from)
+      && query.to.is_none_or(|to| entry.timestamp <= to)
+  })).await;
+
+  match page {
+    Ok(Ok(page)) => Ok(HttpResponse::Ok().json(page)),
+    Ok(Err(err)) => {
+      error!("Couldn't load audit log: {}", err);
+      Ok(HttpResponse::InternalServerError().finish())
+    },
+    Err(err) => {
+      error!("Couldn't load audit log: {}", err);
+      Ok(HttpResponse::InternalServerError().finish())
+    },
+  }
+}
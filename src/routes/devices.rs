@@ -0,0 +1,59 @@
+use crate::AppState;
+use crate::logs::*;
+
+use std::cmp::Reverse;
+
+use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse};
+
+#[post("/logout")]
+pub async fn logout(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  let mut app_state = state.write().await;
+  let id = app_state.device(&req)?.id.clone();
+  app_state.revoke_device(&id);
+
+  Ok(HttpResponse::Ok().body("Logged out"))
+}
+
+// Issues a new token for the current device, the old one stops working right away
+#[post("/token/refresh")]
+pub async fn refresh(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  let mut app_state = state.write().await;
+  let id = app_state.device(&req)?.id.clone();
+
+  let token = req.headers().get("Authorization").and_then(|token| token.to_str().ok()).unwrap_or_default();
+  match app_state.rotate_token(token) {
+    Some(token) => {
+      info!("Rotated the token of device {}", id);
+      Ok(HttpResponse::Ok().body(token))
+    },
+    None => Ok(HttpResponse::Unauthorized().body("Unauthorized")),
+  }
+}
+
+#[get("/devices")]
+pub async fn index(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let current = app_state.device(&req)?;
+
+  let mut devices = app_state.devices.values()
+    .filter(|device| device.user == current.user)
+    .map(|device| device.info(device.id == current.id))
+    .collect::<Vec<_>>();
+
+  devices.sort_by_key(|device| Reverse(device.last_seen));
+  Ok(HttpResponse::Ok().json(devices))
+}
+
+#[delete("/devices/{id}")]
+pub async fn revoke(req: HttpRequest, state: web::Data<AppState>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+  let mut app_state = state.write().await;
+  let user = app_state.auth_token(req)?;
+
+  let id = id.into_inner();
+  if !app_state.devices.values().any(|device| device.id == id && device.user == user) {
+    return Ok(HttpResponse::NotFound().body("Device not found"));
+  }
+
+  app_state.revoke_device(&id);
+  Ok(HttpResponse::Ok().body("Device revoked"))
+}
@@ -1,16 +1,15 @@
 use crate::state::session::Session;
 use crate::state::state::{GoogleEvent, SseEvent};
-use crate::filestreamer::FileStreamer;
-use crate::{google, AppState};
+use crate::state::trash::{TrashItem, Trashed};
+use crate::audit::{self, Source};
+use crate::{google, jobs, storage, AppState};
 use crate::logs::*;
 
 use std::collections::HashMap;
-use std::io::{Seek, SeekFrom};
-use std::fs;
+use std::sync::Arc;
 
 use actix_web::{web, HttpRequest, HttpResponse};
 use chrono::Utc;
-use futures::future;
 use reqwest::ClientBuilder;
 use serde::Deserialize;
 use uuid::Uuid;
@@ -88,25 +87,20 @@ pub async fn index(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse
   };
 
   let mut app_state = state.write().await;
-  let path = format!("{}events/{}.json", app_state.path, user);
-
-  let webhook = app_state.calendar_webhooks.get_mut(&user).unwrap();
-  webhook.sync_token = google_req.next_sync_token;
+  let storage = Arc::clone(&app_state.storage);
 
   if google_req.items.len() == 0 {
+    app_state.calendar_webhooks.get_mut(&user).unwrap().sync_token = google_req.next_sync_token;
     warning!("No events from the google calendar");
     return HttpResponse::NoContent().finish();
   }
 
   info!("Got {} events from the google calendar", google_req.items.len());
-  let mut events = match fs::File::open(&path) {
-    Ok(file) => {
-      let mut file = file;
-      file.seek(SeekFrom::Start(0)).unwrap();
-      serde_json::from_reader::<_, Vec<GoogleEvent>>(file).unwrap()
-    },
+  let (reader, owner) = (Arc::clone(&storage), user.clone());
+  let mut events = match storage::run(move || reader.load_events(&owner)).await {
+    Ok(events) => events.unwrap_or_default(),
     Err(err) => {
-      error!("Error while opening the file: {}", err);
+      error!("Error while loading the events: {}", err);
       Vec::new()
     }
   };
@@ -121,14 +115,18 @@ pub async fn index(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse
             app_state.broadcast_to(SseEvent::EventUpdated(&event), &user).await;
             let is_session = app_state.sessions.iter().any(|s| s.calendar_ids.get(&user_email).is_some_and(|id| id == &event.id));
             if (target.start != event.start || target.end != event.end) && is_session {
-              let session = app_state.sessions.iter_mut().find(|s| s.calendar_ids.get(&user_email).is_some_and(|id| id == &event.id)).unwrap();
+              let index = app_state.sessions.iter().position(|s| s.calendar_ids.get(&user_email).is_some_and(|id| id == &event.id)).unwrap();
+              let mut session = app_state.sessions[index].clone();
               session.start = event.start.into_timestamp();
               session.end = event.end.into_timestamp();
               session.last_updated = Utc::now().timestamp() as u64;
-              session.write();
-              
-              let session_clone = session.clone();
-              app_state.broadcast(SseEvent::SessionUpdated(&session_clone)).await;
+              if let Err(err) = audit::updated(&storage, &user_email, Source::Calendar, &app_state.sessions[index], &session).await {
+                return audit::failed(err).error_response();
+              }
+
+              session.write(&storage);
+              app_state.sessions[index] = session.clone();
+              app_state.broadcast(SseEvent::SessionUpdated(&session)).await;
             }
 
             info!("Updated event {} in the local state", event.id);
@@ -154,7 +152,11 @@ pub async fn index(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse
                   calendar_ids: HashMap::from([(user_email.clone(), event.id.to_owned())]),
                 };
 
-                session.write();
+                if let Err(err) = audit::created(&storage, &user_email, Source::Calendar, &session).await {
+                  return audit::failed(err).error_response();
+                }
+
+                session.write(&storage);
                 app_state.broadcast(SseEvent::SessionAdded(&session)).await;
                 app_state.sessions.push(session);
               }
@@ -166,13 +168,16 @@ pub async fn index(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse
       },
       Item::Deleted(event) => {
         events.retain(|e| e.id != event.id);
-        let session = app_state.sessions.iter().find(|s| s.calendar_ids.get(&user_email).is_some_and(|id| id == &event.id));
-        if let Some(session) = session {
-          session.delete();
-          app_state.broadcast(SseEvent::SessionRemoved(&session.uuid)).await;
-          
+        let position = app_state.sessions.iter().position(|s| s.calendar_ids.get(&user_email).is_some_and(|id| id == &event.id));
+        if let Some(position) = position {
+          if let Err(err) = audit::deleted(&storage, &user_email, Source::Calendar, &app_state.sessions[position]).await {
+            return audit::failed(err).error_response();
+          }
+
+          let session = app_state.sessions.remove(position);
           let session_uuid = session.uuid.clone();
-          app_state.sessions.retain(|s| s.uuid != session_uuid);
+          app_state.move_to_trash(TrashItem::new(Trashed::Session { session }, &user_email)).await;
+          app_state.broadcast(SseEvent::SessionRemoved(&session_uuid)).await;
         }
 
         app_state.broadcast_to(SseEvent::EventRemoved(&event.id), &user).await;
@@ -181,25 +186,35 @@ pub async fn index(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse
     }
   }
 
+  // Only moved on once every change is recorded, a failed notification is fetched again with the old token
+  app_state.calendar_webhooks.get_mut(&user).unwrap().sync_token = google_req.next_sync_token;
   app_state.write();
-  fs::write(&path, serde_json::to_string(&events).unwrap()).unwrap();
+  storage::spawn_write(move || {
+    if let Err(err) = storage.save_events(&user, &events) {
+      error!("Error while saving the events: {}", err);
+    }
+  });
+
   HttpResponse::NoContent().finish()
 }
 
 #[actix_web::get("/events")]
 pub async fn get_events(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
   let app_state = state.read().await;
-  let user = app_state.auth_token(req)?;
+  let access = app_state.access(req).await?;
+  access.require(access.role.can_schedule())?;
 
-  let file = match fs::File::open(format!("{}events/{}.json", app_state.path, user)) {
-    Ok(file) => file,
-    Err(_) => {
-      return Ok(HttpResponse::Ok().body("[]"));
-    }
-  };
+  let storage = Arc::clone(&app_state.storage);
+  drop(app_state);
 
-  let streamer = FileStreamer(file);
-  Ok(HttpResponse::Ok().streaming(streamer))
+  match storage::run(move || storage.load_events(&access.token)).await {
+    Ok(Some(events)) => Ok(HttpResponse::Ok().json(events)),
+    Ok(None) => Ok(HttpResponse::Ok().body("[]")),
+    Err(err) => {
+      error!("Error while loading the events: {}", err);
+      Ok(HttpResponse::InternalServerError().finish())
+    }
+  }
 }
 
 #[derive(Debug, Deserialize)]
@@ -215,7 +230,9 @@ struct NewEvent {
 #[actix_web::post("/event")]
 pub async fn create_event(req: HttpRequest, state: web::Data<AppState>, data: web::Json<NewEvent>) -> Result<HttpResponse, actix_web::Error> {
   let mut app_state = state.write().await;
-  let user = app_state.auth_token(req)?;
+  let access = app_state.access(req).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role.can_schedule())?;
 
   let data = data.into_inner();
   let event = google::RawCalendarEvent {
@@ -225,9 +242,10 @@ pub async fn create_event(req: HttpRequest, state: web::Data<AppState>, data: we
     summary: data.summary,
     uuid: String::new(),
     colorId: data.color_id,
+    time_zone: app_state.env.time_zone.clone(),
   };
   
-  let user = app_state.users.get(&user).unwrap().read().await;
+  let user = app_state.users.get(&access.token).unwrap().read().await;
 
   let event_id = match google::add_event(&user.access_token, &event).await {
     Ok(id) => id,
@@ -242,7 +260,7 @@ pub async fn create_event(req: HttpRequest, state: web::Data<AppState>, data: we
     None => return Ok(HttpResponse::Ok().finish()),
   };
 
-  if !app_state.patients.iter().any(|p| p.uuid == patient_uuid) {
+  if !app_state.patients.iter().any(|p| p.uuid == patient_uuid && access.can_see(p)) {
     return Ok(HttpResponse::BadRequest().body("Patient not found"));
   }
 
@@ -262,8 +280,9 @@ pub async fn create_event(req: HttpRequest, state: web::Data<AppState>, data: we
     calendar_ids,
   };
 
+  audit::created(&storage, &user.user_info.email, Source::Api, &session).await.map_err(audit::failed)?;
   drop(user);
-  session.write();
+  session.write(&storage);
 
   let payload = SseEvent::SessionAdded(&session);
   app_state.broadcast(payload).await;
@@ -285,28 +304,28 @@ pub async fn delete_event(req: HttpRequest, state: web::Data<AppState>, body: we
   }
   
   let mut app_state = state.write().await;
-  let user = app_state.check_auth(req)?;
+  let access = app_state.access(req.clone()).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role.can_schedule())?;
+
+  let user = Arc::clone(&app_state.users[&access.token]);
+  let actor = access.email.clone();
   
   if body.kind == 2 || body.kind == 4 {
-    let session = match app_state.sessions.iter().find(|s| s.uuid == body.id) {
-      Some(session) => session,
+    app_state.step_up(&access, &req).await?;
+    let position = match app_state.sessions.iter().position(|s| s.uuid == body.id && app_state.can_see_session(&access, s)) {
+      Some(position) => position,
       None => return Ok(HttpResponse::NotFound().finish()),
     };
 
-    let users = future::join_all(app_state.users.values().map(|u| u.read())).await;
-    for (email, id) in session.calendar_ids.iter() {
-      let user = users.iter().find(|u| &u.user_info.email == email).unwrap();
-      match google::delete_event(&user.access_token, &id).await {
-        Ok(_) => {},
-        Err(err) => { error!("Failed to delete event for user {}: {}", user.user_info.email, err); },
-      };
+    audit::deleted(&storage, &actor, Source::Api, &app_state.sessions[position]).await.map_err(audit::failed)?;
+    let session = app_state.sessions.remove(position);
+    for (email, id) in &session.calendar_ids {
+      jobs::delete_event(&storage, email, id);
     }
 
-    session.delete();
+    app_state.move_to_trash(TrashItem::new(Trashed::Session { session }, &actor)).await;
     app_state.broadcast(SseEvent::SessionRemoved(&body.id)).await;
-
-    drop(users);
-    app_state.sessions.retain(|s| s.uuid != body.id);
   }
 
   if body.kind == 3 {
@@ -331,8 +350,10 @@ struct EditBody {
 #[actix_web::put("/event/{id}")]
 pub async fn edit_event(req: HttpRequest, state: web::Data<AppState>, body: web::Json<EditBody>, id: web::Path<String>) -> Result<HttpResponse, actix_web::Error> {
   let app_state = state.read().await;
-  let user = app_state.check_auth(req)?;
+  let access = app_state.access(req).await?;
+  access.require(access.role.can_schedule())?;
 
+  let user = Arc::clone(&app_state.users[&access.token]);
   let body = body.into_inner();
   let user = user.read().await;
 
@@ -343,6 +364,7 @@ pub async fn edit_event(req: HttpRequest, state: web::Data<AppState>, body: web:
     summary: body.summary,
     id: id.to_string(),
     colorId: body.color_id,
+    time_zone: app_state.env.time_zone.clone(),
   };
 
   if let Err(e) = google::edit_event(&user.access_token, &event).await {
@@ -0,0 +1,39 @@
+use crate::jobs;
+use crate::state::user::{Access, Role};
+use crate::AppState;
+use crate::logs::*;
+
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+
+// Everyone sees the jobs for their own calendar, owners see all of them
+fn can_see(access: &Access, job: &jobs::Job) -> bool {
+  access.role == Role::Owner || job.email == access.email
+}
+
+#[get("/jobs/failed")]
+pub async fn failed(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  let access = state.read().await.access(req).await?;
+
+  let mut failed = jobs::failed().into_iter().filter(|job| can_see(&access, job)).collect::<Vec<_>>();
+  failed.sort_by_key(|job| job.failed_at);
+  Ok(HttpResponse::Ok().json(failed))
+}
+
+#[post("/jobs/{id}/retry")]
+pub async fn retry(req: HttpRequest, state: web::Data<AppState>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let access = app_state.access(req).await?;
+
+  let id = id.into_inner();
+  if !jobs::failed().iter().any(|job| job.id == id && can_see(&access, job)) {
+    return Ok(HttpResponse::NotFound().body("Failed job not found"));
+  }
+
+  match jobs::retry(&app_state.storage, &id) {
+    Some(job) => {
+      info!("{} retried calendar job {} for {}", access.email, job.id, job.email);
+      Ok(HttpResponse::Ok().json(job))
+    },
+    None => Ok(HttpResponse::NotFound().body("Failed job not found")),
+  }
+}
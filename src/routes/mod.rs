@@ -6,6 +6,12 @@ mod oauth;
 mod sse;
 mod settings;
 mod event;
+mod admin;
+mod audit;
+mod trash;
+mod devices;
+mod two_factor;
+mod jobs;
 
 pub fn get_routes() -> Scope {
   web::scope("")
@@ -17,6 +23,10 @@ pub fn get_routes() -> Scope {
 fn api() -> Scope {
   web::scope("/api")
     .service(oauth::auth)
+    .service(devices::logout)
+    .service(devices::refresh)
+    .service(devices::index)
+    .service(devices::revoke)
     .service(sse::get_token)
     .service(sse::stream)
     .service(event::index)
@@ -28,6 +38,27 @@ fn api() -> Scope {
     .service(patients())
     .service(settings::index)
     .service(settings::google_calendar_resync)
+    .service(two_factor::enroll)
+    .service(two_factor::verify)
+    .service(two_factor::backup_codes)
+    .service(two_factor::disable)
+    .service(admin::quarantine)
+    .service(admin::backups)
+    .service(admin::backup_verify)
+    .service(admin::backup_diff)
+    .service(admin::backup_restore)
+    .service(admin::replication_status)
+    .service(admin::lockouts)
+    .service(admin::unlock)
+    .service(admin::users)
+    .service(admin::invite_user)
+    .service(admin::update_user)
+    .service(admin::revoke_user)
+    .service(audit::index)
+    .service(trash::index)
+    .service(trash::restore)
+    .service(jobs::failed)
+    .service(jobs::retry)
 }
 
 fn sessions() -> Scope {
@@ -35,6 +66,7 @@ fn sessions() -> Scope {
     .service(session::create_session)
     .service(session::update_session)
     .service(session::delete_session)
+    .service(session::ticket)
     .service(session::stream)
     .service(session::gen_pdf)
 }
@@ -44,4 +76,5 @@ fn patients() -> Scope {
     .service(patient::create_patient)
     .service(patient::update_patient)
     .service(patient::delete_patient)
+    .service(patient::set_practitioners)
 }
\ No newline at end of file
@@ -1,32 +1,23 @@
 use std::collections::hash_map::Entry;
-use std::sync::Arc;
 
 use crate::state::state::ArcState;
-use crate::state::user::{UserInfo, User};
+use crate::state::user::UserInfo;
 use crate::{AppState, EnvVars};
-use crate::consts;
+use crate::{consts, secrets, tokens};
 use crate::logs::*;
 
 use actix_web::{Responder, web, Either, HttpResponse, HttpRequest};
 use chrono::Utc;
 use futures::future;
 use reqwest::ClientBuilder;
-use tokio::sync::mpsc;
 
 #[actix_web::get("/authorize")]
-pub async fn index(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>) -> impl Responder {
-  let origin = if env.is_production {
-    let origin = req.connection_info().host().to_owned();
-    format!("https://{}", if let Some(strip) = origin.strip_prefix("www.") { strip } else { &origin })
-  } else {
-    format!("http://localhost:{}", env.inner_port)
-  };
-  
-  let appstate = state.read().await;
+pub async fn index(req: HttpRequest, env: web::Data<EnvVars>) -> impl Responder {
+  let origin = env.origin(req.connection_info().host());
   let url = format!("https://accounts.google.com/o/oauth2/v2/auth?scope={}&access_type=offline&response_type=code&redirect_uri={}/oauth&client_id={}&prompt=consent",
     consts::SCOPES.join(" "),
     origin,
-    appstate.secrets.client_id
+    secrets::current().client_id
   );
 
   web::Redirect::to(url).permanent()
@@ -48,12 +39,7 @@ pub struct GoogleResp {
 
 #[actix_web::get("/oauth")]
 pub async fn oauth(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>, query: web::Query<Query>) -> Either<String, web::Redirect> {
-  let origin = if env.is_production {
-    let origin = req.connection_info().host().to_owned();
-    format!("https://{}", if let Some(strip) = origin.strip_prefix("www.") { strip } else { &origin })
-  } else {
-    format!("http://localhost:{}", env.inner_port)
-  };
+  let origin = env.origin(req.connection_info().host());
 
   let (code, scopes) = match query.into_inner() {
     Query::Success { code, scope } => (code, scope),
@@ -67,6 +53,7 @@ pub async fn oauth(req: HttpRequest, state: web::Data<AppState>, env: web::Data<
   }
 
   let appstate = state.read().await;
+  let secrets = secrets::current();
 
   let client = ClientBuilder::new()
     .danger_accept_invalid_certs(true)
@@ -78,8 +65,8 @@ pub async fn oauth(req: HttpRequest, state: web::Data<AppState>, env: web::Data<
     .header("Content-Type", "application/x-www-form-urlencoded")
     .form(&[
       ("code", &code),
-      ("client_id", &appstate.secrets.client_id),
-      ("client_secret", &appstate.secrets.client_secret),
+      ("client_id", &secrets.client_id),
+      ("client_secret", &secrets.client_secret),
       ("redirect_uri", &format!("{}/oauth", origin)),
       ("grant_type", &"authorization_code".into()),
     ])
@@ -111,11 +98,6 @@ pub async fn oauth(req: HttpRequest, state: web::Data<AppState>, env: web::Data<
     Err(err) => return Either::Left(format!("Error: {}", err)),
   };
 
-  if env.users.iter().all(|mail| mail != &user.email) {
-    warning!("Someone tried to authorize with an unauthorized email: {}", user.email);
-    return Either::Left("Error: unauthorized email".into());
-  }
-
   drop(appstate);
   
   let mut appstate = state.write().await;
@@ -128,21 +110,14 @@ pub async fn oauth(req: HttpRequest, state: web::Data<AppState>, env: web::Data<
   let token = match token {
     Some(token) => token,
     None => {
-      let (tx, rx) = mpsc::channel(1);
-      let write_tx = appstate.write_tx.clone();
-
-      let user = User {
-        access_token: res.access_token,
-        user_info: user,
-        settings: Default::default(),
-        expires_at: res.expires_in + Utc::now().timestamp() as u64,
-        refresh_token: res.refresh_token,
-        stop_tx: tx,
-        write_tx,
-        secrets: Arc::clone(&appstate.secrets),
-      };
-
-      appstate.add_new_user(user, rx)
+      let email = user.email.clone();
+      match appstate.accept_invitation(user, res.access_token, res.refresh_token, res.expires_in + Utc::now().timestamp() as u64) {
+        Some(token) => token,
+        None => {
+          warning!("Someone tried to authorize with an unauthorized email: {}", email);
+          return Either::Left("Error: unauthorized email".into());
+        }
+      }
     }
   };
   
@@ -153,16 +128,21 @@ pub async fn oauth(req: HttpRequest, state: web::Data<AppState>, env: web::Data<
   Either::Right(web::Redirect::to(format!("{}/dashboard?code={}", origin, code)))
 }
 
+// Exchanges the code from the OAuth redirect for an access token of a new device
 #[actix_web::post("/auth")]
-pub async fn auth(state: web::Data<AppState>, code: web::Bytes) -> impl Responder {
+pub async fn auth(req: HttpRequest, state: web::Data<AppState>, code: web::Bytes) -> impl Responder {
   let mut appstate = state.write().await;
-  let code = String::from_utf8(code.to_vec()).unwrap();
+  let code = String::from_utf8(code.to_vec()).unwrap_or_default();
+  let code = appstate.auth_codes.keys().fold(None, |found, key| if tokens::matches(key, &code) { Some(key.clone()) } else { found }).unwrap_or(code);
   match appstate.auth_codes.entry(code) {
     Entry::Occupied(entry) => {
-      let token = entry.get().clone();
+      let user = entry.get().clone();
       entry.remove();
+
+      let user_agent = req.headers().get("User-Agent").and_then(|agent| agent.to_str().ok()).unwrap_or_default();
+      let token = appstate.add_device(&user, user_agent.to_owned());
       
-      info!("User {} authenticated", token);
+      info!("User {} authenticated", user);
       HttpResponse::Ok().body(token)
     },
     Entry::Vacant(_) => {
@@ -1,12 +1,16 @@
-use crate::google;
+use crate::audit::{self, Source};
+use crate::jobs;
 use crate::state::state::{SseEvent, DrainWith};
 use crate::state::patient;
+use crate::state::user::Role;
+use crate::state::trash::{TrashItem, Trashed};
 use crate::AppState;
 use crate::logs::*;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use actix_web::{delete, post, patch, web, HttpResponse, HttpRequest};
+use actix_web::{delete, post, patch, put, web, HttpResponse, HttpRequest};
 use serde::Deserialize;
 use uuid::Uuid;
 use futures_util::future;
@@ -21,7 +25,9 @@ struct NewPatient {
 #[post("/")]
 pub async fn create_patient(req: HttpRequest, state: web::Data<AppState>, new_patient: web::Json<NewPatient>) -> Result<HttpResponse, actix_web::Error> {
   let mut state = state.write().await;
-  state.auth_token(req)?;
+  let access = state.access(req).await?;
+  let storage = Arc::clone(&state.storage);
+  access.require(access.role.can_manage_patients())?;
 
   let name = new_patient.name.to_lowercase();
   if state.patients.iter().any(|patient| patient.name.to_lowercase() == name) {
@@ -38,9 +44,12 @@ pub async fn create_patient(req: HttpRequest, state: web::Data<AppState>, new_pa
     profile_picture: None,
     created_at: chrono::Utc::now().timestamp() as u64,
     last_updated: chrono::Utc::now().timestamp() as u64,
+    // Otherwise a therapist couldn't see the patient they just created
+    practitioners: if access.role.sees_all_patients() { Vec::new() } else { vec![access.email.clone()] },
   };
 
-  patient.write();
+  audit::created(&storage, &access.email, Source::Api, &patient).await.map_err(audit::failed)?;
+  patient.write(&storage);
   state.broadcast(SseEvent::PatientAdded(&patient)).await;
   state.patients.push(patient);
   
@@ -59,7 +68,9 @@ struct UpdatePatient {
 #[patch("/{uuid}")]
 pub async fn update_patient(req: HttpRequest, state: web::Data<AppState>, uuid: web::Path<String>, update_patient: web::Json<UpdatePatient>) -> Result<HttpResponse, actix_web::Error> {
   let mut app_state = state.write().await;
-  app_state.auth_token(req)?;
+  let access = app_state.access(req).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role.can_manage_patients())?;
 
   let uuid = uuid.into_inner();
   let name = update_patient.name.as_ref().map(|name| name.to_lowercase());
@@ -67,11 +78,13 @@ pub async fn update_patient(req: HttpRequest, state: web::Data<AppState>, uuid:
     return Ok(HttpResponse::Conflict().body("Patient already exists"));
   }
 
-  let patient = match app_state.patients.iter_mut().find(|patient| patient.uuid == uuid) {
-    Some(patient) => patient,
+  let index = match app_state.patients.iter().position(|patient| patient.uuid == uuid && access.can_see(patient)) {
+    Some(index) => index,
     None => return Ok(HttpResponse::NotFound().body("Patient not found")),
   };
 
+  let before = app_state.patients[index].clone();
+  let mut patient = before.clone();
   let UpdatePatient { name, address, age, description } = update_patient.into_inner();
 
   if let Some(address) = address {
@@ -93,52 +106,19 @@ pub async fn update_patient(req: HttpRequest, state: web::Data<AppState>, uuid:
     do_update = true;
   }
 
-  if do_update {
-    let desc = patient.description.clone();
-    let patient_name = patient.name.clone();
-    let summary = format!("S. {}", patient.name);
-    let state2 = state.clone();
-    tokio::spawn(async move {
-      let state = state2.read().await;
-
-      let sessions = state.sessions.iter().filter(|session| session.patient_uuid == uuid);
-      let mut batches: HashMap<String, Vec<google::EditEvent>> = HashMap::new();
-      
-      for session in sessions {
-        session.calendar_ids.iter().for_each(|(mail, id)| {
-          batches.entry(mail.clone()).or_default().push(google::EditEvent {
-            start: session.start,
-            end: session.end,
-            description: Some(desc.clone()),
-            summary: summary.clone(),
-            id: id.to_owned(),
-            colorId: None,
-          });
-        });
-      }
-
-      let users = state.users.values().map(|u| u.read());
-      let users = future::join_all(users).await;
-
-      for user in users {
-        let entries = match batches.get(&user.user_info.email) {
-          Some(entries) => entries,
-          None => continue,
-        };
+  patient.last_updated = chrono::Utc::now().timestamp() as u64;
+  audit::updated(&storage, &access.email, Source::Api, &before, &patient).await.map_err(audit::failed)?;
+  patient.write(&storage);
+  app_state.patients[index] = patient.clone();
 
-        if let Err(err) = google::edit_events(&user.access_token, &entries[..]).await {
-          error!("Failed to edit events for user {}: {}", user.user_info.email, err);
-        }
+  if do_update {
+    for session in app_state.sessions.iter().filter(|session| session.patient_uuid == patient.uuid) {
+      for (email, id) in &session.calendar_ids {
+        jobs::edit_event(&storage, email, &session.uuid, id);
       }
-
-      info!("Edited {} events for patient {}", batches.len(), patient_name);
-    });
+    }
   }
 
-  patient.last_updated = chrono::Utc::now().timestamp() as u64;
-  patient.write();
-
-  let patient = patient.clone();
   app_state.broadcast(SseEvent::PatientUpdated(&patient)).await;
   
   info!("Updated patient {}", patient.name);
@@ -148,47 +128,95 @@ pub async fn update_patient(req: HttpRequest, state: web::Data<AppState>, uuid:
 #[delete("/{uuid}")]
 pub async fn delete_patient(req: HttpRequest, state: web::Data<AppState>, uuid: web::Path<String>) -> Result<HttpResponse, actix_web::Error> {
   let mut app_state = state.write().await;
-  app_state.auth_token(req)?;
+  let access = app_state.access(req.clone()).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role.can_manage_patients())?;
+  app_state.step_up(&access, &req).await?;
+  let actor = access.email.clone();
 
   let uuid = uuid.into_inner();
-  let patient = match app_state.patients.iter().position(|patient| patient.uuid == uuid) {
-    Some(index) => app_state.patients.remove(index),
+  let index = match app_state.patients.iter().position(|patient| patient.uuid == uuid && access.can_see(patient)) {
+    Some(index) => index,
     None => return Ok(HttpResponse::NotFound().body("Patient not found")),
   };
 
-  let mut event_ids: HashMap<String, Vec<String>> = HashMap::new();
+  let mut batch = audit::Batch::new(&actor, Source::Api);
+  for session in app_state.sessions.iter().filter(|session| session.patient_uuid == uuid) {
+    batch.deleted(session);
+  }
+
+  batch.deleted(&app_state.patients[index]);
+  batch.commit(&storage).await.map_err(audit::failed)?;
+  let patient = app_state.patients.remove(index);
   let sessions = app_state.sessions.drain_with(|session| session.patient_uuid == uuid);
-  for session in sessions {
-    session.delete();
-    session.calendar_ids.iter().for_each(|(mail, id)| {
-      event_ids.entry(mail.clone()).or_default().push(id.clone());
-    });
+  for session in &sessions {
+    for (email, id) in &session.calendar_ids {
+      jobs::delete_event(&storage, email, id);
+    }
   }
 
-  patient.delete();
+  let patient_name = patient.name.clone();
+  app_state.move_to_trash(TrashItem::new(Trashed::Patient { patient, sessions }, &actor)).await;
   app_state.broadcast(SseEvent::PatientRemoved(&uuid)).await;
-  
 
-  let app_state = state.clone();
-  let patient_name = patient.name.clone();
-  tokio::spawn(async move {
-    let app_state = app_state.write().await;
-    let users = app_state.users.values().map(|u| u.read());
-    let users = future::join_all(users).await;
-    for user in users {
-      let entries = match event_ids.get(&user.user_info.email) {
-        Some(entries) => entries,
-        None => continue,
-      };
-
-      if let Err(err) = google::delete_events(&user.access_token, &entries[..]).await {
-        error!("Failed to delete events for user {}: {}", user.user_info.email, err);
-      }
-    }
+  info!("Moved patient {} to the trash", patient_name);
+  Ok(HttpResponse::Ok().body("Patient deleted"))
+}
 
-    info!("Deleted events for patient {}", patient_name);
-  });
+#[put("/{uuid}/practitioners")]
+pub async fn set_practitioners(req: HttpRequest, state: web::Data<AppState>, uuid: web::Path<String>, practitioners: web::Json<Vec<String>>) -> Result<HttpResponse, actix_web::Error> {
+  let mut app_state = state.write().await;
+  let access = app_state.access(req).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role == Role::Owner)?;
+
+  let users = future::join_all(app_state.users.values().map(|u| u.read())).await;
+  let mut practitioners = practitioners.into_inner();
+  if let Some(email) = practitioners.iter().find(|email| !users.iter().any(|u| &&u.user_info.email == email)) {
+    return Ok(HttpResponse::BadRequest().body(format!("Unknown user {}", email)));
+  }
 
-  info!("Deleted patient {}", patient.name);
-  Ok(HttpResponse::Ok().body("Patient deleted"))
-}
\ No newline at end of file
+  drop(users);
+  practitioners.sort();
+  practitioners.dedup();
+
+  let uuid = uuid.into_inner();
+  let index = match app_state.patients.iter().position(|patient| patient.uuid == uuid) {
+    Some(index) => index,
+    None => return Ok(HttpResponse::NotFound().body("Patient not found")),
+  };
+
+  let before = app_state.patients[index].clone();
+  let mut patient = before.clone();
+  patient.practitioners = practitioners;
+  patient.last_updated = chrono::Utc::now().timestamp() as u64;
+  audit::updated(&storage, &access.email, Source::Api, &before, &patient).await.map_err(audit::failed)?;
+  patient.write(&storage);
+  app_state.patients[index] = patient.clone();
+
+  // Clients that gained or lost the patient get it added or removed along with its sessions
+  let sessions = app_state.sessions.iter().filter(|session| session.patient_uuid == uuid).cloned().collect::<Vec<_>>();
+  let clients = app_state.sse.iter().map(|(client, _)| (client.token.clone(), (client.can_see(&before), client.can_see(&patient)))).collect::<HashMap<_, _>>();
+
+  for (token, (saw, sees)) in clients {
+    match (saw, sees) {
+      (false, true) => {
+        app_state.broadcast_to(SseEvent::PatientAdded(&patient), &token).await;
+        for session in &sessions {
+          app_state.broadcast_to(SseEvent::SessionAdded(session), &token).await;
+        }
+      },
+      (true, false) => {
+        for session in &sessions {
+          app_state.broadcast_to(SseEvent::SessionRemoved(&session.uuid), &token).await;
+        }
+        app_state.broadcast_to(SseEvent::PatientRemoved(&patient.uuid), &token).await;
+      },
+      (true, true) => app_state.broadcast_to(SseEvent::PatientUpdated(&patient), &token).await,
+      (false, false) => {},
+    }
+  }
+
+  info!("Assigned patient {} to {} practitioners", patient.name, patient.practitioners.len());
+  Ok(HttpResponse::Ok().body("Practitioners updated"))
+}
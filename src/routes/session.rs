@@ -1,19 +1,19 @@
-use crate::macros::path;
 use crate::state::session::{SessionSocket, Session, Emotion};
-use crate::{AppState, google};
+use crate::{AppState, EnvVars, jobs, shutdown};
 use crate::state::state::SseEvent;
+use crate::state::trash::{TrashItem, Trashed};
+use crate::audit::{self, Source};
 use crate::logs::*;
 
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::{fs, env};
+use std::fs;
 use std::time::Instant;
 
 use actix_web::delete;
 use actix_web::{HttpRequest, web, Error, HttpResponse, get, post, patch};
 use actix_web_actors::ws;
 use chrono::Datelike;
-use futures_util::future;
 use serde::Deserialize;
 use headless_chrome::types::PrintToPdfOptions;
 use headless_chrome::{Browser, LaunchOptions};
@@ -31,11 +31,15 @@ struct NewPatient {
 #[post("/")]
 pub async fn create_session(req: HttpRequest, state: web::Data<AppState>, new_session: web::Json<NewPatient>) -> Result<HttpResponse, actix_web::Error> {
   let mut app_state = state.write().await;
-  app_state.auth_token(req)?;
+  let access = app_state.access(req).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role.can_schedule())?;
+  let actor = access.email.clone();
 
-  if !app_state.patients.iter().any(|patient| patient.uuid == new_session.patient) {
-    return Ok(HttpResponse::NotFound().body("Patient not found"));
-  }
+  let calendars = match app_state.patients.iter().find(|patient| patient.uuid == new_session.patient && access.can_see(patient)) {
+    Some(patient) => app_state.calendars(patient).await,
+    None => return Ok(HttpResponse::NotFound().body("Patient not found")),
+  };
 
   let NewPatient { patient, time_start, time_end } = new_session.into_inner();
   info!("Created session for patient {}", patient);
@@ -43,7 +47,7 @@ pub async fn create_session(req: HttpRequest, state: web::Data<AppState>, new_se
   let emotion_uuid = Uuid::new_v4();
   let uuid = Uuid::new_v4();
 
-  let session = Session {
+  let mut session = Session {
     uuid: uuid.to_string(),
     patient_uuid: patient,
     start: time_start,
@@ -63,43 +67,17 @@ pub async fn create_session(req: HttpRequest, state: web::Data<AppState>, new_se
     calendar_ids: HashMap::new(),
   };
 
-  let patient = app_state.patients.iter().find(|patient| patient.uuid == session.patient_uuid).unwrap();
-  let raw_calendar_event = google::RawCalendarEvent {
-    start: time_start,
-    end: time_end,
-    description: Some(patient.description.to_owned()),
-    summary: format!("S. {}", if patient.name.is_empty() { "<Pacjent bez nazwy>" } else { patient.name.as_str() }),
-    uuid: uuid.to_string(),
-    colorId: None,
-  };
+  // Events are created in the background, their ids are known up front so later edits can find them
+  session.calendar_ids = calendars.into_iter().map(|email| (email, jobs::event_id())).collect();
+
+  audit::created(&storage, &actor, Source::Api, &session).await.map_err(audit::failed)?;
+  for (email, id) in &session.calendar_ids {
+    jobs::create_event(&storage, email, &session.uuid, id);
+  }
 
-  session.write();
+  session.write(&storage);
   app_state.broadcast(SseEvent::SessionAdded(&session)).await;
   app_state.sessions.push(session);
-
-  let app_state = state.clone();
-  tokio::spawn(async move {
-    let mut app_state = app_state.write().await;
-    let mut ids = HashMap::new();
-    
-    for user in app_state.users.values() {      
-      let user = user.read().await;
-      if !user.settings.google_calendar_enabled {
-        continue;
-      }
-
-      match google::add_event(&user.access_token, &raw_calendar_event).await {
-        Ok(id) => { ids.insert(user.user_info.email.clone(), id); },
-        Err(err) => { error!("Failed to add event for user {}: {}", user.user_info.email, err); },
-      };
-    }
-
-    let session = app_state.sessions.iter_mut().find(|s| s.uuid == uuid.to_string()).unwrap();
-    session.calendar_ids = ids;
-
-    session.write();
-    info!("Added events for session {}", uuid);
-  });
   
   Ok(HttpResponse::Ok().body(uuid.to_string()))
 }
@@ -115,19 +93,30 @@ struct UpdateSession {
 #[patch("/{uuid}")]
 pub async fn update_session(req: HttpRequest, state: web::Data<AppState>, payload: web::Json<UpdateSession>, session_uuid: web::Path<String>) -> Result<HttpResponse, Error> {
   let mut app_state = state.write().await;
-  app_state.auth_token(req)?;
+  let access = app_state.access(req).await?;
+  let storage = Arc::clone(&app_state.storage);
+  let actor = access.email.clone();
 
   let UpdateSession { patient, time_start, time_end, paid } = payload.into_inner();
-  if patient.is_some() && !app_state.patients.iter().any(|pt| patient.as_ref().is_some_and(|p| p == &pt.uuid)) {
+  access.require((patient.is_none() && time_start.is_none() && time_end.is_none()) || access.role.can_schedule())?;
+  access.require(paid.is_none() || access.role.can_bill())?;
+
+  if patient.is_some() && !app_state.patients.iter().any(|pt| patient.as_ref().is_some_and(|p| p == &pt.uuid) && access.can_see(pt)) {
     return Ok(HttpResponse::NotFound().body("Patient not found"));
   }
 
   let session_uuid = session_uuid.into_inner();
-  let session = match app_state.sessions.iter_mut().find(|s| s.uuid == session_uuid) {
-    Some(session) => session,
+  if !app_state.sessions.iter().any(|s| s.uuid == session_uuid && app_state.can_see_session(&access, s)) {
+    return Ok(HttpResponse::NotFound().body("Not Found"));
+  }
+
+  let index = match app_state.sessions.iter().position(|s| s.uuid == session_uuid) {
+    Some(index) => index,
     None => return Ok(HttpResponse::NotFound().body("Not Found")),
   };
 
+  let before = app_state.sessions[index].clone();
+  let mut session = before.clone();
   let mut do_update = false;
   if let Some(patient) = patient && patient != session.patient_uuid {
     session.patient_uuid = patient;
@@ -144,50 +133,21 @@ pub async fn update_session(req: HttpRequest, state: web::Data<AppState>, payloa
     do_update = true;
   }
 
-  if do_update {
-    let state2 = state.clone();
-    let ids = session.calendar_ids.clone();
-    let start = session.start;
-    let end = session.end;
-    let patient_uuid = session.patient_uuid.clone();
-    tokio::spawn(async move {
-      let state = state2.read().await;
-    
-      let patient = state.patients.iter().find(|p| p.uuid == patient_uuid).unwrap();
-      let summary = format!("S. {}", patient.name);
-    
-      let users = state.users.values().map(|u| u.read());
-      let users = future::join_all(users).await;
-
-      for (email, id) in ids {
-        let user = users.iter().find(|u| u.user_info.email == email).unwrap();
-        let event_edit = google::EditEvent {
-          start,
-          end,
-          description: Some(patient.description.to_owned()),
-          summary: summary.clone(),
-          id,
-          colorId: None,
-        };
-        
-        match google::edit_event(&user.access_token, &event_edit).await {
-          Ok(_) => {},
-          Err(err) => { error!("Failed to edit event for user {}: {}", user.user_info.email, err); },
-        };
-      }
-
-      info!("Edited events for session {}", session_uuid);
-    });
-  }
-
   if let Some(paid) = paid {
     session.paid = paid;
   }
 
   session.last_updated = chrono::Utc::now().timestamp() as u64;
-  session.write();
+  audit::updated(&storage, &actor, Source::Api, &before, &session).await.map_err(audit::failed)?;
+
+  if do_update {
+    for (email, id) in &session.calendar_ids {
+      jobs::edit_event(&storage, email, &session.uuid, id);
+    }
+  }
 
-  let session = session.clone();
+  session.write(&storage);
+  app_state.sessions[index] = session.clone();
   app_state.broadcast(SseEvent::SessionUpdated(&session)).await;
 
   info!("Updated session {}", session.uuid);
@@ -197,53 +157,70 @@ pub async fn update_session(req: HttpRequest, state: web::Data<AppState>, payloa
 #[delete("/{uuid}")]
 pub async fn delete_session(req: HttpRequest, state: web::Data<AppState>, session: web::Path<String>) -> Result<HttpResponse, Error> {
   let mut app_state = state.write().await;
-  app_state.auth_token(req)?;
+  let access = app_state.access(req.clone()).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role.can_schedule())?;
+  app_state.step_up(&access, &req).await?;
+  let actor = access.email.clone();
 
   let uuid = session.into_inner();
-  let session = match app_state.sessions.iter().find(|s| s.uuid == uuid) {
-    Some(session) => session,
+  let index = match app_state.sessions.iter().position(|s| s.uuid == uuid && app_state.can_see_session(&access, s)) {
+    Some(index) => index,
     None => return Ok(HttpResponse::NotFound().body("Not Found")),
   };
 
-  let calendar_ids = session.calendar_ids.clone();
-  let state = state.clone();
-  let uuid2 = uuid.clone();
-  tokio::spawn(async move {
-    let state = state.write().await;
-    let users = state.users.values().map(|u| u.read());
-    let users = future::join_all(users).await;
-    
-    let len = calendar_ids.len();
-    for (email, id) in calendar_ids {
-      let user = users.iter().find(|u| u.user_info.email == email).unwrap();
-      match google::delete_event(&user.access_token, &id).await {
-        Ok(_) => {},
-        Err(err) => { error!("Failed to delete event for user {}: {}", user.user_info.email, err); },
-      };
-    }
-
-    info!("Deleted {} events for session {}", len, uuid2);
-  });
+  audit::deleted(&storage, &actor, Source::Api, &app_state.sessions[index]).await.map_err(audit::failed)?;
+  let session = app_state.sessions.remove(index);
+  for (email, id) in &session.calendar_ids {
+    jobs::delete_event(&storage, email, id);
+  }
 
-  session.delete();
+  app_state.move_to_trash(TrashItem::new(Trashed::Session { session }, &actor)).await;
   app_state.broadcast(SseEvent::SessionRemoved(&uuid)).await;
-  app_state.sessions.retain(|s| s.uuid != uuid);
 
-  info!("Deleted session {}", uuid);
+  info!("Moved session {} to the trash", uuid);
   Ok(HttpResponse::Ok().body("Deleted"))
 }
 
+// Sockets can't send headers, so the client gets a short-lived ticket here and passes it in the URL
+#[post("/{session}/ticket")]
+pub async fn ticket(req: HttpRequest, state: web::Data<AppState>, session: web::Path<String>) -> Result<HttpResponse, Error> {
+  let mut app_state = state.write().await;
+  let access = app_state.access(req).await?;
+  access.require(access.role.can_read_notes())?;
+
+  let session = session.into_inner();
+  if !app_state.sessions.iter().any(|s| s.uuid == session && app_state.can_see_session(&access, s)) {
+    return Ok(HttpResponse::NotFound().body("Not Found"));
+  }
+
+  Ok(HttpResponse::Ok().body(app_state.issue_socket_ticket(session, access)))
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+  ticket: String,
+}
+
 #[get("/{session}/stream")]
-pub async fn stream(req: HttpRequest, state: web::Data<AppState>, payload: web::Payload, session: web::Path<String>) -> Result<HttpResponse, Error> {
+pub async fn stream(req: HttpRequest, state: web::Data<AppState>, payload: web::Payload, session: web::Path<String>, query: web::Query<StreamQuery>) -> Result<HttpResponse, Error> {
   let session = session.into_inner();
-  let app_state = state.clone();
-  let app_state = app_state.read().await;
-  
+  let mut app_state = state.write().await;
+
+  let access = match app_state.take_socket_ticket(&query.ticket, &session) {
+    Some(access) => access,
+    None => {
+      warning!("Rejected session socket for {} with an invalid ticket", session);
+      return Ok(HttpResponse::Unauthorized().body("Unauthorized"));
+    }
+  };
+
   if !app_state.sessions.iter().any(|s| s.uuid == session) {
     return Ok(HttpResponse::NotFound().body("Not Found"));
   }
 
-  let socket = SessionSocket::new(Arc::clone(&*state.into_inner()), session);
+  drop(app_state);
+  let socket = SessionSocket::new(Arc::clone(&*state.into_inner()), session, access);
   let resp = ws::start(socket, &req, payload)?;
   Ok(resp)
 }
@@ -323,13 +300,15 @@ const KIND_TABLE: [&str; 4] = [
 ];
 
 #[post("/{session}/pdf")]
-pub async fn gen_pdf(req: HttpRequest, state: web::Data<AppState>, session: web::Path<String>) -> Result<HttpResponse, Error> {
+pub async fn gen_pdf(req: HttpRequest, state: web::Data<AppState>, env: web::Data<EnvVars>, session: web::Path<String>) -> Result<HttpResponse, Error> {
   let session = session.into_inner();
   let app_state = state.clone();
   let app_state = app_state.read().await;
 
-  app_state.auth_token(req)?;
-  let session = match app_state.sessions.iter().find(|s| s.uuid == session) {
+  let access = app_state.access(req).await?;
+  access.require(access.role.can_read_notes())?;
+
+  let session = match app_state.sessions.iter().find(|s| s.uuid == session && app_state.can_see_session(&access, s)) {
     Some(session) => session,
     None => return Ok(HttpResponse::NotFound().body("Not Found")),
   };
@@ -339,6 +318,8 @@ pub async fn gen_pdf(req: HttpRequest, state: web::Data<AppState>, session: web:
     None => return Ok(HttpResponse::NotFound().body("Patient not found")),
   };
 
+  // Shutdown waits for the PDF instead of leaving a half-written file behind
+  let _job = shutdown::job();
   info!("Generating PDF for session {}", session.uuid);
   let now = Instant::now();
 
@@ -385,7 +366,7 @@ pub async fn gen_pdf(req: HttpRequest, state: web::Data<AppState>, session: web:
   drop(app_state);
 
   let uuid = Uuid::new_v4();
-  fs::write(format!("{}pdf/{}.html", path(), uuid), html).unwrap();
+  fs::write(format!("{}pdf/{}.html", env.data_dir, uuid), html).unwrap();
 
   let opt = LaunchOptions {
     headless: true,
@@ -428,8 +409,7 @@ pub async fn gen_pdf(req: HttpRequest, state: web::Data<AppState>, session: web:
     transfer_mode: None,
   };
 
-  let is_production = env::var("PRODUCTION").map_or(false, |production| production == "true");
-  let url = if is_production { format!("https://entitia.com/{}/html", uuid) } else { format!("http://localhost:{}/{}/html", env::var("INNER_PORT").unwrap_or("2137".into()), uuid) };
+  let url = format!("{}/{}/html", env.base_url(), uuid);
 
   let tab = match tab.navigate_to(&url) {
     Ok(res) => res,
@@ -455,8 +435,8 @@ pub async fn gen_pdf(req: HttpRequest, state: web::Data<AppState>, session: web:
     },
   };
 
-  fs::write(format!("{}pdf/{}.pdf", path(), uuid), bytes).unwrap();
-  fs::remove_file(format!("{}pdf/{}.html", path(), uuid)).unwrap();
+  fs::write(format!("{}pdf/{}.pdf", env.data_dir, uuid), bytes).unwrap();
+  fs::remove_file(format!("{}pdf/{}.html", env.data_dir, uuid)).unwrap();
 
   info!("Generated PDF for session {}, took {}ms", session_uuid, now.elapsed().as_millis());
 
@@ -8,24 +8,40 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 struct PartialSettings {
   google_calendar_enabled: Option<bool>,
+  step_up_enabled: Option<bool>,
 }
 
 #[patch("/settings")]
 pub async fn index(req: HttpRequest, state: web::Data<AppState>, body: web::Json<PartialSettings>) -> Result<HttpResponse, Error> {
   let app_state = state.read().await;
-  let user = app_state.auth_token(req)?;
+  let access = app_state.access(req.clone()).await?;
+
+  // Otherwise a stolen token could simply switch the confirmation off
+  if body.step_up_enabled == Some(false) {
+    app_state.step_up(&access, &req).await?;
+  }
+
+  let user = access.token;
   let mut user = match app_state.users.get(&user) {
     Some(user) => user.write().await,
     None => {
-      error!("Couldn't find user for token {}", user);
+      error!("Couldn't find the user of {}", access.email);
       return Ok(HttpResponse::InternalServerError().body("Internal Server Error"));
     }
   };
 
+  if body.step_up_enabled == Some(true) && !user.totp.as_ref().is_some_and(|totp| totp.confirmed) {
+    return Ok(HttpResponse::Conflict().body("Two-factor authentication is not enabled"));
+  }
+
   if let Some(google_calendar_enabled) = body.google_calendar_enabled {
     user.settings.google_calendar_enabled = google_calendar_enabled;
   }
 
+  if let Some(step_up_enabled) = body.step_up_enabled {
+    user.settings.step_up_enabled = step_up_enabled;
+  }
+
   info!("Updated settings for user {}", user.user_info.email);
 
   app_state.write();
@@ -39,7 +55,7 @@ pub async fn google_calendar_resync(req: HttpRequest, state: web::Data<AppState>
   let mut _user = match app_state.users.get(&user) {
     Some(user) => user.write().await,
     None => {
-      error!("Couldn't find user for token {}", user);
+      error!("Couldn't find the user behind a valid token");
       return Ok(HttpResponse::InternalServerError().body("Internal Server Error"));
     }
   };
@@ -1,7 +1,7 @@
-use crate::AppState;
+use crate::{tokens, AppState};
 use crate::logs::*;
 use crate::state::state::SseEvent;
-use crate::state::state::State;
+use crate::state::user::Access;
 
 use actix_web::Either;
 use actix_web::Responder;
@@ -18,27 +18,29 @@ struct SseInfo {
 
 #[actix_web::get("/sse/token")]
 pub async fn get_token(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, actix_web::Error> {
-  let app_state = state.read().await;
-  let token = app_state.auth_token(req)?;
+  let mut app_state = state.write().await;
+  let device = app_state.device(&req)?.id.clone();
   
-  let sse_token = match app_state.sse_tokens.get(&token) {
-    Some(sse_token) => sse_token,
-    None => {
-      error!("Couldn't find sse token for user {}", token);
-      return Ok(HttpResponse::Unauthorized().body("Unauthorized"));
-    }
-  };
-
+  let bytes = app_state.env.token_bytes;
+  let sse_token = app_state.sse_tokens.entry(device).or_insert_with(|| tokens::generate(bytes));
   Ok(HttpResponse::Ok().body(sse_token.to_owned()))
 }
 
 #[actix_web::get("/sse/stream")]
 pub async fn stream(state: web::Data<AppState>, query: web::Query<SseInfo>) -> Either<HttpResponse, impl Responder> {
   let mut app_state = state.write().await;
-  let token = match app_state.sse_tokens.iter().find_map(|(k, v)| if v == &query.token { Some(k) } else { None }) {
-    Some(token) => token.to_owned(),
+  let device = match app_state.sse_tokens.iter().fold(None, |found, (k, v)| if tokens::matches(v, &query.token) { Some(k) } else { found }) {
+    Some(device) => device.to_owned(),
     None => {
-      error!("Couldn't find sse token for user {}", query.token);
+      error!("Rejected an unknown sse token");
+      return Either::Left(HttpResponse::Unauthorized().body("Unauthorized"));
+    }
+  };
+
+  let token = match app_state.devices.values().find(|d| d.id == device && !d.is_expired()) {
+    Some(device) => device.user.clone(),
+    None => {
+      error!("Couldn't find device {} for sse token", device);
       return Either::Left(HttpResponse::Unauthorized().body("Unauthorized"));
     }
   };
@@ -46,34 +48,54 @@ pub async fn stream(state: web::Data<AppState>, query: web::Query<SseInfo>) -> E
   let user = match app_state.users.get(&token) {
     Some(user) => user.read().await,
     None => {
-      error!("Couldn't find user for token {}", token);
+      error!("Couldn't find the user of device {}", device);
       return Either::Left(HttpResponse::InternalServerError().body("Internal Server Error"));
     }
   };
 
+  let access = Access {
+    token: token.clone(),
+    device: device.clone(),
+    email: user.user_info.email.clone(),
+    role: user.role,
+  };
+
+  // Therapists only get their own patients and roles that can't read notes get them redacted
+  let notes = access.role.can_read_notes();
+  let patients = app_state.patients.iter()
+    .filter(|patient| access.can_see(patient))
+    .map(|patient| if notes { patient.clone() } else { patient.redacted() })
+    .collect::<Vec<_>>();
+  let sessions = app_state.sessions.iter()
+    .filter(|session| app_state.can_see_session(&access, session))
+    .map(|session| if notes { session.clone() } else { session.redacted() })
+    .collect::<Vec<_>>();
+
   let (tx, rx) = mpsc::channel(10);
 
   let msg = SseEvent::Ready {
-    patients: &app_state.patients,
-    sessions: &app_state.sessions,
+    patients: &patients,
+    sessions: &sessions,
     user_mail: &user.user_info.email,
     user_avatar: &user.user_info.picture,
     settings: &user.settings,
+    role: user.role,
   };
 
   let tx2 = tx.clone();
   let msg = serde_json::to_string(&msg).unwrap();
   drop(user);
   
-  info!("User {} connected to sse", token.clone());
+  info!("Device {} connected to sse", device);
   tokio::spawn(async move {
     if let Err(err) = tx2.send(sse::Data::new(msg).into()).await {
       error!("Couldn't send message to sse channel: {}", err);
     }
   });
   
-  app_state.sse.push((token.clone(), tx));
-  app_state.sse_tokens.entry(token).and_modify(|e| *e = State::generate_token());
+  app_state.sse.push((access, tx));
+  let bytes = app_state.env.token_bytes;
+  app_state.sse_tokens.entry(device).and_modify(|e| *e = tokens::generate(bytes));
   
   drop(app_state);
   Either::Right(Sse::from_infallible_receiver(rx))
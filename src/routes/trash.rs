@@ -0,0 +1,202 @@
+use crate::audit::{self, Source};
+use crate::jobs;
+use crate::state::state::SseEvent;
+use crate::state::trash::Trashed;
+use crate::AppState;
+use crate::logs::*;
+
+use std::sync::Arc;
+
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
+
+#[get("/trash")]
+pub async fn index(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let access = app_state.access(req).await?;
+  access.require(access.role.can_manage_patients())?;
+
+  let items = app_state.trash.iter().filter(|item| app_state.can_see_trash(&access, item)).collect::<Vec<_>>();
+  Ok(HttpResponse::Ok().json(items))
+}
+
+#[post("/trash/{uuid}/restore")]
+pub async fn restore(req: HttpRequest, state: web::Data<AppState>, uuid: web::Path<String>) -> Result<HttpResponse, Error> {
+  let mut app_state = state.write().await;
+  let access = app_state.access(req).await?;
+  let storage = Arc::clone(&app_state.storage);
+  access.require(access.role.can_manage_patients())?;
+  let actor = access.email.clone();
+
+  let uuid = uuid.into_inner();
+  let position = match app_state.trash.iter().position(|item| item.uuid == uuid && app_state.can_see_trash(&access, item)) {
+    Some(position) => position,
+    None => return Ok(HttpResponse::NotFound().body("Not found in the trash")),
+  };
+
+  match &app_state.trash[position].item {
+    Trashed::Patient { patient, .. } => {
+      let name = patient.name.to_lowercase();
+      if app_state.patients.iter().any(|patient| patient.name.to_lowercase() == name) {
+        return Ok(HttpResponse::Conflict().body("Patient already exists"));
+      }
+    },
+    Trashed::Session { session } => {
+      if !app_state.patients.iter().any(|patient| patient.uuid == session.patient_uuid) {
+        return Ok(HttpResponse::Conflict().body("Patient of this session no longer exists"));
+      }
+    },
+  }
+
+  let (patient, sessions) = match app_state.trash[position].item.clone() {
+    Trashed::Patient { patient, sessions } => (Some(patient), sessions),
+    Trashed::Session { session } => (None, vec![session]),
+  };
+
+  // Calendar events were removed when the records were trashed, so they have to be created again
+  let owner = patient.as_ref().or_else(|| sessions.first().and_then(|session| app_state.patients.iter().find(|p| p.uuid == session.patient_uuid)));
+  let calendars = match owner {
+    Some(owner) => app_state.calendars(owner).await,
+    None => Vec::new(),
+  };
+
+  let sessions = sessions.into_iter().map(|mut session| {
+    if !session.calendar_ids.is_empty() {
+      session.calendar_ids = calendars.iter().map(|email| (email.clone(), jobs::event_id())).collect();
+    }
+    session
+  }).collect::<Vec<_>>();
+
+  let mut batch = audit::Batch::new(&actor, Source::Api);
+  if let Some(patient) = &patient {
+    batch.restored(patient);
+  }
+  for session in &sessions {
+    batch.restored(session);
+  }
+  batch.commit(&storage).await.map_err(audit::failed)?;
+
+  // Restored records are written before the trash item is removed so a crash can't lose them
+  let item = app_state.trash.remove(position);
+  if let Some(patient) = patient {
+    patient.write(&storage);
+    app_state.broadcast(SseEvent::PatientAdded(&patient)).await;
+    app_state.patients.push(patient);
+  }
+
+  for session in sessions {
+    for (email, id) in &session.calendar_ids {
+      jobs::create_event(&storage, email, &session.uuid, id);
+    }
+
+    session.write(&storage);
+    app_state.broadcast(SseEvent::SessionAdded(&session)).await;
+    app_state.sessions.push(session);
+  }
+
+  item.delete(&storage);
+  app_state.broadcast(SseEvent::TrashRemoved(&item.uuid)).await;
+
+  info!("Restored {} from the trash", item.uuid);
+  Ok(HttpResponse::Ok().body("Restored"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audit::Action;
+  use crate::state::patient::Patient;
+  use crate::state::session::Session;
+  use crate::state::state::State;
+  use crate::state::trash::TrashItem;
+  use crate::state::user::Role;
+  use crate::storage::{self, encryption, Storage, StorageKind};
+
+  use actix_web::http::StatusCode;
+  use actix_web::test::{call_service, init_service, TestRequest};
+  use actix_web::App;
+
+  fn patient(uuid: &str, name: &str) -> Patient {
+    Patient { uuid: uuid.into(), name: name.into(), practitioners: vec!["t@b.c".into()], ..Default::default() }
+  }
+
+  fn session(uuid: &str, patient: &str) -> Session {
+    Session { uuid: uuid.into(), patient_uuid: patient.into(), ..Default::default() }
+  }
+
+  fn setup() -> (Arc<dyn Storage>, AppState) {
+    let _ = encryption::init();
+    let storage = storage::open(StorageKind::Memory, "").unwrap();
+    let state = State::for_tests(Arc::clone(&storage)).unwrap();
+    (storage, state)
+  }
+
+  async fn restore_as(state: &AppState, token: &str, uuid: &str) -> StatusCode {
+    let app = init_service(App::new().app_data(web::Data::new(Arc::clone(state))).service(restore)).await;
+    let req = TestRequest::post().uri(&format!("/trash/{}/restore", uuid)).insert_header(("Authorization", token)).to_request();
+    call_service(&app, req).await.status()
+  }
+
+  #[actix_web::test]
+  async fn restores_a_patient_with_its_sessions() {
+    let (storage, state) = setup();
+    let token = {
+      let mut app_state = state.write().await;
+      let item = TrashItem::new(Trashed::Patient { patient: patient("p1", "Jan"), sessions: vec![session("s1", "p1")] }, "a@b.c");
+      app_state.move_to_trash(item).await;
+      app_state.sign_in_for_tests("a@b.c", Role::Owner)
+    };
+
+    assert_eq!(restore_as(&state, &token, "p1").await, StatusCode::OK);
+    assert_eq!(restore_as(&state, &token, "p1").await, StatusCode::NOT_FOUND);
+    storage::run(|| Ok(())).await.unwrap();
+
+    let app_state = state.read().await;
+    assert!(app_state.trash.is_empty());
+    assert_eq!(app_state.patients.len(), 1);
+    assert_eq!(app_state.sessions[0].uuid, "s1");
+
+    assert!(storage.load_trash().unwrap().is_empty());
+    assert_eq!(storage.load_patients().unwrap()[0].uuid, "p1");
+    assert_eq!(storage.load_sessions().unwrap()[0].uuid, "s1");
+
+    let page = storage.load_audit(None, 10, &|entry| entry.action == Action::Restore).unwrap();
+    assert_eq!(page.entries.len(), 2);
+    assert!(page.entries.iter().all(|entry| entry.actor == "a@b.c"));
+  }
+
+  #[actix_web::test]
+  async fn refuses_restores_that_would_clash_or_dangle() {
+    let (_, state) = setup();
+    let token = {
+      let mut app_state = state.write().await;
+      app_state.patients.push(patient("p2", "jan"));
+      app_state.move_to_trash(TrashItem::new(Trashed::Patient { patient: patient("p1", "Jan"), sessions: Vec::new() }, "a@b.c")).await;
+      app_state.move_to_trash(TrashItem::new(Trashed::Session { session: session("s1", "p3") }, "a@b.c")).await;
+      app_state.sign_in_for_tests("a@b.c", Role::Owner)
+    };
+
+    // Another patient took the name in the meantime
+    assert_eq!(restore_as(&state, &token, "p1").await, StatusCode::CONFLICT);
+    // The session's patient is gone for good
+    assert_eq!(restore_as(&state, &token, "s1").await, StatusCode::CONFLICT);
+    assert_eq!(state.read().await.trash.len(), 2);
+  }
+
+  #[actix_web::test]
+  async fn only_shows_the_trash_to_those_who_may_restore_it() {
+    let (_, state) = setup();
+    let (therapist, other, assistant) = {
+      let mut app_state = state.write().await;
+      app_state.move_to_trash(TrashItem::new(Trashed::Patient { patient: patient("p1", "Jan"), sessions: Vec::new() }, "a@b.c")).await;
+      (
+        app_state.sign_in_for_tests("t@b.c", Role::Therapist),
+        app_state.sign_in_for_tests("o@b.c", Role::Therapist),
+        app_state.sign_in_for_tests("s@b.c", Role::Assistant),
+      )
+    };
+
+    assert_eq!(restore_as(&state, &assistant, "p1").await, StatusCode::FORBIDDEN);
+    assert_eq!(restore_as(&state, &other, "p1").await, StatusCode::NOT_FOUND);
+    assert_eq!(restore_as(&state, &therapist, "p1").await, StatusCode::OK);
+  }
+}
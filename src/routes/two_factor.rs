@@ -0,0 +1,110 @@
+use crate::AppState;
+use crate::logs::*;
+use crate::totp::Totp;
+
+use actix_web::{delete, post, web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+struct CodeBody {
+  code: String,
+}
+
+// Starts over with a new secret until the enrollment is verified
+#[post("/2fa/enroll")]
+pub async fn enroll(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let access = app_state.access(req).await?;
+  let mut user = app_state.users[&access.token].write().await;
+
+  if user.totp.as_ref().is_some_and(|totp| totp.confirmed) {
+    return Ok(HttpResponse::Conflict().body("Two-factor authentication is already enabled"));
+  }
+
+  let totp = Totp::new();
+  let body = json!({ "secret": totp.secret, "url": totp.url(&access.email) });
+  user.totp = Some(totp);
+
+  drop(user);
+  app_state.write();
+  Ok(HttpResponse::Ok().json(body))
+}
+
+// The backup codes are only ever shown in this response
+#[post("/2fa/verify")]
+pub async fn verify(req: HttpRequest, state: web::Data<AppState>, body: web::Json<CodeBody>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let access = app_state.access(req).await?;
+  let mut user = app_state.users[&access.token].write().await;
+
+  let totp = match user.totp.as_mut() {
+    Some(totp) if !totp.confirmed => totp,
+    Some(_) => return Ok(HttpResponse::Conflict().body("Two-factor authentication is already enabled")),
+    None => return Ok(HttpResponse::NotFound().body("No enrollment in progress")),
+  };
+
+  if !totp.verify(body.code.trim()) {
+    return Ok(HttpResponse::Forbidden().body("Invalid code"));
+  }
+
+  totp.confirmed = true;
+  let codes = totp.regenerate_backup_codes();
+  info!("{} enabled two-factor authentication", access.email);
+
+  drop(user);
+  app_state.write();
+  Ok(HttpResponse::Ok().json(json!({ "backup_codes": codes })))
+}
+
+#[post("/2fa/backup-codes")]
+pub async fn backup_codes(req: HttpRequest, state: web::Data<AppState>, body: web::Json<CodeBody>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let access = app_state.access(req).await?;
+  let mut user = app_state.users[&access.token].write().await;
+
+  let totp = match user.totp.as_mut() {
+    Some(totp) if totp.confirmed => totp,
+    _ => return Ok(HttpResponse::NotFound().body("Two-factor authentication is not enabled")),
+  };
+
+  let confirmed = totp.confirm(body.code.trim());
+  let codes = confirmed.then(|| totp.regenerate_backup_codes());
+
+  drop(user);
+  app_state.write();
+  match codes {
+    Some(codes) => {
+      info!("{} regenerated their backup codes", access.email);
+      Ok(HttpResponse::Ok().json(json!({ "backup_codes": codes })))
+    },
+    None => Ok(HttpResponse::Forbidden().body("Invalid code")),
+  }
+}
+
+// Turning two-factor off also turns off the step-up confirmation that depends on it
+#[delete("/2fa")]
+pub async fn disable(req: HttpRequest, state: web::Data<AppState>, body: web::Json<CodeBody>) -> Result<HttpResponse, Error> {
+  let app_state = state.read().await;
+  let access = app_state.access(req).await?;
+  let mut user = app_state.users[&access.token].write().await;
+
+  let confirmed = match user.totp.as_mut() {
+    Some(totp) if totp.confirmed => totp.confirm(body.code.trim()),
+    _ => return Ok(HttpResponse::NotFound().body("Two-factor authentication is not enabled")),
+  };
+
+  if confirmed {
+    user.totp = None;
+    user.settings.step_up_enabled = false;
+    warning!("{} disabled two-factor authentication", access.email);
+  }
+
+  drop(user);
+  app_state.write();
+  if confirmed {
+    Ok(HttpResponse::NoContent().finish())
+  } else {
+    Ok(HttpResponse::Forbidden().body("Invalid code"))
+  }
+}
@@ -0,0 +1,94 @@
+use crate::logs::*;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::signal::unix::{signal, SignalKind};
+
+// Where `docker secret` and compose `secrets:` mount their files
+const DOCKER_SECRETS: &str = "/run/secrets";
+
+#[derive(Debug, Deserialize)]
+pub struct Secrets {
+  pub client_id: String,
+  pub client_secret: String,
+}
+
+// The file from the config, kept so SIGHUP can read it again
+static FILE: OnceLock<Option<String>> = OnceLock::new();
+static SECRETS: OnceLock<RwLock<Arc<Secrets>>> = OnceLock::new();
+
+// Looked up in order: GOOGLE_CLIENT_ID and GOOGLE_CLIENT_SECRET, the secrets_file from the config (either
+// {"client_id", "client_secret"} or the JSON Google's console downloads), then the google_client_id and
+// google_client_secret Docker secrets
+fn load() -> io::Result<(Secrets, String)> {
+  if let (Ok(client_id), Ok(client_secret)) = (env::var("GOOGLE_CLIENT_ID"), env::var("GOOGLE_CLIENT_SECRET")) {
+    return Ok((Secrets { client_id, client_secret }, "the environment".into()));
+  }
+
+  if let Some(Some(file)) = FILE.get() {
+    let contents = fs::read_to_string(file).map_err(|err| io::Error::new(err.kind(), format!("Couldn't read {}: {}", file, err)))?;
+    let json = serde_json::from_str::<Value>(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{} isn't valid JSON: {}", file, err)))?;
+    let json = json.get("web").or(json.get("installed")).unwrap_or(&json).clone();
+
+    let secrets = serde_json::from_value(json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, err)))?;
+    return Ok((secrets, file.clone()));
+  }
+
+  let docker = |name: &str| fs::read_to_string(Path::new(DOCKER_SECRETS).join(name)).map(|value| value.trim().to_owned());
+  if let (Ok(client_id), Ok(client_secret)) = (docker("google_client_id"), docker("google_client_secret")) {
+    return Ok((Secrets { client_id, client_secret }, format!("Docker secrets in {}", DOCKER_SECRETS)));
+  }
+
+  Err(io::Error::new(io::ErrorKind::NotFound, format!(
+    "Google OAuth credentials are missing: set GOOGLE_CLIENT_ID and GOOGLE_CLIENT_SECRET, point secrets_file (or SECRETS_FILE) at the client JSON, or mount google_client_id and google_client_secret in {}",
+    DOCKER_SECRETS,
+  )))
+}
+
+fn validated() -> io::Result<Secrets> {
+  let (secrets, source) = load()?;
+  if secrets.client_id.trim().is_empty() || secrets.client_secret.trim().is_empty() {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The Google OAuth credentials from {} are empty", source)));
+  }
+
+  info!("Loaded Google OAuth credentials from {}", source);
+  Ok(secrets)
+}
+
+// Refuses to start without credentials, every sign-in and token refresh needs them
+pub fn init(file: Option<&str>) -> io::Result<()> {
+  FILE.set(file.map(str::to_owned)).map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "Secrets already initialized"))?;
+  let secrets = validated()?;
+
+  SECRETS.set(RwLock::new(Arc::new(secrets))).map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "Secrets already initialized"))
+}
+
+pub fn current() -> Arc<Secrets> {
+  Arc::clone(&SECRETS.get().expect("Secrets not initialized").read().unwrap())
+}
+
+// A failed reload keeps the credentials that were working
+pub fn reload() {
+  match validated() {
+    Ok(secrets) => *SECRETS.get().expect("Secrets not initialized").write().unwrap() = Arc::new(secrets),
+    Err(err) => error!("Couldn't reload the Google OAuth credentials, keeping the previous ones: {}", err),
+  }
+}
+
+pub fn start_reload_on_sighup() -> io::Result<()> {
+  let mut hangup = signal(SignalKind::hangup())?;
+  tokio::spawn(async move {
+    while hangup.recv().await.is_some() {
+      info!("Received SIGHUP, reloading the Google OAuth credentials...");
+      reload();
+    }
+  });
+
+  Ok(())
+}
@@ -0,0 +1,167 @@
+use crate::state::session::GoingAway;
+use crate::state::state::SseEvent;
+use crate::{backup, AppState, EnvVars};
+use crate::logs::*;
+
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use actix_web::dev::Server;
+use futures::future;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::time;
+
+// How long in-flight requests get to finish, and then how long background jobs get
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+// How long the final backup gets. It isn't replicated, the first backup round after the next start does that.
+const BACKUP_TIMEOUT: Duration = Duration::from_secs(60);
+// The longest shutdown can take besides flushing the state, the stop grace period (e.g. `docker stop -t`)
+// should be at least this long or the final backup may be cut off
+const BUDGET: Duration = Duration::from_secs(2 * TIMEOUT.as_secs() + BACKUP_TIMEOUT.as_secs());
+const REASON: &str = "Server is shutting down";
+
+static JOBS: AtomicUsize = AtomicUsize::new(0);
+static FINISHED: Notify = Notify::const_new();
+
+// Held for as long as work that shouldn't be cut off runs, shutdown waits until every one is dropped
+pub struct Job;
+
+impl Drop for Job {
+  fn drop(&mut self) {
+    if JOBS.fetch_sub(1, Ordering::SeqCst) == 1 {
+      FINISHED.notify_waiters();
+    }
+  }
+}
+
+pub fn job() -> Job {
+  JOBS.fetch_add(1, Ordering::SeqCst);
+  Job
+}
+
+// tokio::spawn for writes and Google calendar edits, shutdown waits for them before the final flush
+pub fn spawn<F>(fut: F)
+where
+  F: Future<Output = ()> + Send + 'static,
+{
+  let job = job();
+  tokio::spawn(async move {
+    fut.await;
+    drop(job);
+  });
+}
+
+async fn drain() {
+  loop {
+    let finished = FINISHED.notified();
+    if JOBS.load(Ordering::SeqCst) == 0 {
+      return;
+    }
+
+    finished.await;
+  }
+}
+
+// Runs the servers until SIGTERM or Ctrl+C, then stops accepting requests, closes the live connections,
+// waits for in-flight work and writes everything out before returning
+pub async fn serve(state: AppState, servers: Vec<Server>, env: &EnvVars) -> io::Result<()> {
+  let handles = servers.iter().map(Server::handle).collect::<Vec<_>>();
+  let mut running = tokio::spawn(future::try_join_all(servers));
+  let mut terminate = signal(SignalKind::terminate())?;
+
+  tokio::select! {
+    res = &mut running => return res.map_err(io::Error::other)?.map(|_| ()),
+    _ = terminate.recv() => info!("Received SIGTERM, shutting down within {}s...", BUDGET.as_secs()),
+    _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down within {}s...", BUDGET.as_secs()),
+  }
+
+  // Listeners close right away, requests that are already being handled get up to TIMEOUT
+  let stopped = future::join_all(handles.iter().map(|handle| handle.stop(true)));
+  close_clients(&state).await;
+  stopped.await;
+
+  if let Ok(Err(err)) = running.await {
+    error!("Server stopped with an error: {}", err);
+  }
+
+  finish(&state, env, TIMEOUT).await;
+  info!("Shut down");
+  Ok(())
+}
+
+// Waits up to `timeout` for the background jobs, then writes the state and takes the final backup
+async fn finish(state: &AppState, env: &EnvVars, timeout: Duration) {
+  let jobs = JOBS.load(Ordering::SeqCst);
+  if jobs > 0 {
+    info!("Waiting for {} background jobs...", jobs);
+  }
+
+  if time::timeout(timeout, drain()).await.is_err() {
+    warning!("{} background jobs didn't finish within {}s, abandoning them", JOBS.load(Ordering::SeqCst), timeout.as_secs());
+  }
+
+  let app_state = state.read().await;
+  match app_state.flush().await {
+    Ok(()) => info!("Flushed the state"),
+    Err(err) => error!("Couldn't flush the state: {}", err),
+  }
+
+  let storage = Arc::clone(&app_state.storage);
+  drop(app_state);
+  if time::timeout(BACKUP_TIMEOUT, backup::archive(&*storage, env)).await.is_err() {
+    warning!("The final backup didn't finish within {}s, abandoning it", BACKUP_TIMEOUT.as_secs());
+  }
+}
+
+async fn close_clients(state: &AppState) {
+  let mut app_state = state.write().await;
+  app_state.broadcast(SseEvent::ShuttingDown(REASON)).await;
+
+  // Dropping the senders ends the SSE streams
+  let streams = app_state.sse.drain(..).count();
+  let sockets = app_state.sockets.drain(..).map(|(_, addr)| addr.do_send(GoingAway(REASON.into()))).count();
+  info!("Closed {} SSE streams and {} session sockets", streams, sockets);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::state::State;
+  use crate::storage::{self, encryption, StorageKind};
+
+  use std::sync::atomic::AtomicBool;
+  use std::time::Instant;
+
+  #[actix_web::test]
+  async fn waits_for_jobs_then_flushes() {
+    let _ = encryption::init();
+    let storage = storage::open(StorageKind::Memory, "").unwrap();
+    let state = State::for_tests(Arc::clone(&storage)).unwrap();
+    let env = EnvVars::default();
+
+    // A job that never finishes is given up on after the timeout, the state is written regardless
+    let stuck = job();
+    let started = Instant::now();
+    finish(&state, &env, Duration::from_millis(200)).await;
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert!(storage.load_state().unwrap().is_some());
+    drop(stuck);
+
+    // One that finishes in time is waited for
+    let done = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&done);
+    spawn(async move {
+      time::sleep(Duration::from_millis(100)).await;
+      flag.store(true, Ordering::SeqCst);
+    });
+
+    let started = Instant::now();
+    finish(&state, &env, Duration::from_secs(5)).await;
+    assert!(done.load(Ordering::SeqCst));
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+}
@@ -0,0 +1,99 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// Tokens expire after this long without being used
+pub const TOKEN_TTL: u64 = 30 * 24 * 60 * 60;
+
+// One signed-in browser, keyed by its access token in State.devices
+#[derive(Debug)]
+pub struct Device {
+  pub id: String,
+  // Key of the user in State.users
+  pub user: String,
+  pub user_agent: String,
+  pub created_at: u64,
+  pub last_seen: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RwDevice {
+  pub id: String,
+  pub user: String,
+  #[serde(default)]
+  pub user_agent: String,
+  pub created_at: u64,
+  pub last_seen: u64,
+}
+
+#[derive(Serialize)]
+pub struct DeviceInfo<'a> {
+  pub id: &'a str,
+  pub user_agent: &'a str,
+  pub created_at: u64,
+  pub last_seen: u64,
+  pub expires_at: u64,
+  pub current: bool,
+}
+
+impl Device {
+  pub fn new(id: String, user: String, user_agent: String) -> Self {
+    let now = Utc::now().timestamp() as u64;
+    Device {
+      id,
+      user,
+      user_agent,
+      created_at: now,
+      last_seen: AtomicU64::new(now),
+    }
+  }
+
+  pub fn from_rw(device: RwDevice) -> Self {
+    Device {
+      id: device.id,
+      user: device.user,
+      user_agent: device.user_agent,
+      created_at: device.created_at,
+      last_seen: AtomicU64::new(device.last_seen),
+    }
+  }
+
+  pub fn to_rw(&self) -> RwDevice {
+    RwDevice {
+      id: self.id.clone(),
+      user: self.user.clone(),
+      user_agent: self.user_agent.clone(),
+      created_at: self.created_at,
+      last_seen: self.last_seen(),
+    }
+  }
+
+  pub fn last_seen(&self) -> u64 {
+    self.last_seen.load(Ordering::Relaxed)
+  }
+
+  pub fn expires_at(&self) -> u64 {
+    self.last_seen() + TOKEN_TTL
+  }
+
+  pub fn is_expired(&self) -> bool {
+    self.expires_at() <= Utc::now().timestamp() as u64
+  }
+
+  // Every authenticated request pushes the expiry back
+  pub fn touch(&self) {
+    self.last_seen.store(Utc::now().timestamp() as u64, Ordering::Relaxed);
+  }
+
+  pub fn info(&self, current: bool) -> DeviceInfo<'_> {
+    DeviceInfo {
+      id: &self.id,
+      user_agent: &self.user_agent,
+      created_at: self.created_at,
+      last_seen: self.last_seen(),
+      expires_at: self.expires_at(),
+      current,
+    }
+  }
+}
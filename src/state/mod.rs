@@ -1,6 +1,8 @@
 pub mod patient;
 pub mod session;
 pub mod user;
+pub mod device;
+pub mod trash;
 
 #[allow(clippy::module_inception)]
 pub mod state;
\ No newline at end of file
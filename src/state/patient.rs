@@ -1,7 +1,8 @@
+use crate::storage::migrations::Document;
+use crate::storage::{self, Storage};
 use crate::logs::*;
 
-use std::path::Path;
-use std::{fs, io};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
@@ -15,10 +16,14 @@ pub struct Patient {
   pub profile_picture: Option<String>,
   pub created_at: u64,
   pub last_updated: u64,
+  // Emails of the therapists the patient is assigned to
+  #[serde(default)]
+  pub practitioners: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct FsPatient {
+pub struct FsPatient {
+  schema_version: u32,
   description: String,
   age: Option<u8>,
   name: String,
@@ -26,20 +31,13 @@ struct FsPatient {
   profile_picture: Option<String>,
   created_at: u64,
   last_updated: u64,
+  practitioners: Vec<String>,
 }
 
 impl Patient {
-  pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-    let file = fs::read_to_string(path.as_ref())?;
-    let fs_patient = serde_json::from_str::<FsPatient>(&file);
-
-    if fs_patient.is_err() {
-      log::error!("Couldn't parse patient file {}.json", path.as_ref().file_stem().unwrap().to_str().unwrap());
-    }
-
-    let fs_patient = fs_patient?;
-    let patient = Patient {
-      uuid: path.as_ref().file_stem().unwrap().to_str().unwrap().to_owned(),
+  pub fn from_fs(uuid: String, fs_patient: FsPatient) -> Self {
+    Patient {
+      uuid,
       description: fs_patient.description,
       age: fs_patient.age,
       name: fs_patient.name,
@@ -47,30 +45,13 @@ impl Patient {
       profile_picture: fs_patient.profile_picture,
       created_at: fs_patient.created_at,
       last_updated: fs_patient.last_updated,
-    };
-
-    Ok(patient)
-  }
-
-  pub fn from_dir<P: AsRef<Path>>(path: P) -> io::Result<Vec<Self>> {
-    let dir = fs::read_dir(path)?;
-    let mut patients = Vec::new();
-
-    for entry in dir {
-      let path = entry?.path();
-      if path.is_file() {
-        let patient = Patient::from_file(path)?;
-        patients.push(patient);
-      }
+      practitioners: fs_patient.practitioners,
     }
-
-    info!("Loaded {} patients", patients.len());
-    Ok(patients)
   }
 
-  pub fn write(&self) {
-    let path = format!("{}patients/{}.json", fspath!(), self.uuid);
-    let fs_patient = FsPatient {
+  pub fn to_fs(&self) -> FsPatient {
+    FsPatient {
+      schema_version: Document::Patient.version(),
       description: self.description.clone(),
       age: self.age,
       name: self.name.clone(),
@@ -78,17 +59,33 @@ impl Patient {
       profile_picture: self.profile_picture.clone(),
       created_at: self.created_at,
       last_updated: self.last_updated,
-    };
-
-    if let Err(err) = fs::write(path, serde_json::to_string(&fs_patient).unwrap()) {
-      error!("Couldn't write patient file: {}", err);
+      practitioners: self.practitioners.clone(),
     }
   }
 
-  pub fn delete(&self) {
-    let path = format!("{}patients/{}.json", fspath!(), self.uuid);
-    if let Err(err) = fs::remove_file(path) {
-      error!("Couldn't delete patient file: {}", err);
+  // What roles that can't read notes get to see
+  pub fn redacted(&self) -> Self {
+    Patient {
+      description: String::new(),
+      ..self.clone()
     }
   }
-}
\ No newline at end of file
+
+  pub fn write(&self, storage: &Arc<dyn Storage>) {
+    let (storage, record) = (Arc::clone(storage), self.clone());
+    storage::spawn_write(move || {
+      if let Err(err) = storage.save_patient(&record) {
+        error!("Couldn't write patient {}: {}", record.uuid, err);
+      }
+    });
+  }
+
+  pub fn delete(&self, storage: &Arc<dyn Storage>) {
+    let (storage, uuid) = (Arc::clone(storage), self.uuid.clone());
+    storage::spawn_write(move || {
+      if let Err(err) = storage.delete_patient(&uuid) {
+        error!("Couldn't delete patient {}: {}", uuid, err);
+      }
+    });
+  }
+}
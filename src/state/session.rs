@@ -1,3 +1,6 @@
+use crate::storage::migrations::Document;
+use crate::audit::{self, Source};
+use crate::storage::{self, Storage};
 use crate::AppState;
 use crate::logs::*;
 
@@ -5,16 +8,16 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Instant, Duration};
 use std::collections::HashMap;
-use std::path::Path;
-use std::{fs, io};
 
 use actix_web_actors::ws;
 use actix::{Actor, StreamHandler, AsyncContext, ActorContext, Message, Handler, Addr};
 use actix::Running;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 use tokio::time;
 
-use super::state::SseEvent;
+use super::state::{SseEvent, State};
+use super::user::Access;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
@@ -34,7 +37,8 @@ pub struct Session {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct FsSession {
+pub struct FsSession {
+  schema_version: u32,
   patient_uuid: String,
   start: u64,
   end: u64,
@@ -56,6 +60,19 @@ pub struct Emotion {
   pub created_at: u64,
 }
 
+impl Emotion {
+  fn new(uuid: String) -> Self {
+    Emotion {
+      uuid,
+      id: None,
+      kind: None,
+      aquired_age: None,
+      aquired_person: "".to_string(),
+      created_at: chrono::Utc::now().timestamp() as u64,
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum TimelineEvent {
   SessionStart,
@@ -65,17 +82,9 @@ pub enum TimelineEvent {
 }
 
 impl Session {
-  pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-    let file = fs::read_to_string(path.as_ref())?;
-    let fs_session = serde_json::from_str::<FsSession>(&file);
-
-    if fs_session.is_err() {
-      log::error!("Couldn't parse session file {}.json", path.as_ref().file_stem().unwrap().to_str().unwrap());
-    }
-
-    let fs_session = fs_session?;
-    let session = Session {
-      uuid: path.as_ref().file_stem().unwrap().to_str().unwrap().to_string(),
+  pub fn from_fs(uuid: String, fs_session: FsSession) -> Self {
+    Session {
+      uuid,
       patient_uuid: fs_session.patient_uuid,
       start: fs_session.start,
       end: fs_session.end,
@@ -85,30 +94,12 @@ impl Session {
       created_at: fs_session.created_at,
       last_updated: fs_session.last_updated,
       calendar_ids: fs_session.calendar_ids,
-    };
-    
-    Ok(session)
-  }
-
-  pub fn from_dir<P: AsRef<Path>>(path: P) -> io::Result<Vec<Self>> {
-    let dir = fs::read_dir(path)?;
-    let mut sessions = Vec::new();
-
-    for entry in dir {
-      let path = entry?.path();
-      if path.is_file() {
-        let session = Session::from_file(path)?;
-        sessions.push(session);
-      }
     }
-
-    info!("Loaded {} sessions", sessions.len());
-    Ok(sessions)
   }
 
-  pub fn write(&self) {
-    let path = format!("{}sessions/{}.json", fspath!(), self.uuid);
-    let fs_session = FsSession {
+  pub fn to_fs(&self) -> FsSession {
+    FsSession {
+      schema_version: Document::Session.version(),
       patient_uuid: self.patient_uuid.clone(),
       start: self.start,
       end: self.end,
@@ -118,19 +109,34 @@ impl Session {
       created_at: self.created_at,
       last_updated: self.last_updated,
       calendar_ids: self.calendar_ids.clone(),
-    };
-
-    if let Err(err) = fs::write(path, serde_json::to_string(&fs_session).unwrap()) {
-      error!("Couldn't write session to file: {}", err);
     }
   }
 
-  pub fn delete(&self) {
-    let path = format!("{}sessions/{}.json", fspath!(), self.uuid);
-    if let Err(err) = fs::remove_file(path) {
-      error!("Couldn't delete session file: {}", err);
+  pub fn redacted(&self) -> Self {
+    Session {
+      emotions: Vec::new(),
+      timeline: HashMap::new(),
+      ..self.clone()
     }
   }
+
+  pub fn write(&self, storage: &Arc<dyn Storage>) {
+    let (storage, record) = (Arc::clone(storage), self.clone());
+    storage::spawn_write(move || {
+      if let Err(err) = storage.save_session(&record) {
+        error!("Couldn't write session {}: {}", record.uuid, err);
+      }
+    });
+  }
+
+  pub fn delete(&self, storage: &Arc<dyn Storage>) {
+    let (storage, uuid) = (Arc::clone(storage), self.uuid.clone());
+    storage::spawn_write(move || {
+      if let Err(err) = storage.delete_session(&uuid) {
+        error!("Couldn't delete session {}: {}", uuid, err);
+      }
+    });
+  }
 }
 
 #[derive(Deserialize, Debug)]
@@ -152,29 +158,43 @@ enum SocketMessage {
   EditDescription(String),
 }
 
+// Lets one socket for the session be opened within SOCKET_TICKET_TTL seconds
+#[derive(Debug)]
+pub struct SocketTicket {
+  pub session: String,
+  pub access: Access,
+  pub expires_at: u64,
+}
+
+pub const SOCKET_TICKET_TTL: u64 = 30;
+
 #[derive(Debug, Clone)]
 pub struct SessionSocket {
   state: AppState,
   uuid: String,
-  authorized: Arc<AtomicBool>,
+  // Who opened the socket, every change made through it is attributed to them
+  access: Access,
   addr: Option<Arc<Addr<SessionSocket>>>,
   hb: Instant,
   socket_id: u64,
   is_patient_update_scheduled: Arc<AtomicBool>,
   is_session_update_scheduled: Arc<AtomicBool>,
+  // Tells the task that registered the socket in State.sockets to take it out again
+  unregister: Option<mpsc::Sender<()>>,
 }
 
 impl SessionSocket {
-  pub fn new(state: AppState, uuid: String) -> Self {
+  pub fn new(state: AppState, uuid: String, access: Access) -> Self {
     Self {
       state,
       uuid,
-      authorized: Arc::new(AtomicBool::new(false)),
+      access,
       addr: None,
       hb: Instant::now(),
       socket_id: chrono::Utc::now().timestamp_millis() as u64,
       is_patient_update_scheduled: Arc::new(AtomicBool::new(false)),
       is_session_update_scheduled: Arc::new(AtomicBool::new(false)),
+      unregister: None,
     }
   }
   
@@ -251,48 +271,37 @@ impl SessionSocket {
   async fn handle_messge(&self, msg: String) -> Result<(), &'static str> {
     let msg: SocketMessage = serde_json::from_str(&msg).map_err(|_| "Couldn't parse message")?;
     let mut state = self.state.write().await;
+    let storage = Arc::clone(&state.storage);
+
+    // The role or the patient's practitioners may have changed since the socket was opened
+    let mut access = self.access.clone();
+    access.role = state.users.get(&access.token).ok_or("Unauthorized")?.read().await.role;
+    if !access.role.can_read_notes() || !state.sessions.iter().any(|s| s.uuid == self.uuid && state.can_see_session(&access, s)) {
+      return Err("Forbidden");
+    }
     
+    // Changes are made on a copy and only applied once they're in the audit log
     match msg {
       SocketMessage::AddEmotion(uuid) => {
-        let session = state.sessions.iter_mut().find(|session| session.uuid == self.uuid).ok_or("Session not found")?;
+        let (index, mut session) = self.session(&state)?;
         if session.emotions.iter().any(|emotion| emotion.uuid == uuid) {
           return Err("Emotion already exists");
         }
 
-        let emotion = Emotion {
-          uuid,
-          id: None,
-          kind: None,
-          aquired_age: None,
-          aquired_person: "".to_string(),
-          created_at: chrono::Utc::now().timestamp() as u64,
-        };
-
-        session.emotions.push(emotion.clone());
-        self.schedule_session_update();
-        session.write();
+        session.emotions.push(Emotion::new(uuid));
+        self.update_session(&mut state, index, session).await?;
       },
       SocketMessage::AddEmotionPrepend(uuid) => {
-        let session = state.sessions.iter_mut().find(|session| session.uuid == self.uuid).ok_or("Session not found")?;
+        let (index, mut session) = self.session(&state)?;
         if session.emotions.iter().any(|emotion| emotion.uuid == uuid) {
           return Err("Emotion already exists");
         }
 
-        let emotion = Emotion {
-          uuid,
-          id: None,
-          kind: None,
-          aquired_age: None,
-          aquired_person: "".to_string(),
-          created_at: chrono::Utc::now().timestamp() as u64,
-        };
-
-        session.emotions.insert(0, emotion.clone());
-        self.schedule_session_update();
-        session.write();
+        session.emotions.insert(0, Emotion::new(uuid));
+        self.update_session(&mut state, index, session).await?;
       },
       SocketMessage::EditEmotion(edit) => {
-        let session = state.sessions.iter_mut().find(|session| session.uuid == self.uuid).ok_or("Session not found")?;
+        let (index, mut session) = self.session(&state)?;
         let emotion = session.emotions.iter_mut().find(|emotion| emotion.uuid == edit.uuid).ok_or("Emotion not found")?;
 
         if let Some(id) = edit.id {
@@ -311,39 +320,72 @@ impl SessionSocket {
           emotion.aquired_person = edit.aquired_person;
         }
 
-        self.schedule_session_update();
-        session.write();
+        self.update_session(&mut state, index, session).await?;
       },
       SocketMessage::RemoveEmotion(uuid) => {
-        let session = state.sessions.iter_mut().find(|session| session.uuid == self.uuid).ok_or("Session not found")?;
+        let (index, mut session) = self.session(&state)?;
         session.emotions.retain(|emotion| emotion.uuid != uuid);
-        self.schedule_session_update();
-        session.write();
+        self.update_session(&mut state, index, session).await?;
       },
       SocketMessage::EditDescription(description) => {
         let patient_uuid = state.sessions.iter().find(|session| session.uuid == self.uuid).ok_or("Session not found")?.patient_uuid.clone();
-        let patient = state.patients.iter_mut().find(|patient| patient.uuid == patient_uuid).ok_or("Patient not found")?;
+        let index = state.patients.iter().position(|patient| patient.uuid == patient_uuid).ok_or("Patient not found")?;
+        let mut patient = state.patients[index].clone();
         patient.description = description;
+
+        audit::updated(&storage, &self.access.email, Source::Socket, &state.patients[index], &patient).await.map_err(|_| "Couldn't record the change")?;
+        patient.write(&storage);
+        state.patients[index] = patient;
         self.schedule_patient_update();
-        patient.write();
       },
     };
     Ok(())
   }
+
+  fn session(&self, state: &State) -> Result<(usize, Session), &'static str> {
+    let index = state.sessions.iter().position(|session| session.uuid == self.uuid).ok_or("Session not found")?;
+    Ok((index, state.sessions[index].clone()))
+  }
+
+  async fn update_session(&self, state: &mut State, index: usize, session: Session) -> Result<(), &'static str> {
+    audit::updated(&state.storage, &self.access.email, Source::Socket, &state.sessions[index], &session).await.map_err(|_| "Couldn't record the change")?;
+    session.write(&state.storage);
+    state.sessions[index] = session;
+    self.schedule_session_update();
+    Ok(())
+  }
 }
 
 impl Actor for SessionSocket {
   type Context = ws::WebsocketContext<Self>;
 
   fn started(&mut self, ctx: &mut Self::Context) {
-    info!("Session socket started");
+    info!("Session socket started for {}", self.access.email);
     
     self.addr = Some(Arc::new(ctx.address()));
     self.hb(ctx);
+
+    // Registered so the socket can be closed when its device is signed out. The same task removes
+    // it once the socket stops, so a socket that stops right away can't be left behind.
+    let state = self.state.clone();
+    let device = self.access.device.clone();
+    let addr = ctx.address();
+    let (tx, mut rx) = mpsc::channel(1);
+    self.unregister = Some(tx);
+    tokio::spawn(async move {
+      state.write().await.sockets.push((device, addr.clone()));
+      rx.recv().await;
+      state.write().await.sockets.retain(|(_, socket)| socket != &addr);
+    });
+
+    ctx.text(format!("Authorized: {}", self.socket_id));
   }
 
   fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
     info!("Session socket stopping");
+    if let Some(unregister) = self.unregister.take() {
+      let _ = unregister.try_send(());
+    }
     
     Running::Stop
   }
@@ -361,40 +403,15 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SessionSocket {
       },
       Ok(ws::Message::Text(text)) => {
         let msg: String = text.into();
-
-        if self.authorized.load(Ordering::Relaxed) {
-          let addr = self.addr.clone().unwrap();
-          let arc_state = Arc::new(self.clone());
-
-          tokio::spawn(async move {
-            if let Err(err) = arc_state.handle_messge(msg).await {
-              addr.do_send(WsMessage(err.to_string()));
-              addr.do_send(CloseSession);
-              error!("Error sending message: {:?}", err);
-            }
-          });
-          
-          return;
-        }
-        
-        let state = self.state.clone();
-        let authorized = self.authorized.clone();
         let addr = self.addr.clone().unwrap();
-        
-        let id = self.socket_id;
+        let arc_state = Arc::new(self.clone());
+
         tokio::spawn(async move {
-          let state = state.read().await;
-          if state.users.contains_key(&msg) {
-            info!("Session socket authorized");
-            
-            authorized.store(true, Ordering::Relaxed);
-            addr.do_send(WsMessage(format!("Authorized: {}", id)));
-            return;
+          if let Err(err) = arc_state.handle_messge(msg).await {
+            addr.do_send(WsMessage(err.to_string()));
+            addr.do_send(CloseSession);
+            error!("Error sending message: {:?}", err);
           }
-
-          info!("Session socket failed to authorize");
-          addr.do_send(WsMessage("Unauthorized".to_string()));
-          addr.do_send(CloseSession);
         });
       },
       Err(err) => {
@@ -429,3 +446,17 @@ impl Handler<CloseSession> for SessionSocket {
     ctx.stop();
   }
 }
+
+// Unlike CloseSession the client gets a close frame saying why
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct GoingAway(pub String);
+
+impl Handler<GoingAway> for SessionSocket {
+  type Result = ();
+
+  fn handle(&mut self, msg: GoingAway, ctx: &mut Self::Context) {
+    ctx.close(Some(ws::CloseReason { code: ws::CloseCode::Away, description: Some(msg.0) }));
+    ctx.stop();
+  }
+}
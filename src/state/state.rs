@@ -1,20 +1,27 @@
-use super::user::{User, RwUser, Settings};
+use super::user::{User, UserInfo, RwUser, Settings, Access, Role, Invitation};
+use super::device::{Device, RwDevice};
+use super::session::{SessionSocket, SocketTicket, CloseSession, SOCKET_TICKET_TTL};
 use super::patient::Patient;
 use super::session::Session;
-use crate::AppState;
+use super::trash::{TrashItem, Trashed};
+use crate::jobs::{self, Job};
+use crate::storage::migrations::Document;
+use crate::storage::{self, Storage};
+use crate::{google, secrets, shutdown, tokens, AppState, EnvVars};
 use crate::logs::*;
 
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
-use std::{fs, io};
+use std::io;
 use std::sync::Arc;
 
+use actix::Addr;
 use actix_web::HttpRequest;
 use actix_web_lab::sse;
 use chrono::Utc;
 use reqwest::ClientBuilder;
-use sha2::{Sha256, Digest};
 use tokio::sync::{mpsc, RwLock};
 use tokio::time;
 use serde::{Serialize, Deserialize};
@@ -22,27 +29,38 @@ use tokio::time::interval;
 use futures::future;
 use uuid::Uuid;
 
-const SECRETS: &str = include_str!("../../secrets.json");
-
 #[derive(Debug)]
 pub struct State {
   pub sessions: Vec<Session>,
   pub patients: Vec<Patient>,
+  pub trash: Vec<TrashItem>,
   pub users: HashMap<String, Arc<RwLock<User>>>,
-  pub path: String,
+  // <Email, Invitation>
+  pub invitations: HashMap<String, Invitation>,
   pub calendar_webhooks: HashMap<String, GoogleWebhook>,
 
-  // <Access token, SSE token>
+  // <Access token, Device>
+  pub devices: HashMap<String, Device>,
+  // <Device id, SSE token>
   pub sse_tokens: HashMap<String, String>,
-  pub sse: Vec<(String, mpsc::Sender<sse::Event>)>,
-  pub secrets: Arc<Secrets>,
+  pub sse: Vec<(Access, mpsc::Sender<sse::Event>)>,
+  // <Device id, Session socket>
+  pub sockets: Vec<(String, Addr<SessionSocket>)>,
+  // <Ticket, Socket ticket>
+  pub socket_tickets: HashMap<String, SocketTicket>,
   pub write_tx: mpsc::Sender<()>,
 
-  // <Verification code, Access token>
+  // <Verification code, User key>
   pub auth_codes: HashMap<String, String>,
 
   // Used to identify messages
   pub ack: AtomicU64,
+  // Snapshots are numbered when they're taken, one that finishes late never overwrites a newer one
+  writes: AtomicU64,
+  written: Arc<AtomicU64>,
+
+  pub storage: Arc<dyn Storage>,
+  pub env: EnvVars,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,16 +110,13 @@ impl DateTime {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
-struct RwState {
+pub struct RwState {
+  schema_version: u32,
   users: HashMap<String, RwUser>,
   sse_tokens: HashMap<String, String>,
-  calendar_webhooks: Option<HashMap<String, GoogleWebhook>>,
-}
-
-#[derive(Debug, Clone, Deserialize)]
-pub struct Secrets {
-  pub client_id: String,
-  pub client_secret: String,
+  calendar_webhooks: HashMap<String, GoogleWebhook>,
+  invitations: HashMap<String, Invitation>,
+  devices: HashMap<String, RwDevice>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -121,7 +136,7 @@ pub trait ArcState {
 impl ArcState for AppState {
   fn fs_write(&self) {
     let state = self.clone();
-    tokio::spawn(async move {
+    shutdown::spawn(async move {
       let state = state.read().await;
       state.write();
     });
@@ -129,9 +144,9 @@ impl ArcState for AppState {
 
   async fn new_code(&self, token: &str) -> String {
     let mut state = self.write().await;
-    let code = State::generate_token();
+    let code = tokens::generate(state.env.token_bytes);
     state.auth_codes.insert(code.clone(), token.to_string());
-    info!("Created new auth code: {}", code);
+    info!("Created a new auth code");
 
     let state = self.clone();
     let code2 = code.clone();
@@ -139,7 +154,7 @@ impl ArcState for AppState {
       time::sleep(time::Duration::from_secs(60)).await;
       let mut state = state.write().await;
       if state.auth_codes.remove(&code2).is_some() {
-        info!("Removed auth code: {}", code2);
+        info!("Removed an auth code that was never used");
       }
     });
     
@@ -148,58 +163,55 @@ impl ArcState for AppState {
 }
 
 impl State {
-  pub fn generate_token() -> String {
-    let mut hasher = Sha256::new();
-    hasher.update((i64::MAX - Utc::now().timestamp()).to_string());
-    format!("{:x}", hasher.finalize())
-  }
-
-  pub fn new(write_tx: mpsc::Sender<()>, file_path: String) -> io::Result<Self> {
-    let sessions_dir = format!("{}sessions", file_path);
-    let patients_dir = format!("{}patients", file_path);
-    let path = format!("{}state.json", file_path);
-
-    if fs::metadata(&sessions_dir).is_err() {
-      fs::create_dir_all(&sessions_dir)?;
-    }
-
-    if fs::metadata(&patients_dir).is_err() {
-      fs::create_dir_all(&patients_dir)?;
-    }
-
-    let secrets = serde_json::from_str(SECRETS)?;
-    if fs::metadata(&path).is_err() {
-      info!("No state file found, creating empty state...");
-      return Ok(State {
-        sessions: Session::from_dir(&sessions_dir)?,
-        patients: Patient::from_dir(&patients_dir)?,
-        users: HashMap::new(),
-        path: file_path,
-        calendar_webhooks: HashMap::new(),
-        sse_tokens: HashMap::new(),
-        sse: Vec::new(),
-        secrets: Arc::new(secrets),
-        write_tx,
-        auth_codes: HashMap::new(),
-        ack: AtomicU64::new(0),
-      });
+  // USERS only seeds the invitations of a fresh install, after that accounts are managed through the API
+  pub fn new(storage: Arc<dyn Storage>, write_tx: mpsc::Sender<()>, env: &EnvVars) -> io::Result<Self> {
+    let rwstate = match storage.load_state()? {
+      Some(rwstate) => rwstate,
+      None => {
+        info!("No state file found, creating empty state...");
+        RwState::default()
+      }
+    };
+
+    let mut invitations = rwstate.invitations;
+    if rwstate.users.is_empty() && invitations.is_empty() {
+      for (email, role) in &env.users {
+        info!("Inviting {} as {:?} from USERS", email, role);
+        invitations.insert(email.to_lowercase(), Invitation {
+          role: *role,
+          invited_by: String::from("USERS"),
+          invited_at: Utc::now().timestamp() as u64,
+        });
+      }
     }
 
-    let file = fs::read_to_string(&path)?;
-    let rwstate = serde_json::from_str::<RwState>(&file)?;
-
-    let secrets = Arc::new(secrets);
+    // Accounts from before roles existed get the role USERS lists for them. Otherwise a lone account
+    // ran the practice and becomes its owner, and anyone else starts with the least access.
+    let alone = rwstate.users.len() == 1;
+    let mut owners = 0;
     let mut users = HashMap::new();
-
     for (token, user) in rwstate.users {
       let (tx, rx) = mpsc::channel(1);
       let write_tx = write_tx.clone();
+
+      let role = user.role.unwrap_or_else(|| {
+        let configured = env.users.iter().find(|(email, _)| email.eq_ignore_ascii_case(&user.email)).map(|(_, role)| *role);
+        let role = configured.unwrap_or(if alone { Role::Owner } else { Role::Assistant });
+        warning!("{} had no role, giving them {:?}", user.email, role);
+        role
+      });
+
+      if role == Role::Owner {
+        owners += 1;
+      }
       
       let user = User {
         access_token: user.access_token,
         expires_at: user.expires_at,
         refresh_token: user.refresh_token,
         settings: user.settings,
+        role,
+        totp: user.totp,
         user_info: crate::state::user::UserInfo {
           id: user.id,
           email: user.email,
@@ -211,7 +223,6 @@ impl State {
         },
         stop_tx: tx,
         write_tx,
-        secrets: Arc::clone(&secrets),
       };
 
       let user = Arc::new(RwLock::new(user));
@@ -222,22 +233,49 @@ impl State {
       });
     }
     
-    info!("Loaded state from disk, found {} users", users.len());
+    if owners == 0 && !users.is_empty() {
+      warning!("No account is an owner, roles can't be managed until one is");
+    }
+
+    info!("Loaded state, found {} users", users.len());
     Ok(State {
-      sessions: Session::from_dir(&sessions_dir)?,
-      patients: Patient::from_dir(&patients_dir)?,
+      sessions: storage.load_sessions()?,
+      patients: storage.load_patients()?,
+      trash: storage.load_trash()?,
       users,
-      path: file_path,
-      calendar_webhooks: rwstate.calendar_webhooks.unwrap_or_default(),
+      invitations,
+      devices: rwstate.devices.into_iter().map(|(token, device)| (token, Device::from_rw(device))).collect(),
+      calendar_webhooks: rwstate.calendar_webhooks,
       sse_tokens: rwstate.sse_tokens,
       sse: Vec::new(),
-      secrets,
+      sockets: Vec::new(),
+      socket_tickets: HashMap::new(),
       write_tx,
       auth_codes: HashMap::new(),
       ack: AtomicU64::new(0),
+      writes: AtomicU64::new(0),
+      written: Arc::new(AtomicU64::new(0)),
+      storage,
+      env: env.clone(),
     })
   }
 
+  // Set up like main does it, over the given storage and the default config
+  #[cfg(test)]
+  pub fn for_tests(storage: Arc<dyn Storage>) -> io::Result<AppState> {
+    let (write_tx, _) = mpsc::channel(1);
+    let _ = jobs::init(&*storage);
+    Ok(Arc::new(RwLock::new(State::new(storage, write_tx, &EnvVars::default())?)))
+  }
+
+  // Invites and signs in someone without going through Google, returns the token of their one device
+  #[cfg(test)]
+  pub fn sign_in_for_tests(&mut self, email: &str, role: Role) -> String {
+    self.invitations.insert(email.to_lowercase(), Invitation { role, invited_by: "tests".into(), invited_at: 0 });
+    let key = self.accept_invitation(UserInfo::for_tests(email), String::new(), String::new(), Utc::now().timestamp() as u64 + 3600).unwrap();
+    self.add_device(&key, String::new())
+  }
+
   pub fn spawn_ping_loop(state: AppState) {
     tokio::spawn(async move {
       info!("Starting ping loop...");
@@ -245,7 +283,9 @@ impl State {
 
       loop {
         interval.tick().await;
-        state.write().await.remove_stale_clients().await;
+        let mut state = state.write().await;
+        state.remove_stale_clients().await;
+        state.remove_expired_devices();
       }
     });
   }
@@ -262,6 +302,15 @@ impl State {
     }
 
     self.sse = active_clients;
+    self.sockets.retain(|(_, addr)| addr.connected());
+  }
+
+  fn remove_expired_devices(&mut self) {
+    let expired = self.devices.values().filter(|device| device.is_expired()).map(|device| device.id.clone()).collect::<Vec<_>>();
+    for id in expired {
+      info!("Device {} expired", id);
+      self.revoke_device(&id);
+    }
   }
 
   pub fn start_write_loop(state: AppState, mut write_rx: mpsc::Receiver<()>) {
@@ -279,27 +328,103 @@ impl State {
     });
   }
 
+  // Rewrites every record under the current RECORD_KEY, one at a time so the lock is only held briefly
+  pub fn start_key_rotation(state: AppState) {
+    tokio::spawn(Self::rotate_keys(state));
+  }
+
+  async fn rotate_keys(state: AppState) {
+    let storage = Arc::clone(&state.read().await.storage);
+    if !storage.keyring().rotation_pending() {
+      return;
+    }
+
+    match storage.keyring().key_id() {
+      Some(id) => info!("Re-encrypting records with key {}...", id),
+      None => info!("Decrypting records, RECORD_KEY is not set..."),
+    }
+
+    let (patients, sessions, trash) = {
+      let state = state.read().await;
+      (
+        state.patients.iter().map(|p| p.uuid.clone()).collect::<Vec<_>>(),
+        state.sessions.iter().map(|s| s.uuid.clone()).collect::<Vec<_>>(),
+        state.trash.iter().map(|t| t.uuid.clone()).collect::<Vec<_>>(),
+      )
+    };
+
+    for uuid in &patients {
+      if let Some(patient) = state.read().await.patients.iter().find(|p| &p.uuid == uuid) {
+        patient.write(&storage);
+      }
+    }
+
+    for uuid in &sessions {
+      if let Some(session) = state.read().await.sessions.iter().find(|s| &s.uuid == uuid) {
+        session.write(&storage);
+      }
+    }
+
+    for uuid in &trash {
+      if let Some(item) = state.read().await.trash.iter().find(|t| &t.uuid == uuid) {
+        item.write(&storage);
+      }
+    }
+
+    let jobs = jobs::rewrite(&storage);
+    info!("Rewrote {} records under the current key", patients.len() + sessions.len() + trash.len() + jobs);
+  }
+
   pub fn write(&self) {
-    let path = format!("{}state.json", self.path);
+    let rwstate = self.snapshot();
+    let storage = Arc::clone(&self.storage);
+    let (seq, written) = (self.writes.fetch_add(1, Ordering::SeqCst) + 1, Arc::clone(&self.written));
+
+    shutdown::spawn(async move {
+      let rwstate = rwstate.await;
+      storage::spawn_write(move || {
+        if written.fetch_max(seq, Ordering::SeqCst) > seq {
+          return;
+        }
+
+        if let Err(err) = storage.save_state(&rwstate) {
+          error!("There was an error while writing the state: {}", err);
+        }
+      });
+    });
+  }
+
+  // Writes the state before returning, used on shutdown when there's nothing left to spawn onto
+  pub async fn flush(&self) -> io::Result<()> {
+    let seq = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+    let rwstate = self.snapshot().await;
+    self.written.fetch_max(seq, Ordering::SeqCst);
+
+    let storage = Arc::clone(&self.storage);
+    tokio::task::spawn_blocking(move || storage.save_state(&rwstate)).await.map_err(io::Error::other)?
+  }
+
+  // Everything but the users is copied right away, the users are locked once the future runs
+  fn snapshot(&self) -> impl Future<Output = RwState> + Send + 'static {
     let users = self.users.clone();
     let sse_tokens = self.sse_tokens.clone();
     let webhooks = self.calendar_webhooks.clone();
+    let invitations = self.invitations.clone();
+    let devices = self.devices.iter().map(|(token, device)| (token.clone(), device.to_rw())).collect();
 
-    tokio::spawn(async move {
+    async move {
       let bare_users = users.values().map(|u| u.read());
       let bare_users = future::join_all(bare_users).await;
 
-      let rwstate = RwState {
+      RwState {
+        schema_version: Document::State.version(),
         users: users.keys().enumerate().map(|(i, token)| (token.clone(), RwUser::from_user(&bare_users[i]))).collect(),
         sse_tokens,
-        calendar_webhooks: Some(webhooks),
-      };
-
-      let json = serde_json::to_string(&rwstate).unwrap();
-      if let Err(err) = fs::write(path, json) {
-        error!("There was an error while writing the state to disk: {}", err);
+        calendar_webhooks: webhooks,
+        invitations,
+        devices,
       }
-    });
+    }
   }
 
   pub async fn schedule_webhook_refresh(state: &AppState) {
@@ -335,7 +460,15 @@ impl State {
         let uuid = Uuid::new_v4().to_string();
         
         let app_state = state.read().await;
-        let auth = format!("Bearer {}", app_state.users.get(&user).unwrap().read().await.access_token);
+        let auth = match app_state.users.get(&user) {
+          Some(rw_user) => format!("Bearer {}", rw_user.read().await.access_token),
+          None => {
+            info!("Not renewing the Google webhook of revoked user {}", user);
+            return;
+          },
+        };
+
+        let address = format!("{}/api/webhook/v11", app_state.env.public_url);
         drop(app_state);
 
         let client = ClientBuilder::new()
@@ -350,7 +483,7 @@ impl State {
           .json(&serde_json::json!({
             "id": uuid,
             "type": "web_hook",
-            "address": "https://entitia.com/api/webhook/v11",
+            "address": address,
           }))
           .send()
           .await;
@@ -403,14 +536,13 @@ impl State {
 
         let mut app_state = state.write().await;
 
-        // If /events doesn't exists, create it
-        if fs::metadata(format!("{}events", app_state.path)).is_err() {
-          fs::create_dir(format!("{}events", app_state.path)).unwrap();
-        }
-
         info!("Fetched {} events for user {}", res.items.len(), user);
-        let events = serde_json::to_string(&res.items).unwrap();
-        fs::write(format!("{}events/{}.json", app_state.path, user), events).unwrap();
+        let (storage, events, owner) = (Arc::clone(&app_state.storage), res.items, user.clone());
+        storage::spawn_write(move || {
+          if let Err(err) = storage.save_events(&owner, &events) {
+            error!("There was an error while saving the Google events for user {}: {}", owner, err);
+          }
+        });
 
         // Timestamp in ms
         let expiry = webhook.expiration.parse().unwrap();
@@ -431,24 +563,234 @@ impl State {
     });
   }
 
+  pub fn device(&self, req: &HttpRequest) -> Result<&Device, actix_web::Error> {
+    let token = req.headers().get("Authorization").ok_or(actix_web::error::ErrorUnauthorized("Missing Authorization header"))?;
+    let token = token.to_str().map_err(|_| actix_web::error::ErrorUnauthorized("Invalid Authorization header"))?;
+    let device = self.find_device(token).ok_or(actix_web::error::ErrorUnauthorized("Unauthorized"))?;
+
+    device.touch();
+    Ok(device)
+  }
+
+  // Compares against every token instead of hashing into the map so the lookup runs in constant time
+  pub fn find_device(&self, token: &str) -> Option<&Device> {
+    self.devices.iter()
+      .fold(None, |found, (key, device)| if tokens::matches(key, token) { Some(device) } else { found })
+      .filter(|device| !device.is_expired())
+  }
+
+  // Returns the key of the user the token belongs to
   pub fn auth_token(&self, req: HttpRequest) -> Result<String, actix_web::Error> {
-    req.headers().get("Authorization").map_or(Err(actix_web::error::ErrorUnauthorized("Missing Authorization header")), |token| {
-      let token = token.to_str().map_err(|_| actix_web::error::ErrorUnauthorized("Invalid Authorization header"))?;
-      self.users.contains_key(token).then(|| token.to_string()).ok_or(actix_web::error::ErrorUnauthorized("Unauthorized"))
-    })
+    self.device(&req).map(|device| device.user.clone())
   }
 
-  pub fn check_auth(&self, req: HttpRequest) -> Result<Arc<RwLock<User>>, actix_web::Error> {
-    req.headers().get("Authorization").map_or(Err(actix_web::error::ErrorUnauthorized("Missing Authorization header")), |token| {
-      let token = token.to_str().map_err(|_| actix_web::error::ErrorUnauthorized("Invalid Authorization header"))?;
-      self.users.get(token).map(Arc::clone).ok_or(actix_web::error::ErrorUnauthorized("Unauthorized"))
+  pub async fn access(&self, req: HttpRequest) -> Result<Access, actix_web::Error> {
+    let device = self.device(&req)?;
+    // A device can outlive its user for as long as it takes to revoke it
+    let user = match self.users.get(&device.user) {
+      Some(user) => user.read().await,
+      None => return Err(actix_web::error::ErrorUnauthorized("Unauthorized")),
+    };
+
+    Ok(Access {
+      token: device.user.clone(),
+      device: device.id.clone(),
+      email: user.user_info.email.clone(),
+      role: user.role,
     })
   }
 
+  // Users who enabled step-up confirmation have to send a code in X-Confirm-Code before deleting anything
+  pub async fn step_up(&self, access: &Access, req: &HttpRequest) -> Result<(), actix_web::Error> {
+    let mut user = self.users[&access.token].write().await;
+    if !user.settings.step_up_enabled {
+      return Ok(());
+    }
+
+    let code = match req.headers().get("X-Confirm-Code").and_then(|code| code.to_str().ok()) {
+      Some(code) => code.trim(),
+      None => return Err(actix_web::error::ErrorForbidden("Confirmation required")),
+    };
+
+    let confirmed = user.totp.as_mut().is_some_and(|totp| totp.confirm(code));
+    drop(user);
+
+    // The used step or backup code has to be persisted so it can't be replayed
+    self.write();
+    if confirmed {
+      Ok(())
+    } else {
+      warning!("{} failed a step-up confirmation", access.email);
+      Err(actix_web::error::ErrorForbidden("Invalid confirmation code"))
+    }
+  }
+
+  // A session whose patient is gone is only shown to roles that see every patient
+  pub fn can_see_session(&self, access: &Access, session: &Session) -> bool {
+    match self.patients.iter().find(|patient| patient.uuid == session.patient_uuid) {
+      Some(patient) => access.can_see(patient),
+      None => access.role.sees_all_patients(),
+    }
+  }
+
+  pub fn can_see_trash(&self, access: &Access, item: &TrashItem) -> bool {
+    access.role.can_manage_patients() && match &item.item {
+      Trashed::Patient { patient, .. } => access.can_see(patient),
+      Trashed::Session { session } => self.can_see_session(access, session),
+    }
+  }
+
+  // Removals only carry a uuid and are sent to everyone, the record may already be gone from the state
+  fn can_see_event(&self, access: &Access, event: &SseEvent) -> bool {
+    match event {
+      SseEvent::PatientAdded(patient) | SseEvent::PatientUpdated(patient) => access.can_see(patient),
+      SseEvent::SessionAdded(session) | SseEvent::SessionUpdated(session) => self.can_see_session(access, session),
+      SseEvent::TrashAdded(item) => self.can_see_trash(access, item),
+      SseEvent::TrashRemoved(_) => access.role.can_manage_patients(),
+      SseEvent::JobFailed(job) => job.email == access.email || access.role == Role::Owner,
+      _ => true,
+    }
+  }
+
+  // The trash item is written before the originals are removed so a crash can't lose the record
+  pub async fn move_to_trash(&mut self, item: TrashItem) {
+    item.write(&self.storage);
+    match &item.item {
+      Trashed::Patient { patient, sessions } => {
+        sessions.iter().for_each(|session| session.delete(&self.storage));
+        patient.delete(&self.storage);
+      },
+      Trashed::Session { session } => session.delete(&self.storage),
+    }
+
+    self.broadcast(SseEvent::TrashAdded(&item)).await;
+    self.trash.push(item);
+  }
+
+  // Stops everything running on behalf of the user and forgets their token
+  pub async fn revoke_user(&mut self, token: &str) {
+    let user = match self.users.remove(token) {
+      Some(user) => user,
+      None => return,
+    };
+
+    let user = user.read().await;
+    if let Err(err) = user.stop_tx.try_send(()) {
+      error!("Couldn't stop the refresh loop for user {}: {}", user.user_info.email, err);
+    }
+
+    let devices = self.devices.values().filter(|device| device.user == token).map(|device| device.id.clone()).collect::<Vec<_>>();
+    for id in devices {
+      self.revoke_device(&id);
+    }
+
+    self.auth_codes.retain(|_, code_token| code_token != token);
+
+    if let Some(webhook) = self.calendar_webhooks.remove(token) {
+      let access_token = user.access_token.clone();
+      let email = user.user_info.email.clone();
+      shutdown::spawn(async move {
+        if let Err(err) = google::stop_channel(&access_token, &webhook.uuid, &webhook.resource_id).await {
+          error!("Couldn't stop the Google webhook for user {}: {}", email, err);
+        }
+      });
+    }
+
+    let (storage, token, email) = (Arc::clone(&self.storage), token.to_owned(), user.user_info.email.clone());
+    storage::spawn_write(move || {
+      if let Err(err) = storage.delete_events(&token) {
+        error!("Couldn't delete the Google events of user {}: {}", email, err);
+      }
+    });
+
+    info!("Revoked user {}", user.user_info.email);
+    drop(user);
+    self.write();
+  }
+
+  // Signs the token out, its SSE streams and session sockets are closed right away
+  pub fn revoke_device(&mut self, id: &str) {
+    self.devices.retain(|_, device| device.id != id);
+    self.sse_tokens.remove(id);
+
+    // Dropping the senders ends the SSE streams
+    self.sse.retain(|(client, _)| client.device != id);
+    self.sockets.retain(|(device, addr)| {
+      if device == id {
+        addr.do_send(CloseSession);
+      }
+
+      device != id
+    });
+
+    info!("Revoked device {}", id);
+    self.write();
+  }
+
+  pub fn add_device(&mut self, user: &str, user_agent: String) -> String {
+    let token = tokens::generate(self.env.token_bytes);
+    let device = Device::new(Uuid::new_v4().to_string(), user.to_owned(), user_agent);
+    info!("Added device {} for user {}", device.id, user);
+
+    self.devices.insert(token.clone(), device);
+    self.write();
+    token
+  }
+
+  // Swaps the token for a new one, the device keeps its id and connections
+  pub fn rotate_token(&mut self, token: &str) -> Option<String> {
+    let device = self.devices.remove(token)?;
+    device.touch();
+
+    let token = tokens::generate(self.env.token_bytes);
+    self.devices.insert(token.clone(), device);
+    self.write();
+    Some(token)
+  }
+
+  pub fn issue_socket_ticket(&mut self, session: String, access: Access) -> String {
+    let now = Utc::now().timestamp() as u64;
+    self.socket_tickets.retain(|_, ticket| ticket.expires_at > now);
+
+    let ticket = tokens::generate(self.env.token_bytes);
+    self.socket_tickets.insert(ticket.clone(), SocketTicket { session, access, expires_at: now + SOCKET_TICKET_TTL });
+    ticket
+  }
+
+  // Tickets are single use, a matching one is consumed even if it turns out to be for another session
+  pub fn take_socket_ticket(&mut self, ticket: &str, session: &str) -> Option<Access> {
+    let key = self.socket_tickets.keys().fold(None, |found, key| if tokens::matches(key, ticket) { Some(key.clone()) } else { found })?;
+    let ticket = self.socket_tickets.remove(&key)?;
+
+    let valid = ticket.session == session && ticket.expires_at > Utc::now().timestamp() as u64 && self.devices.values().any(|device| device.id == ticket.access.device);
+    valid.then_some(ticket.access)
+  }
+
+  // A Google account signing in for the first time needs an invitation, which it uses up.
+  // Returns the key of the new user, or None when nobody invited them.
+  pub fn accept_invitation(&mut self, user_info: UserInfo, access_token: String, refresh_token: String, expires_at: u64) -> Option<String> {
+    let invitation = self.invitations.remove(&user_info.email.to_lowercase())?;
+    info!("{} accepted the invitation from {}", user_info.email, invitation.invited_by);
+
+    let (stop_tx, stop_rx) = mpsc::channel(1);
+    let user = User {
+      access_token,
+      user_info,
+      settings: Default::default(),
+      role: invitation.role,
+      totp: None,
+      expires_at,
+      refresh_token,
+      stop_tx,
+      write_tx: self.write_tx.clone(),
+    };
+
+    Some(self.add_new_user(user, stop_rx))
+  }
+
   pub fn add_new_user(&mut self, user: User, stop_rx: mpsc::Receiver<()>) -> String {
     let user = Arc::new(RwLock::new(user));
-    let token = Self::generate_token();
-    self.sse_tokens.insert(token.clone(), State::generate_token());
+    let token = tokens::generate(self.env.token_bytes);
     self.users.insert(token.clone(), Arc::clone(&user));
     self.write();
     
@@ -475,6 +817,7 @@ impl State {
       }
 
       let mut rw_user = user.write().await;
+      let secrets = secrets::current();
       
       let client = ClientBuilder::new()
         .danger_accept_invalid_certs(true)
@@ -484,8 +827,8 @@ impl State {
         .post("https://oauth2.googleapis.com/token")
         .header("Content-Type", "application/x-www-form-urlencoded")
         .form(&[
-          ("client_id", &rw_user.secrets.client_id),
-          ("client_secret", &rw_user.secrets.client_secret),
+          ("client_id", &secrets.client_id),
+          ("client_secret", &secrets.client_secret),
           ("refresh_token", &rw_user.refresh_token),
           ("grant_type", &"refresh_token".into()),
         ])
@@ -524,12 +867,28 @@ impl State {
     }
   }
 
-  async fn broadcast_message<'a>(&self, event: SseEvent<'a>, socket_ack: Option<u64>) {
+  async fn broadcast_message<'a>(&self, event: SseEvent<'a>, socket_ack: Option<u64>, token: Option<&str>) {
     let ack = self.ack.fetch_add(1, Ordering::Relaxed);
-    let msg = serde_json::to_string(&BroadcastMessage { payload: event, ack, socket_ack }).unwrap();
-    info!("Broadcasting SSE message to {} clients", self.sse.len());
-
-    let futs = self.sse.iter().map(|(_, tx)| tx.send(sse::Data::new(msg.clone()).into()));
+    let message = |payload: SseEvent| serde_json::to_string(&BroadcastMessage { payload, ack, socket_ack }).unwrap();
+
+    let msg = message(event);
+    let redacted = match event {
+      SseEvent::PatientAdded(patient) => message(SseEvent::PatientAdded(&patient.redacted())),
+      SseEvent::PatientUpdated(patient) => message(SseEvent::PatientUpdated(&patient.redacted())),
+      SseEvent::SessionAdded(session) => message(SseEvent::SessionAdded(&session.redacted())),
+      SseEvent::SessionUpdated(session) => message(SseEvent::SessionUpdated(&session.redacted())),
+      _ => msg.clone(),
+    };
+
+    let clients = self.sse.iter()
+      .filter(|(access, _)| token.is_none_or(|token| access.token == token) && self.can_see_event(access, &event))
+      .collect::<Vec<_>>();
+    info!("Broadcasting SSE message to {} clients", clients.len());
+
+    let futs = clients.iter().map(|(access, tx)| {
+      let msg = if access.role.can_read_notes() { &msg } else { &redacted };
+      tx.send(sse::Data::new(msg.clone()).into())
+    });
     let res = future::join_all(futs).await;
     
     for (i, res) in res.into_iter().enumerate() {
@@ -539,35 +898,33 @@ impl State {
     }
   }
 
-  async fn broadcast_message_to<'a>(&self, event: SseEvent<'a>, socket_ack: Option<u64>, token: &str) {
-    let ack = self.ack.fetch_add(1, Ordering::Relaxed);
-    let msg = serde_json::to_string(&BroadcastMessage { payload: event, ack, socket_ack }).unwrap();
-    info!("Broadcasting SSE message to {} clients", self.sse.len());
-
-    let futs = self.sse.iter().filter(|(t, _)| t == token).map(|(_, tx)| tx.send(sse::Data::new(msg.clone()).into()));
-    let res = future::join_all(futs).await;
-    
-    for (i, res) in res.into_iter().enumerate() {
-      if let Err(err) = res {
-        error!("Couldn't send message to sse client {}: {}", i, err);
+  // Calendars the patient's sessions go into: of users who turned theirs on and may see the patient
+  pub async fn calendars(&self, patient: &Patient) -> Vec<String> {
+    let mut calendars = Vec::new();
+    for user in self.users.values() {
+      let user = user.read().await;
+      if user.settings.google_calendar_enabled && user.can_see(patient) {
+        calendars.push(user.user_info.email.clone());
       }
     }
+
+    calendars
   }
 
   pub async fn broadcast<'a>(&self, msg: SseEvent<'a>) {
-    self.broadcast_message(msg, None).await;
+    self.broadcast_message(msg, None, None).await;
   }
 
   pub async fn broadcast_socket<'a>(&self, msg: SseEvent<'a>, socket_ack: u64) {
-    self.broadcast_message(msg, Some(socket_ack)).await;
+    self.broadcast_message(msg, Some(socket_ack), None).await;
   }
 
   pub async fn broadcast_to<'a>(&self, msg: SseEvent<'a>, token: &str) {
-    self.broadcast_message_to(msg, None, token).await;
+    self.broadcast_message(msg, None, Some(token)).await;
   }
 
   pub async fn broadcast_socket_to<'a>(&self, msg: SseEvent<'a>, socket_ack: u64, token: &str) {
-    self.broadcast_message_to(msg, Some(socket_ack), token).await;
+    self.broadcast_message(msg, Some(socket_ack), Some(token)).await;
   }
 }
 
@@ -579,7 +936,7 @@ struct BroadcastMessage<'a> {
   ack: u64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, Copy)]
 #[serde(tag = "type", content = "payload")]
 pub enum SseEvent<'a> {
   Ready {
@@ -588,6 +945,7 @@ pub enum SseEvent<'a> {
     user_mail: &'a str,
     user_avatar: &'a str,
     settings: &'a Settings,
+    role: Role,
   },
   PatientAdded(&'a Patient),
   PatientUpdated(&'a Patient),
@@ -598,6 +956,11 @@ pub enum SseEvent<'a> {
   EventAdded(&'a GoogleEvent),
   EventUpdated(&'a GoogleEvent),
   EventRemoved(&'a String),
+  TrashAdded(&'a TrashItem),
+  TrashRemoved(&'a String),
+  // Sent right before the streams are closed, the client should reconnect once the server is back
+  ShuttingDown(&'a str),
+  JobFailed(&'a Job),
 }
 
 pub trait DrainWith<T> {
@@ -620,3 +983,121 @@ impl<T> DrainWith<T> for Vec<T> {
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::{encryption, SqliteStorage};
+  use crate::storage::encryption::Keyring;
+
+  use actix_web::test::TestRequest;
+
+  fn storage(name: &str, key: Option<[u8; 32]>) -> Arc<dyn Storage> {
+    Arc::new(SqliteStorage::shared(name, Arc::new(Keyring::with_keys(key, None))).unwrap())
+  }
+
+  fn seed(storage: &Arc<dyn Storage>) {
+    storage.save_patient(&Patient { uuid: "p1".into(), name: "Jan".into(), ..Default::default() }).unwrap();
+    storage.save_session(&Session { uuid: "s1".into(), patient_uuid: "p1".into(), ..Default::default() }).unwrap();
+  }
+
+  fn memory() -> AppState {
+    let _ = encryption::init();
+    State::for_tests(storage::open(storage::StorageKind::Memory, "").unwrap()).unwrap()
+  }
+
+  fn issue(state: &mut State, token: &str, session: &str) -> String {
+    let device = state.find_device(token).unwrap();
+    let access = Access { token: device.user.clone(), device: device.id.clone(), email: "a@b.c".into(), role: Role::Owner };
+    state.issue_socket_ticket(session.into(), access)
+  }
+
+  #[actix_web::test]
+  async fn devices_of_removed_users_are_unauthorized() {
+    let state = memory();
+    let token = state.write().await.add_device("gone", String::new());
+
+    let req = TestRequest::default().insert_header(("Authorization", token)).to_http_request();
+    let err = state.read().await.access(req).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), actix_web::http::StatusCode::UNAUTHORIZED);
+  }
+
+  #[actix_web::test]
+  async fn socket_tickets_open_one_socket_of_their_session() {
+    let state = memory();
+    let mut state = state.write().await;
+    let token = state.add_device("user", String::new());
+
+    let ticket = issue(&mut state, &token, "s1");
+    assert_eq!(state.take_socket_ticket(&ticket, "s1").unwrap().email, "a@b.c");
+    assert!(state.take_socket_ticket(&ticket, "s1").is_none());
+
+    // Trying it on another session uses it up all the same
+    let ticket = issue(&mut state, &token, "s1");
+    assert!(state.take_socket_ticket(&ticket, "s2").is_none());
+    assert!(state.take_socket_ticket(&ticket, "s1").is_none());
+
+    // So does signing the device out
+    let ticket = issue(&mut state, &token, "s1");
+    let device = state.find_device(&token).unwrap().id.clone();
+    state.revoke_device(&device);
+    assert!(state.take_socket_ticket(&ticket, "s1").is_none());
+  }
+
+  #[actix_web::test]
+  async fn socket_tickets_expire() {
+    let state = memory();
+    let mut state = state.write().await;
+    let token = state.add_device("user", String::new());
+
+    let ticket = issue(&mut state, &token, "s1");
+    state.socket_tickets.get_mut(&ticket).unwrap().expires_at = Utc::now().timestamp() as u64;
+    assert!(state.take_socket_ticket(&ticket, "s1").is_none());
+
+    // Expired ones that were never used are dropped when the next one is issued
+    let stale = issue(&mut state, &token, "s1");
+    state.socket_tickets.get_mut(&stale).unwrap().expires_at = 0;
+    issue(&mut state, &token, "s1");
+    assert_eq!(state.socket_tickets.len(), 1);
+    assert!(!state.socket_tickets.contains_key(&stale));
+  }
+
+  #[actix_web::test]
+  async fn rotation_seals_plaintext_records() {
+    let name = Uuid::new_v4().to_string();
+    let plain = storage(&name, None);
+    seed(&plain);
+
+    let keyed = storage(&name, Some([1; 32]));
+    let state = State::for_tests(Arc::clone(&keyed)).unwrap();
+    assert!(keyed.keyring().rotation_pending());
+
+    State::rotate_keys(state).await;
+    storage::run(|| Ok(())).await.unwrap();
+
+    // Nothing is left in plain JSON, and a fresh start under the key has nothing to rewrite
+    let err = plain.load_patients().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(plain.load_sessions().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+    let reopened = storage(&name, Some([1; 32]));
+    assert_eq!(reopened.load_patients().unwrap()[0].name, "Jan");
+    assert_eq!(reopened.load_sessions().unwrap()[0].patient_uuid, "p1");
+    assert!(!reopened.keyring().rotation_pending());
+  }
+
+  #[actix_web::test]
+  async fn refuses_to_start_with_the_wrong_key() {
+    let name = Uuid::new_v4().to_string();
+    let sealed = storage(&name, Some([1; 32]));
+    seed(&sealed);
+
+    let err = State::for_tests(storage(&name, Some([2; 32]))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("is encrypted with key"), "{}", err);
+
+    // Nothing was quarantined along the way
+    assert!(sealed.quarantined().unwrap().is_empty());
+    assert_eq!(sealed.load_patients().unwrap().len(), 1);
+  }
+}
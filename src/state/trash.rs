@@ -0,0 +1,156 @@
+use super::patient::Patient;
+use super::session::Session;
+use super::state::{SseEvent, State};
+use crate::audit::{self, Source};
+use crate::storage::migrations::Document;
+use crate::storage::{self, Storage};
+use crate::AppState;
+use crate::logs::*;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::time::interval;
+
+const TRASH_RETENTION: u64 = 60 * 60 * 24 * 30; // Keep deleted records for 30 days
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Trashed {
+  Patient {
+    patient: Patient,
+    sessions: Vec<Session>,
+  },
+  Session {
+    session: Session,
+  },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashItem {
+  pub schema_version: u32,
+  pub uuid: String,
+  pub deleted_by: String,
+  pub deleted_at: u64,
+  pub expires_at: u64,
+  #[serde(flatten)]
+  pub item: Trashed,
+}
+
+impl TrashItem {
+  pub fn new(item: Trashed, deleted_by: &str) -> Self {
+    let uuid = match &item {
+      Trashed::Patient { patient, .. } => patient.uuid.clone(),
+      Trashed::Session { session } => session.uuid.clone(),
+    };
+
+    let now = Utc::now().timestamp() as u64;
+    TrashItem {
+      schema_version: Document::Trash.version(),
+      uuid,
+      deleted_by: deleted_by.to_owned(),
+      deleted_at: now,
+      expires_at: now + TRASH_RETENTION,
+      item,
+    }
+  }
+
+  pub fn write(&self, storage: &Arc<dyn Storage>) {
+    let (storage, record) = (Arc::clone(storage), self.clone());
+    storage::spawn_write(move || {
+      if let Err(err) = storage.save_trash_item(&record) {
+        error!("Couldn't write trash item {}: {}", record.uuid, err);
+      }
+    });
+  }
+
+  pub fn delete(&self, storage: &Arc<dyn Storage>) {
+    let (storage, uuid) = (Arc::clone(storage), self.uuid.clone());
+    storage::spawn_write(move || {
+      if let Err(err) = storage.delete_trash_item(&uuid) {
+        error!("Couldn't delete trash item {}: {}", uuid, err);
+      }
+    });
+  }
+}
+
+pub fn start_purge_loop(state: AppState) {
+  tokio::spawn(async move {
+    info!("Starting trash purge loop...");
+    let mut interval = interval(PURGE_INTERVAL);
+
+    loop {
+      interval.tick().await;
+      purge(&mut *state.write().await).await;
+    }
+  });
+}
+
+async fn purge(app_state: &mut State) {
+  let storage = Arc::clone(&app_state.storage);
+  let now = Utc::now().timestamp() as u64;
+  let expired = app_state.trash.iter().filter(|item| item.expires_at <= now).map(|item| item.uuid.clone()).collect::<Vec<_>>();
+
+  for uuid in expired {
+    let index = app_state.trash.iter().position(|item| item.uuid == uuid).unwrap();
+    let mut batch = audit::Batch::new("system", Source::System);
+    match &app_state.trash[index].item {
+      Trashed::Patient { patient, sessions } => {
+        sessions.iter().for_each(|session| { batch.purged(session); });
+        batch.purged(patient);
+      },
+      Trashed::Session { session } => { batch.purged(session); },
+    }
+
+    // Left in the trash for the next round if the purge can't be recorded
+    if let Err(err) = batch.commit(&storage).await {
+      error!("Couldn't record the purge of {}, keeping it: {}", uuid, err);
+      continue;
+    }
+
+    let item = app_state.trash.remove(index);
+    item.delete(&storage);
+    app_state.broadcast(SseEvent::TrashRemoved(&item.uuid)).await;
+    info!("Purged {} from the trash", item.uuid);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audit::Action;
+  use crate::state::state::State;
+  use crate::storage::{encryption, StorageKind};
+
+  #[actix_web::test]
+  async fn purges_only_what_expired() {
+    let _ = encryption::init();
+    let storage = storage::open(StorageKind::Memory, "").unwrap();
+    let state = State::for_tests(Arc::clone(&storage)).unwrap();
+    let mut app_state = state.write().await;
+
+    let patient = Patient { uuid: "p1".into(), name: "Jan".into(), ..Default::default() };
+    let session = Session { uuid: "s1".into(), patient_uuid: "p1".into(), ..Default::default() };
+    let mut expired = TrashItem::new(Trashed::Patient { patient, sessions: vec![session] }, "a@b.c");
+    expired.expires_at = Utc::now().timestamp() as u64;
+    app_state.move_to_trash(expired).await;
+
+    let kept = Session { uuid: "s2".into(), patient_uuid: "p2".into(), ..Default::default() };
+    app_state.move_to_trash(TrashItem::new(Trashed::Session { session: kept }, "a@b.c")).await;
+
+    purge(&mut app_state).await;
+    storage::run(|| Ok(())).await.unwrap();
+
+    assert_eq!(app_state.trash.iter().map(|item| item.uuid.as_str()).collect::<Vec<_>>(), ["s2"]);
+    assert_eq!(storage.load_trash().unwrap().iter().map(|item| item.uuid.as_str()).collect::<Vec<_>>(), ["s2"]);
+
+    // Both the patient and the session that went with it are on record as purged
+    let page = storage.load_audit(None, 10, &|_| true).unwrap();
+    let mut purged = page.entries.iter().filter(|entry| entry.action == Action::Purge).map(|entry| entry.uuid.as_str()).collect::<Vec<_>>();
+    purged.sort();
+    assert_eq!(purged, ["p1", "s1"]);
+  }
+}
@@ -1,10 +1,91 @@
-use super::state::Secrets;
+use super::patient::Patient;
+use crate::totp::Totp;
 
-use std::sync::Arc;
+use std::str::FromStr;
 
+use actix_web::error::ErrorForbidden;
 use serde::{Serialize, Deserialize};
 use tokio::sync::mpsc;
 
+// There's deliberately no default, accounts from before roles existed get one when the state loads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+  Owner,
+  Therapist,
+  Assistant,
+  Billing,
+}
+
+impl Role {
+  pub fn can_read_notes(self) -> bool {
+    matches!(self, Role::Owner | Role::Therapist)
+  }
+
+  pub fn can_manage_patients(self) -> bool {
+    matches!(self, Role::Owner | Role::Therapist)
+  }
+
+  pub fn can_schedule(self) -> bool {
+    matches!(self, Role::Owner | Role::Therapist | Role::Assistant)
+  }
+
+  pub fn can_bill(self) -> bool {
+    matches!(self, Role::Owner | Role::Therapist | Role::Billing)
+  }
+
+  // Everyone but the owner only sees the patients they're assigned to
+  pub fn sees_all_patients(self) -> bool {
+    self == Role::Owner
+  }
+
+  pub fn can_see(self, email: &str, patient: &Patient) -> bool {
+    self.sees_all_patients() || patient.practitioners.iter().any(|practitioner| practitioner == email)
+  }
+}
+
+impl FromStr for Role {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "owner" => Ok(Role::Owner),
+      "therapist" => Ok(Role::Therapist),
+      "assistant" => Ok(Role::Assistant),
+      "billing" => Ok(Role::Billing),
+      _ => Err(format!("Unknown role: {}", s)),
+    }
+  }
+}
+
+// A Google account that may sign in for the first time, removed once it does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invitation {
+  pub role: Role,
+  pub invited_by: String,
+  pub invited_at: u64,
+}
+
+// Who is behind a request, resolved from the access token
+#[derive(Debug, Clone)]
+pub struct Access {
+  // Key of the user in State.users
+  pub token: String,
+  pub device: String,
+  pub email: String,
+  pub role: Role,
+}
+
+impl Access {
+  pub fn can_see(&self, patient: &Patient) -> bool {
+    self.role.can_see(&self.email, patient)
+  }
+
+  pub fn require(&self, allowed: bool) -> Result<(), actix_web::Error> {
+    if allowed { Ok(()) } else { Err(ErrorForbidden("Forbidden")) }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct User {
   pub access_token: String,
@@ -12,15 +93,25 @@ pub struct User {
   pub refresh_token: String,
   pub user_info: UserInfo,
   pub settings: Settings,
+  pub role: Role,
+  pub totp: Option<Totp>,
 
   pub stop_tx: mpsc::Sender<()>,
   pub write_tx: mpsc::Sender<()>,
-  pub secrets: Arc<Secrets>,
+}
+
+impl User {
+  pub fn can_see(&self, patient: &Patient) -> bool {
+    self.role.can_see(&self.user_info.email, patient)
+  }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct Settings {
   pub google_calendar_enabled: bool,
+  // Deleting data permanently asks for a code from the authenticator app
+  #[serde(default)]
+  pub step_up_enabled: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -34,6 +125,21 @@ pub struct UserInfo {
   pub locale: String,
 }
 
+#[cfg(test)]
+impl UserInfo {
+  pub fn for_tests(email: &str) -> Self {
+    UserInfo {
+      id: email.to_owned(),
+      email: email.to_owned(),
+      verified_email: true,
+      name: email.to_owned(),
+      given_name: email.to_owned(),
+      picture: String::new(),
+      locale: String::new(),
+    }
+  }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct RwUser {
   pub id: String,
@@ -45,6 +151,11 @@ pub struct RwUser {
   pub locale: String,
   
   pub settings: Settings,
+  // Missing on accounts from before roles existed
+  #[serde(default)]
+  pub role: Option<Role>,
+  #[serde(default)]
+  pub totp: Option<Totp>,
   pub access_token: String,
   pub expires_at: u64,
   pub refresh_token: String,
@@ -62,6 +173,8 @@ impl RwUser {
       locale: u.user_info.locale.clone(),
 
       settings: u.settings.clone(),
+      role: Some(u.role),
+      totp: u.totp.clone(),
       access_token: u.access_token.clone(),
       expires_at: u.expires_at,
       refresh_token: u.refresh_token.clone(),
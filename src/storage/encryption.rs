@@ -0,0 +1,160 @@
+use crate::crypto;
+
+use std::env;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Patient, session, trash and job records, cached events and audit entries are wrapped in an
+// envelope when RECORD_KEY is set.
+// Keys are 32 random bytes in base64 (e.g. `openssl rand -base64 32`). To rotate, move the old
+// key to RECORD_KEY_PREVIOUS and set a new RECORD_KEY, everything gets re-encrypted after startup.
+// Moving the key to RECORD_KEY_PREVIOUS alone decrypts the records back to plain JSON.
+// The audit log is append-only and never rewritten, its older entries stay readable only for as
+// long as the key they were sealed with is RECORD_KEY or RECORD_KEY_PREVIOUS.
+// The key id, the record's kind and its uuid are authenticated with the data, so a sealed record
+// can't be copied over another one or under another name.
+struct Key {
+  id: String,
+  key: [u8; 32],
+}
+
+impl Key {
+  fn new(key: [u8; 32]) -> Self {
+    let id = format!("{:x}", Sha256::digest(key))[..16].to_owned();
+    Key { id, key }
+  }
+}
+
+// Each storage keeps its own, the one read from the environment is shared by everything else
+pub struct Keyring {
+  current: Option<Key>,
+  previous: Option<Key>,
+  // Set when a loaded record wasn't stored under the current key
+  stale: AtomicBool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+  encrypted: Sealed,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+  key: String,
+  nonce: String,
+  data: String,
+  // Envelopes without it only authenticated the key id, they're rewritten like a rotation
+  #[serde(default)]
+  bound: bool,
+}
+
+// Audit entries aren't known by anything before they're opened, so they're bound to the log itself
+pub const AUDIT_LOG: &str = "log";
+
+static KEYRING: OnceLock<Arc<Keyring>> = OnceLock::new();
+
+fn parse_key(var: &str) -> io::Result<Option<Key>> {
+  let encoded = match env::var(var) {
+    Ok(encoded) if !encoded.trim().is_empty() => encoded,
+    _ => return Ok(None),
+  };
+
+  let key: [u8; 32] = BASE64.decode(encoded.trim()).ok().and_then(|key| key.try_into().ok())
+    .ok_or(io::Error::new(io::ErrorKind::InvalidInput, format!("{} must be 32 bytes encoded as base64", var)))?;
+
+  Ok(Some(Key::new(key)))
+}
+
+pub fn init() -> io::Result<()> {
+  let keyring = Keyring::new(parse_key("RECORD_KEY")?, parse_key("RECORD_KEY_PREVIOUS")?);
+  KEYRING.set(Arc::new(keyring)).map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "Keyring already initialized"))
+}
+
+pub fn keyring() -> Arc<Keyring> {
+  Arc::clone(KEYRING.get().expect("Keyring not initialized"))
+}
+
+impl Keyring {
+  fn new(current: Option<Key>, previous: Option<Key>) -> Self {
+    Keyring { current, previous, stale: AtomicBool::new(false) }
+  }
+
+  #[cfg(test)]
+  pub fn with_keys(current: Option<[u8; 32]>, previous: Option<[u8; 32]>) -> Self {
+    Keyring::new(current.map(Key::new), previous.map(Key::new))
+  }
+
+  pub fn key_id(&self) -> Option<&str> {
+    self.current.as_ref().map(|key| key.id.as_str())
+  }
+
+  // Whether a loaded record wasn't stored under the current key and should be rewritten
+  pub fn rotation_pending(&self) -> bool {
+    self.stale.load(Ordering::Relaxed)
+  }
+
+  pub fn seal(&self, kind: &str, uuid: &str, json: String) -> io::Result<String> {
+    let key = match &self.current {
+      Some(key) => key,
+      None => return Ok(json),
+    };
+
+    let (nonce, data) = crypto::encrypt(&key.key, json.as_bytes(), &aad(key, kind, uuid))?;
+    let envelope = Envelope {
+      encrypted: Sealed {
+        key: key.id.clone(),
+        nonce: BASE64.encode(nonce),
+        data: BASE64.encode(data),
+        bound: true,
+      },
+    };
+
+    Ok(serde_json::to_string(&envelope)?)
+  }
+
+  // Returns the record's plain JSON. A record encrypted with a key we don't have fails with
+  // PermissionDenied so callers can refuse to start instead of quarantining everything.
+  pub fn unseal(&self, kind: &str, uuid: &str, raw: &str) -> io::Result<String> {
+    let sealed = match serde_json::from_str::<Envelope>(raw) {
+      Ok(envelope) => envelope.encrypted,
+      Err(_) => {
+        if self.current.is_some() {
+          self.stale.store(true, Ordering::Relaxed);
+        }
+
+        return Ok(raw.to_owned());
+      }
+    };
+
+    let key = [&self.current, &self.previous].into_iter().flatten().find(|key| key.id == sealed.key);
+    let key = match key {
+      Some(key) => key,
+      None => return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!(
+        "Record is encrypted with key {} but RECORD_KEY is {}",
+        sealed.key,
+        self.key_id().unwrap_or("not set"),
+      ))),
+    };
+
+    if !sealed.bound || self.current.as_ref().is_none_or(|current| current.id != key.id) {
+      self.stale.store(true, Ordering::Relaxed);
+    }
+
+    let aad = if sealed.bound { aad(key, kind, uuid) } else { key.id.as_bytes().to_vec() };
+    let nonce = BASE64.decode(&sealed.nonce).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let data = BASE64.decode(&sealed.data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let json = crypto::decrypt(&key.key, &nonce, &data, &aad)?;
+
+    String::from_utf8(json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+  }
+}
+
+fn aad(key: &Key, kind: &str, uuid: &str) -> Vec<u8> {
+  format!("{}:{}:{}", key.id, kind, uuid).into_bytes()
+}
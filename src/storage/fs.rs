@@ -0,0 +1,491 @@
+use crate::state::state::{RwState, GoogleEvent};
+use crate::state::patient::{Patient, FsPatient};
+use crate::state::session::{Session, FsSession};
+use crate::state::trash::TrashItem;
+use crate::audit::{AuditEntry, AuditPage};
+use crate::jobs::Job;
+use crate::logs::*;
+use super::migrations::{self, Document};
+use super::encryption::{self, Keyring, AUDIT_LOG};
+use super::{Storage, QuarantinedRecord};
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::{fs, io, mem, process};
+
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+
+const TMP_EXTENSION: &str = "tmp";
+const AUDIT_CHUNK: usize = 64 * 1024;
+const DIRS: [&str; 6] = ["", "patients", "sessions", "events", "trash", "jobs"];
+
+// Directory-of-JSON layout: patients/<uuid>.json, sessions/<uuid>.json, trash/<uuid>.json, jobs/<uuid>.json, events/<token>.json and state.json
+pub struct FsStorage {
+  path: String,
+  keyring: Arc<Keyring>,
+}
+
+impl FsStorage {
+  pub fn new(path: &str) -> io::Result<Self> {
+    fs::create_dir_all(format!("{}patients", path))?;
+    fs::create_dir_all(format!("{}sessions", path))?;
+    fs::create_dir_all(format!("{}events", path))?;
+    fs::create_dir_all(format!("{}trash", path))?;
+    fs::create_dir_all(format!("{}jobs", path))?;
+
+    let storage = FsStorage { path: path.to_owned(), keyring: encryption::keyring() };
+    storage.recover()?;
+    Ok(storage)
+  }
+
+  // Writes go to <file>.<pid>-<n>.tmp first, get fsynced and are then renamed over the target, so a crash
+  // leaves either the old or the new version of the file, never a truncated one. Every write gets its own
+  // temp file, two writes of the same file can't clobber each other's halfway through.
+  fn write_atomic(path: String, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let tmp = format!("{}.{}-{}.{}", path, process::id(), WRITES.fetch_add(1, Ordering::Relaxed), TMP_EXTENSION);
+
+    let result = (|| {
+      let mut file = File::create(&tmp)?;
+      file.write_all(contents.as_ref())?;
+      file.sync_all()?;
+      drop(file);
+
+      fs::rename(&tmp, &path)
+    })();
+
+    if result.is_err() {
+      let _ = fs::remove_file(&tmp);
+    }
+
+    result?;
+    Self::sync_parent(Path::new(&path))
+  }
+
+  fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+      Some(parent) => File::open(parent)?.sync_all(),
+      None => Ok(()),
+    }
+  }
+
+  // A stray temp file means we crashed between writing it and renaming it. If it holds
+  // complete JSON the write finished and only the rename is missing, otherwise it's garbage.
+  // Temp files are handled oldest first, so the newest complete write of a file wins.
+  fn recover(&self) -> io::Result<()> {
+    for dir in DIRS {
+      let mut tmps = Vec::new();
+      for entry in fs::read_dir(format!("{}{}", self.path, dir))? {
+        let entry = entry?;
+        let tmp = entry.path();
+        if tmp.is_file() && tmp.extension().and_then(|ext| ext.to_str()) == Some(TMP_EXTENSION) {
+          tmps.push((entry.metadata()?.modified()?, tmp));
+        }
+      }
+
+      tmps.sort();
+      for (_, tmp) in tmps {
+        let target = Self::tmp_target(&tmp);
+        let is_complete = fs::read(&tmp).is_ok_and(|data| serde_json::from_slice::<serde_json::Value>(&data).is_ok());
+
+        if is_complete {
+          warning!("Completing interrupted write of {}", target.display());
+          fs::rename(&tmp, &target)?;
+          Self::sync_parent(&target)?;
+        } else {
+          warning!("Discarding incomplete write of {}", target.display());
+          fs::remove_file(&tmp)?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  // <file>.json.<pid>-<n>.tmp, or <file>.json.tmp as written by older versions
+  fn tmp_target(tmp: &Path) -> PathBuf {
+    let target = tmp.with_extension("");
+    match target.extension().and_then(|ext| ext.to_str()) {
+      Some("json") => target,
+      _ => target.with_extension(""),
+    }
+  }
+
+  // Unreadable records are moved to quarantine/<dir> instead of failing the whole load
+  fn read_dir<T: DeserializeOwned>(&self, dir: &str, kind: &str, document: Document) -> io::Result<Vec<(String, T, bool)>> {
+    let mut items = Vec::new();
+    for entry in fs::read_dir(format!("{}{}", self.path, dir))? {
+      let path = entry?.path();
+      if !path.is_file() || path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        continue;
+      }
+
+      match self.read_file(&path, kind, document) {
+        Ok(item) => items.push(item),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+          return Err(io::Error::new(err.kind(), format!("Couldn't load {}: {}", path.display(), err)));
+        },
+        Err(err) => {
+          error!("Couldn't load {}, moving it to quarantine: {}", path.display(), err);
+          self.quarantine(dir, &path, &err.to_string())?;
+        }
+      }
+    }
+
+    Ok(items)
+  }
+
+  fn read_file<T: DeserializeOwned>(&self, path: &Path, kind: &str, document: Document) -> io::Result<(String, T, bool)> {
+    let uuid = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_owned();
+    let file = self.keyring.unseal(kind, &uuid, &fs::read_to_string(path)?)?;
+    let (item, migrated) = migrations::parse::<T>(document, &file)?;
+    Ok((uuid, item, migrated))
+  }
+
+  // The error is kept next to the record as <uuid>.<timestamp>.reason
+  fn quarantine(&self, dir: &str, path: &Path, reason: &str) -> io::Result<()> {
+    let quarantine = format!("{}quarantine/{}", self.path, dir);
+    fs::create_dir_all(&quarantine)?;
+
+    let uuid = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let name = format!("{}/{}.{}", quarantine, uuid, Utc::now().timestamp());
+    Self::write_atomic(format!("{}.reason", name), reason)?;
+    fs::rename(path, format!("{}.json", name))
+  }
+
+  fn remove(&self, path: String) -> io::Result<()> {
+    match fs::remove_file(path) {
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+      res => res,
+    }
+  }
+}
+
+impl Storage for FsStorage {
+  fn load_patients(&self) -> io::Result<Vec<Patient>> {
+    let mut patients = Vec::new();
+    for (uuid, patient, migrated) in self.read_dir::<FsPatient>("patients", "patient", Document::Patient)? {
+      let patient = Patient::from_fs(uuid, patient);
+      if migrated {
+        info!("Migrated patient {} to schema version {}", patient.uuid, Document::Patient.version());
+        self.save_patient(&patient)?;
+      }
+
+      patients.push(patient);
+    }
+
+    info!("Loaded {} patients", patients.len());
+    Ok(patients)
+  }
+
+  fn save_patient(&self, patient: &Patient) -> io::Result<()> {
+    Self::write_atomic(format!("{}patients/{}.json", self.path, patient.uuid), self.keyring.seal("patient", &patient.uuid, serde_json::to_string(&patient.to_fs())?)?)
+  }
+
+  fn delete_patient(&self, uuid: &str) -> io::Result<()> {
+    self.remove(format!("{}patients/{}.json", self.path, uuid))
+  }
+
+  fn load_sessions(&self) -> io::Result<Vec<Session>> {
+    let mut sessions = Vec::new();
+    for (uuid, session, migrated) in self.read_dir::<FsSession>("sessions", "session", Document::Session)? {
+      let session = Session::from_fs(uuid, session);
+      if migrated {
+        info!("Migrated session {} to schema version {}", session.uuid, Document::Session.version());
+        self.save_session(&session)?;
+      }
+
+      sessions.push(session);
+    }
+
+    info!("Loaded {} sessions", sessions.len());
+    Ok(sessions)
+  }
+
+  fn save_session(&self, session: &Session) -> io::Result<()> {
+    Self::write_atomic(format!("{}sessions/{}.json", self.path, session.uuid), self.keyring.seal("session", &session.uuid, serde_json::to_string(&session.to_fs())?)?)
+  }
+
+  fn delete_session(&self, uuid: &str) -> io::Result<()> {
+    self.remove(format!("{}sessions/{}.json", self.path, uuid))
+  }
+
+  fn load_trash(&self) -> io::Result<Vec<TrashItem>> {
+    let mut trash = Vec::new();
+    for (_, item, migrated) in self.read_dir::<TrashItem>("trash", "trash", Document::Trash)? {
+      if migrated {
+        self.save_trash_item(&item)?;
+      }
+
+      trash.push(item);
+    }
+
+    info!("Loaded {} trash items", trash.len());
+    Ok(trash)
+  }
+
+  fn save_trash_item(&self, item: &TrashItem) -> io::Result<()> {
+    Self::write_atomic(format!("{}trash/{}.json", self.path, item.uuid), self.keyring.seal("trash", &item.uuid, serde_json::to_string(item)?)?)
+  }
+
+  fn delete_trash_item(&self, uuid: &str) -> io::Result<()> {
+    self.remove(format!("{}trash/{}.json", self.path, uuid))
+  }
+
+  fn load_state(&self) -> io::Result<Option<RwState>> {
+    let path = format!("{}state.json", self.path);
+    if fs::metadata(&path).is_err() {
+      return Ok(None);
+    }
+
+    let file = fs::read_to_string(&path)?;
+    let (state, migrated) = migrations::parse::<RwState>(Document::State, &file)?;
+    if migrated {
+      info!("Migrated state to schema version {}", Document::State.version());
+      self.save_state(&state)?;
+    }
+
+    Ok(Some(state))
+  }
+
+  fn save_state(&self, state: &RwState) -> io::Result<()> {
+    Self::write_atomic(format!("{}state.json", self.path), serde_json::to_string(state)?)
+  }
+
+  fn load_events(&self, user: &str) -> io::Result<Option<Vec<GoogleEvent>>> {
+    match fs::read_to_string(format!("{}events/{}.json", self.path, user)) {
+      Ok(file) => Ok(Some(serde_json::from_str(&self.keyring.unseal("events", user, &file)?)?)),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(err) => Err(err),
+    }
+  }
+
+  fn save_events(&self, user: &str, events: &[GoogleEvent]) -> io::Result<()> {
+    Self::write_atomic(format!("{}events/{}.json", self.path, user), self.keyring.seal("events", user, serde_json::to_string(events)?)?)
+  }
+
+  fn delete_events(&self, user: &str) -> io::Result<()> {
+    self.remove(format!("{}events/{}.json", self.path, user))
+  }
+
+  fn load_jobs(&self) -> io::Result<Vec<Job>> {
+    let mut jobs = Vec::new();
+    for entry in fs::read_dir(format!("{}jobs", self.path))? {
+      let path = entry?.path();
+      if !path.is_file() || path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        continue;
+      }
+
+      let id = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_owned();
+      match fs::read_to_string(&path).and_then(|file| self.keyring.unseal("job", &id, &file)).and_then(|json| Ok(serde_json::from_str(&json)?)) {
+        Ok(job) => jobs.push(job),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+          return Err(io::Error::new(err.kind(), format!("Couldn't load {}: {}", path.display(), err)));
+        },
+        Err(err) => {
+          error!("Couldn't load {}, moving it to quarantine: {}", path.display(), err);
+          self.quarantine("jobs", &path, &err.to_string())?;
+        }
+      }
+    }
+
+    Ok(jobs)
+  }
+
+  fn save_job(&self, job: &Job) -> io::Result<()> {
+    Self::write_atomic(format!("{}jobs/{}.json", self.path, job.id), self.keyring.seal("job", &job.id, serde_json::to_string(job)?)?)
+  }
+
+  fn delete_job(&self, id: &str) -> io::Result<()> {
+    self.remove(format!("{}jobs/{}.json", self.path, id))
+  }
+
+  fn append_audit(&self, entries: &[AuditEntry]) -> io::Result<()> {
+    let mut lines = String::new();
+    for entry in entries {
+      lines.push_str(&self.keyring.seal("audit", AUDIT_LOG, serde_json::to_string(entry)?)?);
+      lines.push('\n');
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(format!("{}audit.jsonl", self.path))?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()
+  }
+
+  // The log is read backwards from the cursor (a byte offset) in chunks, so a page only touches the end of the file
+  fn load_audit(&self, before: Option<u64>, limit: usize, filter: &dyn Fn(&AuditEntry) -> bool) -> io::Result<AuditPage> {
+    let mut file = match File::open(format!("{}audit.jsonl", self.path)) {
+      Ok(file) => file,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(AuditPage { entries: Vec::new(), next: None }),
+      Err(err) => return Err(err),
+    };
+
+    let mut start = before.unwrap_or(u64::MAX).min(file.metadata()?.len());
+    let mut rest = Vec::new();
+    let mut entries = Vec::new();
+
+    loop {
+      // A line is complete once the newline in front of it was read, or the start of the file was reached
+      loop {
+        let (line, offset) = match rest.iter().rposition(|&byte| byte == b'\n') {
+          Some(newline) => {
+            let line = rest.split_off(newline + 1);
+            rest.pop();
+            (line, start + newline as u64 + 1)
+          },
+          None if start == 0 && !rest.is_empty() => (mem::take(&mut rest), 0),
+          None => break,
+        };
+
+        if line.is_empty() {
+          continue;
+        }
+
+        if entries.len() == limit {
+          return Ok(AuditPage { entries, next: Some(offset + line.len() as u64) });
+        }
+
+        // A crash mid-append can leave a partial last line behind, and entries sealed under a key
+        // that's since been dropped can't be opened anymore
+        let entry = String::from_utf8(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+          .and_then(|line| self.keyring.unseal("audit", AUDIT_LOG, &line))
+          .and_then(|json| Ok(serde_json::from_str::<AuditEntry>(&json)?));
+
+        match entry {
+          Ok(entry) if filter(&entry) => entries.push(entry),
+          Ok(_) => {},
+          Err(err) => warning!("Skipping unreadable audit entry: {}", err),
+        }
+      }
+
+      if start == 0 {
+        return Ok(AuditPage { entries, next: None });
+      }
+
+      let size = (start as usize).min(AUDIT_CHUNK);
+      start -= size as u64;
+      let mut chunk = vec![0; size];
+      file.seek(SeekFrom::Start(start))?;
+      file.read_exact(&mut chunk)?;
+
+      chunk.append(&mut rest);
+      rest = chunk;
+    }
+  }
+
+  fn quarantined(&self) -> io::Result<Vec<QuarantinedRecord>> {
+    let mut records = Vec::new();
+    for (kind, dir) in [("patient", "patients"), ("session", "sessions"), ("trash", "trash"), ("job", "jobs")] {
+      let entries = match fs::read_dir(format!("{}quarantine/{}", self.path, dir)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+        Err(err) => return Err(err),
+      };
+
+      for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+          continue;
+        }
+
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        let (uuid, quarantined_at) = stem.rsplit_once('.').unwrap_or((stem, "0"));
+        let reason = match fs::read_to_string(path.with_extension("reason")) {
+          Ok(reason) => reason,
+          Err(err) if err.kind() == io::ErrorKind::NotFound => String::from("No reason was recorded"),
+          Err(err) => return Err(err),
+        };
+
+        records.push(QuarantinedRecord {
+          kind: kind.into(),
+          uuid: uuid.into(),
+          reason,
+          quarantined_at: quarantined_at.parse().unwrap_or_default(),
+        });
+      }
+    }
+
+    Ok(records)
+  }
+
+  fn backup_paths(&self) -> Vec<&'static str> {
+    vec!["state.json", "patients", "sessions", "trash", "jobs", "events", "audit.jsonl"]
+  }
+
+  fn keyring(&self) -> &Keyring {
+    &self.keyring
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+  use std::thread;
+
+  #[test]
+  fn concurrent_writes_of_one_file_dont_collide() {
+    let _ = encryption::init();
+    let dir = std::env::temp_dir().join(format!("dashboard-fs-{}", uuid::Uuid::new_v4()));
+    let storage = Arc::new(FsStorage::new(&format!("{}/", dir.display())).unwrap());
+
+    let threads = (0..8).map(|i| {
+      let storage = Arc::clone(&storage);
+      thread::spawn(move || (0..50).for_each(|_| storage.save_session(&Session { uuid: "s1".into(), paid: i as f32, ..Default::default() }).unwrap()))
+    }).collect::<Vec<_>>();
+    threads.into_iter().for_each(|thread| thread.join().unwrap());
+
+    assert_eq!(storage.load_sessions().unwrap().len(), 1);
+    assert_eq!(fs::read_dir(dir.join("sessions")).unwrap().count(), 1);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn quarantine_keeps_the_original_error() {
+    let _ = encryption::init();
+    let dir = std::env::temp_dir().join(format!("dashboard-fs-{}", uuid::Uuid::new_v4()));
+    let storage = FsStorage::new(&format!("{}/", dir.display())).unwrap();
+    fs::write(dir.join("patients/p1.json"), "{").unwrap();
+    fs::write(dir.join("jobs/j1.json"), "[]").unwrap();
+
+    assert!(storage.load_patients().unwrap().is_empty());
+    assert!(storage.load_jobs().unwrap().is_empty());
+
+    let mut records = storage.quarantined().unwrap();
+    records.sort_by(|a, b| a.kind.cmp(&b.kind));
+    assert_eq!(records.iter().map(|record| (record.kind.as_str(), record.uuid.as_str())).collect::<Vec<_>>(), [("job", "j1"), ("patient", "p1")]);
+    assert!(records[0].reason.contains("expected struct Job"));
+    assert!(records[1].reason.contains("EOF"));
+
+    // Fixing the file in quarantine doesn't change why it was quarantined
+    let patient = fs::read_dir(dir.join("quarantine/patients")).unwrap().map(|entry| entry.unwrap().path()).find(|path| path.extension().unwrap() == "json").unwrap();
+    fs::write(patient, "{}").unwrap();
+    assert!(storage.quarantined().unwrap().iter().any(|record| record.kind == "patient" && record.reason.contains("EOF")));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn temp_files_map_back_to_their_target() {
+    assert_eq!(FsStorage::tmp_target(Path::new("sessions/s1.json.12-3.tmp")), Path::new("sessions/s1.json"));
+    assert_eq!(FsStorage::tmp_target(Path::new("state.json.tmp")), Path::new("state.json"));
+  }
+
+  #[test]
+  fn audit_pages_cover_the_whole_log() {
+    let _ = encryption::init();
+    let dir = std::env::temp_dir().join(format!("dashboard-fs-{}", uuid::Uuid::new_v4()));
+    let storage = FsStorage::new(&format!("{}/", dir.display())).unwrap();
+
+    // More than one chunk, so lines get split across reads
+    let count = AUDIT_CHUNK / 100;
+    let entries = crate::storage::tests::entries(count);
+    entries.chunks(7).for_each(|chunk| storage.append_audit(chunk).unwrap());
+    crate::storage::tests::assert_pages(&storage, count);
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
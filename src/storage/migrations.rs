@@ -0,0 +1,186 @@
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+// Documents written before versioning was introduced have no schema_version and count as version 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Document {
+  Patient,
+  Session,
+  State,
+  Trash,
+}
+
+type Migration = fn(&mut Value);
+
+// MIGRATIONS[n] upgrades a document from version n to n + 1, so the current version is the length of the list
+const PATIENT_MIGRATIONS: &[Migration] = &[noop, patient_v2];
+const SESSION_MIGRATIONS: &[Migration] = &[noop];
+const STATE_MIGRATIONS: &[Migration] = &[state_v1, state_v2, state_v3];
+const TRASH_MIGRATIONS: &[Migration] = &[noop];
+
+impl Document {
+  fn migrations(self) -> &'static [Migration] {
+    match self {
+      Document::Patient => PATIENT_MIGRATIONS,
+      Document::Session => SESSION_MIGRATIONS,
+      Document::State => STATE_MIGRATIONS,
+      Document::Trash => TRASH_MIGRATIONS,
+    }
+  }
+
+  pub fn version(self) -> u32 {
+    self.migrations().len() as u32
+  }
+}
+
+// Only stamps the version on documents that predate it
+fn noop(_: &mut Value) {}
+
+// calendar_webhooks used to be missing from state.json entirely
+fn state_v1(value: &mut Value) {
+  if value.get("calendar_webhooks").is_none_or(Value::is_null) {
+    value["calendar_webhooks"] = json!({});
+  }
+}
+
+// Allowed accounts used to come only from the USERS environment variable
+fn state_v2(value: &mut Value) {
+  if value.get("invitations").is_none_or(Value::is_null) {
+    value["invitations"] = json!({});
+  }
+}
+
+// Users used to be keyed by their only access token, which now becomes their first device.
+// SSE tokens are keyed by device id from here on, clients just fetch a new one.
+fn state_v3(value: &mut Value) {
+  let now = chrono::Utc::now().timestamp() as u64;
+  let devices = value.get("users").and_then(Value::as_object).map(|users| users.keys().map(|token| (token.clone(), json!({
+    "id": uuid::Uuid::new_v4().to_string(),
+    "user": token,
+    "user_agent": "",
+    "created_at": now,
+    "last_seen": now,
+  }))).collect::<serde_json::Map<_, _>>()).unwrap_or_default();
+
+  value["devices"] = Value::Object(devices);
+  value["sse_tokens"] = json!({});
+}
+
+// Patients were visible to everyone before they could be assigned to practitioners
+fn patient_v2(value: &mut Value) {
+  if value.get("practitioners").is_none_or(Value::is_null) {
+    value["practitioners"] = json!([]);
+  }
+}
+
+// Returns whether the document was changed and should be written back
+pub fn upgrade(document: Document, value: &mut Value) -> io::Result<bool> {
+  if !value.is_object() {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} document is not an object", document)));
+  }
+
+  let version = value.get("schema_version").and_then(Value::as_u64).unwrap_or(0) as usize;
+  let migrations = document.migrations();
+
+  if version > migrations.len() {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} document has schema version {}, newest supported is {}", document, version, migrations.len())));
+  }
+
+  for (idx, migration) in migrations.iter().enumerate().skip(version) {
+    migration(value);
+    value["schema_version"] = json!(idx + 1);
+  }
+
+  Ok(version < migrations.len())
+}
+
+pub fn parse<T: DeserializeOwned>(document: Document, data: &str) -> io::Result<(T, bool)> {
+  let mut value = serde_json::from_str::<Value>(data)?;
+  let migrated = upgrade(document, &mut value)?;
+  Ok((serde_json::from_value(value)?, migrated))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::state::patient::{FsPatient, Patient};
+  use crate::state::session::{FsSession, Session};
+  use crate::state::state::RwState;
+
+  const PATIENT_V0: &str = r#"{"description":"notes","age":42,"name":"Jan","address":"Warszawa","profile_picture":null,"created_at":1700000000,"last_updated":1700000100}"#;
+  const SESSION_V0: &str = r#"{"patient_uuid":"p1","start":1700000000,"end":1700003600,"paid":150.0,"emotions":[],"timeline":{},"created_at":1700000000,"last_updated":1700000000,"calendar_ids":{"a@b.c":"abc"}}"#;
+  const STATE_V0: &str = r#"{"users":{},"sse_tokens":{"t":"s"}}"#;
+  const STATE_V0_NULL_WEBHOOKS: &str = r#"{"users":{},"sse_tokens":{},"calendar_webhooks":null}"#;
+
+  #[test]
+  fn upgrades_unversioned_patient() {
+    let (patient, migrated) = parse::<FsPatient>(Document::Patient, PATIENT_V0).unwrap();
+    let patient = Patient::from_fs("p1".into(), patient);
+
+    assert!(migrated);
+    assert_eq!(patient.name, "Jan");
+    assert_eq!(patient.age, Some(42));
+    assert_eq!(serde_json::to_value(patient.to_fs()).unwrap()["schema_version"], json!(Document::Patient.version()));
+  }
+
+  #[test]
+  fn fills_missing_practitioners() {
+    let data = PATIENT_V0.replacen('{', r#"{"schema_version":1,"#, 1);
+    let (patient, migrated) = parse::<FsPatient>(Document::Patient, &data).unwrap();
+    let patient = Patient::from_fs("p1".into(), patient);
+
+    assert!(migrated);
+    assert!(patient.practitioners.is_empty());
+  }
+
+  #[test]
+  fn upgrades_unversioned_session() {
+    let (session, migrated) = parse::<FsSession>(Document::Session, SESSION_V0).unwrap();
+    let session = Session::from_fs("s1".into(), session);
+
+    assert!(migrated);
+    assert_eq!(session.patient_uuid, "p1");
+    assert_eq!(session.calendar_ids.get("a@b.c").map(String::as_str), Some("abc"));
+  }
+
+  #[test]
+  fn fills_missing_calendar_webhooks() {
+    for fixture in [STATE_V0, STATE_V0_NULL_WEBHOOKS] {
+      let mut value = serde_json::from_str::<Value>(fixture).unwrap();
+      assert!(upgrade(Document::State, &mut value).unwrap());
+      assert_eq!(value["calendar_webhooks"], json!({}));
+      assert_eq!(value["invitations"], json!({}));
+      assert_eq!(value["devices"], json!({}));
+      assert_eq!(value["schema_version"], json!(Document::State.version()));
+      assert!(serde_json::from_value::<RwState>(value).is_ok());
+    }
+  }
+
+  #[test]
+  fn moves_user_tokens_to_devices() {
+    let mut value = json!({"schema_version": 2, "users": {"token": {}}, "sse_tokens": {"token": "sse"}, "calendar_webhooks": {}, "invitations": {}});
+    assert!(upgrade(Document::State, &mut value).unwrap());
+    assert_eq!(value["devices"]["token"]["user"], json!("token"));
+    assert_eq!(value["sse_tokens"], json!({}));
+  }
+
+  #[test]
+  fn current_documents_are_left_alone() {
+    let data = serde_json::to_string(&Patient::from_fs("p1".into(), parse::<FsPatient>(Document::Patient, PATIENT_V0).unwrap().0).to_fs()).unwrap();
+    let (_, migrated) = parse::<FsPatient>(Document::Patient, &data).unwrap();
+    assert!(!migrated);
+  }
+
+  #[test]
+  fn rejects_newer_documents() {
+    let data = format!(r#"{{"schema_version":{},"users":{{}},"sse_tokens":{{}},"calendar_webhooks":{{}},"invitations":{{}},"devices":{{}}}}"#, Document::State.version() + 1);
+    assert!(parse::<RwState>(Document::State, &data).is_err());
+  }
+
+  #[test]
+  fn rejects_non_objects() {
+    assert!(parse::<FsPatient>(Document::Patient, "[]").is_err());
+  }
+}
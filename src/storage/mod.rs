@@ -0,0 +1,303 @@
+use crate::state::state::{RwState, GoogleEvent};
+use crate::state::patient::Patient;
+use crate::state::session::Session;
+use crate::state::trash::TrashItem;
+use crate::audit::{AuditEntry, AuditPage};
+use crate::jobs::Job;
+use crate::shutdown;
+use crate::logs::*;
+
+use std::sync::{mpsc, Arc, OnceLock};
+use std::thread;
+use std::str::FromStr;
+use std::{fmt, io};
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+pub mod migrations;
+pub mod encryption;
+mod fs;
+mod sqlite;
+
+pub use fs::FsStorage;
+pub use sqlite::SqliteStorage;
+
+pub trait Storage: Send + Sync {
+  fn load_patients(&self) -> io::Result<Vec<Patient>>;
+  fn save_patient(&self, patient: &Patient) -> io::Result<()>;
+  fn delete_patient(&self, uuid: &str) -> io::Result<()>;
+
+  fn load_sessions(&self) -> io::Result<Vec<Session>>;
+  fn save_session(&self, session: &Session) -> io::Result<()>;
+  fn delete_session(&self, uuid: &str) -> io::Result<()>;
+
+  // Soft-deleted patients and sessions waiting to be restored or purged
+  fn load_trash(&self) -> io::Result<Vec<TrashItem>>;
+  fn save_trash_item(&self, item: &TrashItem) -> io::Result<()>;
+  fn delete_trash_item(&self, uuid: &str) -> io::Result<()>;
+
+  // Users, SSE tokens and calendar webhooks
+  fn load_state(&self) -> io::Result<Option<RwState>>;
+  fn save_state(&self, state: &RwState) -> io::Result<()>;
+
+  // Cached Google Calendar events, keyed by the user's access token
+  fn load_events(&self, user: &str) -> io::Result<Option<Vec<GoogleEvent>>>;
+  fn save_events(&self, user: &str, events: &[GoogleEvent]) -> io::Result<()>;
+  #[allow(dead_code)]
+  fn delete_events(&self, user: &str) -> io::Result<()>;
+
+  // Google Calendar jobs that haven't gone through yet, including the ones that gave up
+  fn load_jobs(&self) -> io::Result<Vec<Job>>;
+  fn save_job(&self, job: &Job) -> io::Result<()>;
+  fn delete_job(&self, id: &str) -> io::Result<()>;
+
+  // Append-only audit trail of every patient and session mutation. Entries are read newest first,
+  // starting below the `before` cursor, until `limit` of them matched the filter.
+  fn append_audit(&self, entries: &[AuditEntry]) -> io::Result<()>;
+  fn load_audit(&self, before: Option<u64>, limit: usize, filter: &dyn Fn(&AuditEntry) -> bool) -> io::Result<AuditPage>;
+
+  // Records that couldn't be parsed on load and were set aside
+  fn quarantined(&self) -> io::Result<Vec<QuarantinedRecord>>;
+
+  // Paths relative to the data directory that should end up in backups
+  fn backup_paths(&self) -> Vec<&'static str>;
+
+  // What the records are sealed with, and whether any of them still need rewriting under it
+  fn keyring(&self) -> &encryption::Keyring;
+}
+
+impl fmt::Debug for dyn Storage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Storage")
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedRecord {
+  pub kind: String,
+  pub uuid: String,
+  pub reason: String,
+  pub quarantined_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+  Fs,
+  Sqlite,
+  Memory,
+}
+
+impl FromStr for StorageKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "fs" | "json" => Ok(StorageKind::Fs),
+      "sqlite" => Ok(StorageKind::Sqlite),
+      "memory" => Ok(StorageKind::Memory),
+      _ => Err(format!("Unknown storage backend: {}", s)),
+    }
+  }
+}
+
+type Write = Box<dyn FnOnce() + Send>;
+
+// Record writes are handed to a single thread that runs them one after another, so the blocking
+// write and fsync stay off the async workers and two writes of the same record can't land out of order
+pub fn spawn_write<F>(write: F)
+where
+  F: FnOnce() + Send + 'static,
+{
+  static WRITER: OnceLock<mpsc::Sender<Write>> = OnceLock::new();
+  let writer = WRITER.get_or_init(|| {
+    let (tx, rx) = mpsc::channel::<Write>();
+    thread::Builder::new().name("storage-writer".into()).spawn(move || rx.into_iter().for_each(|write| write())).expect("Couldn't start the storage writer");
+    tx
+  });
+
+  // Shutdown waits for queued writes like it does for any other background job
+  let job = shutdown::job();
+  let write = Box::new(move || {
+    write();
+    drop(job);
+  });
+
+  if writer.send(write).is_err() {
+    error!("Storage writer is gone, dropping a write");
+  }
+}
+
+// Runs a blocking storage call on the writer thread and waits for it. It goes behind every write
+// queued before it, so a read sees them and an append lands after them.
+pub async fn run<T, F>(call: F) -> io::Result<T>
+where
+  T: Send + 'static,
+  F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+  let (tx, rx) = oneshot::channel();
+  spawn_write(move || {
+    let _ = tx.send(call());
+  });
+
+  rx.await.map_err(io::Error::other)?
+}
+
+pub fn open(kind: StorageKind, path: &str) -> io::Result<Arc<dyn Storage>> {
+  Ok(match kind {
+    StorageKind::Fs => Arc::new(FsStorage::new(path)?),
+    StorageKind::Sqlite => Arc::new(SqliteStorage::open(format!("{}dashboard.db", path))?),
+    StorageKind::Memory => Arc::new(SqliteStorage::in_memory()?),
+  })
+}
+
+// Opens data written by either on-disk backend, e.g. an extracted backup archive
+pub fn open_dir(path: &str) -> io::Result<Box<dyn Storage>> {
+  let db = format!("{}dashboard.db", path);
+  if std::path::Path::new(&db).exists() {
+    return Ok(Box::new(SqliteStorage::open(db)?));
+  }
+
+  Ok(Box::new(FsStorage::new(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audit::{Action, EntityKind, Source};
+  use crate::state::trash::Trashed;
+
+  fn memory() -> Arc<dyn Storage> {
+    let _ = encryption::init();
+    open(StorageKind::Memory, "").unwrap()
+  }
+
+  fn patient(uuid: &str) -> Patient {
+    Patient {
+      uuid: uuid.into(),
+      name: "Jan".into(),
+      description: "notes".into(),
+      practitioners: vec!["a@b.c".into()],
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn patients_round_trip() {
+    let storage = memory();
+    storage.save_patient(&patient("p1")).unwrap();
+    storage.save_patient(&patient("p2")).unwrap();
+    storage.delete_patient("p2").unwrap();
+
+    let patients = storage.load_patients().unwrap();
+    assert_eq!(patients.len(), 1);
+    assert_eq!(patients[0].uuid, "p1");
+    assert_eq!(patients[0].description, "notes");
+    assert_eq!(patients[0].practitioners, vec!["a@b.c".to_owned()]);
+  }
+
+  #[test]
+  fn sessions_round_trip() {
+    let storage = memory();
+    let mut session = Session { uuid: "s1".into(), patient_uuid: "p1".into(), start: 10, end: 20, ..Default::default() };
+    session.calendar_ids.insert("a@b.c".into(), "event".into());
+    storage.save_session(&session).unwrap();
+
+    session.paid = 150.0;
+    storage.save_session(&session).unwrap();
+
+    let sessions = storage.load_sessions().unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].paid, 150.0);
+    assert_eq!(sessions[0].calendar_ids.get("a@b.c").map(String::as_str), Some("event"));
+  }
+
+  #[test]
+  fn trash_round_trip() {
+    let storage = memory();
+    let item = TrashItem::new(Trashed::Patient { patient: patient("p1"), sessions: Vec::new() }, "a@b.c");
+    storage.save_trash_item(&item).unwrap();
+
+    let trash = storage.load_trash().unwrap();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].deleted_by, "a@b.c");
+    assert!(matches!(&trash[0].item, Trashed::Patient { patient, .. } if patient.name == "Jan"));
+
+    storage.delete_trash_item(&item.uuid).unwrap();
+    assert!(storage.load_trash().unwrap().is_empty());
+  }
+
+  #[test]
+  fn state_and_events_round_trip() {
+    let storage = memory();
+    assert!(storage.load_state().unwrap().is_none());
+    storage.save_state(&RwState::default()).unwrap();
+    assert!(storage.load_state().unwrap().is_some());
+
+    assert!(storage.load_events("token").unwrap().is_none());
+    storage.save_events("token", &[]).unwrap();
+    assert_eq!(storage.load_events("token").unwrap().map(|events| events.len()), Some(0));
+    storage.delete_events("token").unwrap();
+    assert!(storage.load_events("token").unwrap().is_none());
+  }
+
+  #[test]
+  fn jobs_round_trip() {
+    let storage = memory();
+    let job = serde_json::from_value::<Job>(serde_json::json!({
+      "id": "j1", "key": "a@b.c:event", "email": "a@b.c", "event_id": "event", "action": { "type": "delete" },
+      "attempts": 2, "next_attempt": 0, "created_at": 0, "last_error": "timeout", "failed_at": null,
+    })).unwrap();
+    storage.save_job(&job).unwrap();
+
+    let jobs = storage.load_jobs().unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].attempts, 2);
+
+    storage.delete_job("j1").unwrap();
+    assert!(storage.load_jobs().unwrap().is_empty());
+  }
+
+  pub(crate) fn entries(count: usize) -> Vec<AuditEntry> {
+    (0..count).map(|i| AuditEntry {
+      timestamp: i as u64,
+      actor: "a@b.c".into(),
+      source: Source::Api,
+      action: [Action::Create, Action::Update, Action::Delete][i % 3],
+      entity: EntityKind::Patient,
+      uuid: format!("p{}", i),
+      patient_uuid: format!("p{}", i),
+      before: None,
+      after: None,
+    }).collect()
+  }
+
+  // Pages come newest first and, followed by their cursors, cover the whole log once
+  pub(crate) fn assert_pages(storage: &dyn Storage, count: usize) {
+    let mut seen = Vec::new();
+    let mut before = None;
+    loop {
+      let page = storage.load_audit(before, 4, &|_| true).unwrap();
+      assert!(page.entries.len() <= 4);
+      seen.extend(page.entries.into_iter().map(|entry| entry.timestamp));
+      match page.next {
+        Some(next) => before = Some(next),
+        None => break,
+      }
+    }
+
+    assert_eq!(seen, (0..count as u64).rev().collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn audit_pages_newest_first() {
+    let storage = memory();
+    let entries = entries(10);
+    storage.append_audit(&entries[..3]).unwrap();
+    storage.append_audit(&entries[3..]).unwrap();
+    assert_pages(&*storage, 10);
+
+    let page = storage.load_audit(None, 10, &|entry| entry.action == Action::Delete).unwrap();
+    assert_eq!(page.entries.iter().map(|entry| entry.timestamp).collect::<Vec<_>>(), [8, 5, 2]);
+  }
+}
@@ -0,0 +1,329 @@
+use crate::state::state::{RwState, GoogleEvent};
+use crate::state::patient::{Patient, FsPatient};
+use crate::state::session::{Session, FsSession};
+use crate::state::trash::TrashItem;
+use crate::audit::{AuditEntry, AuditPage};
+use crate::jobs::Job;
+use crate::logs::*;
+use super::migrations::{self, Document};
+use super::encryption::{self, Keyring, AUDIT_LOG};
+use super::{Storage, QuarantinedRecord};
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::io;
+
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params};
+
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS patients (uuid TEXT PRIMARY KEY, data TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS sessions (uuid TEXT PRIMARY KEY, data TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS trash (uuid TEXT PRIMARY KEY, data TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS jobs (uuid TEXT PRIMARY KEY, data TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS state (id INTEGER PRIMARY KEY CHECK (id = 0), data TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS events (user TEXT PRIMARY KEY, data TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS audit (id INTEGER PRIMARY KEY AUTOINCREMENT, data TEXT NOT NULL);
+  CREATE TABLE IF NOT EXISTS quarantine (kind TEXT NOT NULL, uuid TEXT NOT NULL, data TEXT NOT NULL, reason TEXT NOT NULL, quarantined_at INTEGER NOT NULL);
+";
+
+// Every document is stored as the same JSON the fs backend writes, one row per record
+pub struct SqliteStorage {
+  conn: Mutex<Connection>,
+  in_memory: bool,
+  keyring: Arc<Keyring>,
+}
+
+fn to_io(err: rusqlite::Error) -> io::Error {
+  io::Error::other(err)
+}
+
+impl SqliteStorage {
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let conn = Connection::open(path).map_err(to_io)?;
+    Self::init(conn, false, encryption::keyring())
+  }
+
+  pub fn in_memory() -> io::Result<Self> {
+    let conn = Connection::open_in_memory().map_err(to_io)?;
+    Self::init(conn, true, encryption::keyring())
+  }
+
+  // A named in-memory database that lives as long as one of its connections does, so tests can
+  // open the same records under different keys
+  #[cfg(test)]
+  pub fn shared(name: &str, keyring: Arc<Keyring>) -> io::Result<Self> {
+    let conn = Connection::open(format!("file:{}?mode=memory&cache=shared", name)).map_err(to_io)?;
+    Self::init(conn, true, keyring)
+  }
+
+  fn init(conn: Connection, in_memory: bool, keyring: Arc<Keyring>) -> io::Result<Self> {
+    conn.execute_batch(SCHEMA).map_err(to_io)?;
+    Ok(SqliteStorage { conn: Mutex::new(conn), in_memory, keyring })
+  }
+
+  fn load_all(&self, table: &str) -> io::Result<Vec<(String, String)>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare(&format!("SELECT uuid, data FROM {}", table)).map_err(to_io)?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).map_err(to_io)?;
+    rows.collect::<Result<Vec<_>, _>>().map_err(to_io)
+  }
+
+  fn upsert(&self, table: &str, uuid: &str, data: String) -> io::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(&format!("INSERT OR REPLACE INTO {} (uuid, data) VALUES (?1, ?2)", table), params![uuid, data]).map_err(to_io)?;
+    Ok(())
+  }
+
+  // Moves an unparsable row out of its table so the rest of the data can still load
+  fn quarantine(&self, table: &str, kind: &str, uuid: &str, data: &str, reason: String) -> io::Result<()> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction().map_err(to_io)?;
+    tx.execute(
+      "INSERT INTO quarantine (kind, uuid, data, reason, quarantined_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![kind, uuid, data, reason, Utc::now().timestamp()],
+    ).map_err(to_io)?;
+    tx.execute(&format!("DELETE FROM {} WHERE uuid = ?1", table), params![uuid]).map_err(to_io)?;
+    tx.commit().map_err(to_io)
+  }
+
+  fn remove(&self, table: &str, uuid: &str) -> io::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute(&format!("DELETE FROM {} WHERE uuid = ?1", table), params![uuid]).map_err(to_io)?;
+    Ok(())
+  }
+}
+
+impl Storage for SqliteStorage {
+  fn load_patients(&self) -> io::Result<Vec<Patient>> {
+    let mut patients = Vec::new();
+    for (uuid, data) in self.load_all("patients")? {
+      match self.keyring.unseal("patient", &uuid, &data).and_then(|json| migrations::parse::<FsPatient>(Document::Patient, &json)) {
+        Ok((patient, migrated)) => {
+          let patient = Patient::from_fs(uuid, patient);
+          if migrated {
+            info!("Migrated patient {} to schema version {}", patient.uuid, Document::Patient.version());
+            self.save_patient(&patient)?;
+          }
+
+          patients.push(patient);
+        },
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+          return Err(io::Error::new(err.kind(), format!("Couldn't load patient {}: {}", uuid, err)));
+        },
+        Err(err) => {
+          error!("Couldn't parse patient {}, moving it to quarantine: {}", uuid, err);
+          self.quarantine("patients", "patient", &uuid, &data, err.to_string())?;
+        }
+      }
+    }
+
+    info!("Loaded {} patients", patients.len());
+    Ok(patients)
+  }
+
+  fn save_patient(&self, patient: &Patient) -> io::Result<()> {
+    self.upsert("patients", &patient.uuid, self.keyring.seal("patient", &patient.uuid, serde_json::to_string(&patient.to_fs())?)?)
+  }
+
+  fn delete_patient(&self, uuid: &str) -> io::Result<()> {
+    self.remove("patients", uuid)
+  }
+
+  fn load_sessions(&self) -> io::Result<Vec<Session>> {
+    let mut sessions = Vec::new();
+    for (uuid, data) in self.load_all("sessions")? {
+      match self.keyring.unseal("session", &uuid, &data).and_then(|json| migrations::parse::<FsSession>(Document::Session, &json)) {
+        Ok((session, migrated)) => {
+          let session = Session::from_fs(uuid, session);
+          if migrated {
+            info!("Migrated session {} to schema version {}", session.uuid, Document::Session.version());
+            self.save_session(&session)?;
+          }
+
+          sessions.push(session);
+        },
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+          return Err(io::Error::new(err.kind(), format!("Couldn't load session {}: {}", uuid, err)));
+        },
+        Err(err) => {
+          error!("Couldn't parse session {}, moving it to quarantine: {}", uuid, err);
+          self.quarantine("sessions", "session", &uuid, &data, err.to_string())?;
+        }
+      }
+    }
+
+    info!("Loaded {} sessions", sessions.len());
+    Ok(sessions)
+  }
+
+  fn save_session(&self, session: &Session) -> io::Result<()> {
+    self.upsert("sessions", &session.uuid, self.keyring.seal("session", &session.uuid, serde_json::to_string(&session.to_fs())?)?)
+  }
+
+  fn delete_session(&self, uuid: &str) -> io::Result<()> {
+    self.remove("sessions", uuid)
+  }
+
+  fn load_trash(&self) -> io::Result<Vec<TrashItem>> {
+    let mut trash = Vec::new();
+    for (uuid, data) in self.load_all("trash")? {
+      match self.keyring.unseal("trash", &uuid, &data).and_then(|json| migrations::parse::<TrashItem>(Document::Trash, &json)) {
+        Ok((item, migrated)) => {
+          if migrated {
+            self.save_trash_item(&item)?;
+          }
+
+          trash.push(item);
+        },
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+          return Err(io::Error::new(err.kind(), format!("Couldn't load trash item {}: {}", uuid, err)));
+        },
+        Err(err) => {
+          error!("Couldn't parse trash item {}, moving it to quarantine: {}", uuid, err);
+          self.quarantine("trash", "trash", &uuid, &data, err.to_string())?;
+        }
+      }
+    }
+
+    info!("Loaded {} trash items", trash.len());
+    Ok(trash)
+  }
+
+  fn save_trash_item(&self, item: &TrashItem) -> io::Result<()> {
+    self.upsert("trash", &item.uuid, self.keyring.seal("trash", &item.uuid, serde_json::to_string(item)?)?)
+  }
+
+  fn delete_trash_item(&self, uuid: &str) -> io::Result<()> {
+    self.remove("trash", uuid)
+  }
+
+  fn load_state(&self) -> io::Result<Option<RwState>> {
+    let conn = self.conn.lock().unwrap();
+    let data = conn.query_row("SELECT data FROM state WHERE id = 0", [], |row| row.get::<_, String>(0)).optional().map_err(to_io)?;
+    drop(conn);
+
+    let data = match data {
+      Some(data) => data,
+      None => return Ok(None),
+    };
+
+    let (state, migrated) = migrations::parse::<RwState>(Document::State, &data)?;
+    if migrated {
+      info!("Migrated state to schema version {}", Document::State.version());
+      self.save_state(&state)?;
+    }
+
+    Ok(Some(state))
+  }
+
+  fn save_state(&self, state: &RwState) -> io::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute("INSERT OR REPLACE INTO state (id, data) VALUES (0, ?1)", params![serde_json::to_string(state)?]).map_err(to_io)?;
+    Ok(())
+  }
+
+  fn load_events(&self, user: &str) -> io::Result<Option<Vec<GoogleEvent>>> {
+    let conn = self.conn.lock().unwrap();
+    let data = conn.query_row("SELECT data FROM events WHERE user = ?1", params![user], |row| row.get::<_, String>(0)).optional().map_err(to_io)?;
+    match data {
+      Some(data) => Ok(Some(serde_json::from_str(&self.keyring.unseal("events", user, &data)?)?)),
+      None => Ok(None),
+    }
+  }
+
+  fn save_events(&self, user: &str, events: &[GoogleEvent]) -> io::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute("INSERT OR REPLACE INTO events (user, data) VALUES (?1, ?2)", params![user, self.keyring.seal("events", user, serde_json::to_string(events)?)?]).map_err(to_io)?;
+    Ok(())
+  }
+
+  fn delete_events(&self, user: &str) -> io::Result<()> {
+    let conn = self.conn.lock().unwrap();
+    conn.execute("DELETE FROM events WHERE user = ?1", params![user]).map_err(to_io)?;
+    Ok(())
+  }
+
+  fn load_jobs(&self) -> io::Result<Vec<Job>> {
+    let mut jobs = Vec::new();
+    for (uuid, data) in self.load_all("jobs")? {
+      match self.keyring.unseal("job", &uuid, &data).and_then(|json| Ok(serde_json::from_str(&json)?)) {
+        Ok(job) => jobs.push(job),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+          return Err(io::Error::new(err.kind(), format!("Couldn't load job {}: {}", uuid, err)));
+        },
+        Err(err) => {
+          error!("Couldn't parse job {}, moving it to quarantine: {}", uuid, err);
+          self.quarantine("jobs", "job", &uuid, &data, err.to_string())?;
+        }
+      }
+    }
+
+    Ok(jobs)
+  }
+
+  fn save_job(&self, job: &Job) -> io::Result<()> {
+    self.upsert("jobs", &job.id, self.keyring.seal("job", &job.id, serde_json::to_string(job)?)?)
+  }
+
+  fn delete_job(&self, id: &str) -> io::Result<()> {
+    self.remove("jobs", id)
+  }
+
+  fn append_audit(&self, entries: &[AuditEntry]) -> io::Result<()> {
+    let mut conn = self.conn.lock().unwrap();
+    let tx = conn.transaction().map_err(to_io)?;
+    for entry in entries {
+      tx.execute("INSERT INTO audit (data) VALUES (?1)", params![self.keyring.seal("audit", AUDIT_LOG, serde_json::to_string(entry)?)?]).map_err(to_io)?;
+    }
+
+    tx.commit().map_err(to_io)
+  }
+
+  fn load_audit(&self, before: Option<u64>, limit: usize, filter: &dyn Fn(&AuditEntry) -> bool) -> io::Result<AuditPage> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT id, data FROM audit WHERE id < ?1 ORDER BY id DESC").map_err(to_io)?;
+    let mut rows = stmt.query(params![before.unwrap_or(i64::MAX as u64)]).map_err(to_io)?;
+
+    let mut entries = Vec::new();
+    while let Some(row) = rows.next().map_err(to_io)? {
+      if entries.len() == limit {
+        let id = row.get::<_, u64>(0).map_err(to_io)?;
+        return Ok(AuditPage { entries, next: Some(id + 1) });
+      }
+
+      let entry = self.keyring.unseal("audit", AUDIT_LOG, &row.get::<_, String>(1).map_err(to_io)?)
+        .and_then(|json| Ok(serde_json::from_str::<AuditEntry>(&json)?));
+
+      // Entries sealed under a key that's since been dropped can't be read anymore
+      match entry {
+        Ok(entry) if filter(&entry) => entries.push(entry),
+        Ok(_) => {},
+        Err(err) => warning!("Skipping unreadable audit entry: {}", err),
+      }
+    }
+
+    Ok(AuditPage { entries, next: None })
+  }
+
+  fn quarantined(&self) -> io::Result<Vec<QuarantinedRecord>> {
+    let conn = self.conn.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT kind, uuid, reason, quarantined_at FROM quarantine").map_err(to_io)?;
+    let rows = stmt.query_map([], |row| Ok(QuarantinedRecord {
+      kind: row.get(0)?,
+      uuid: row.get(1)?,
+      reason: row.get(2)?,
+      quarantined_at: row.get(3)?,
+    })).map_err(to_io)?;
+
+    rows.collect::<Result<Vec<_>, _>>().map_err(to_io)
+  }
+
+  fn backup_paths(&self) -> Vec<&'static str> {
+    if self.in_memory { Vec::new() } else { vec!["dashboard.db"] }
+  }
+
+  fn keyring(&self) -> &Keyring {
+    &self.keyring
+  }
+}
@@ -0,0 +1,143 @@
+use crate::logs::*;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::{Certificate, PrivateKey};
+use rustls::server::{ResolvesServerCert, ClientHello};
+use rustls::sign::{CertifiedKey, any_supported_type};
+use tokio::time::interval;
+
+const WATCH_INTERVAL: u64 = 60;
+
+fn load_cert(cert: &PathBuf, key: &PathBuf) -> io::Result<CertifiedKey> {
+  let cert_file = File::open(cert)?;
+  let mut cert_reader = io::BufReader::new(cert_file);
+  let certs = rustls_pemfile::certs(&mut cert_reader)
+    .map(|cert|cert.map(|cert| Certificate(cert.iter().map(|byte| byte.to_owned()).collect())))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  if certs.is_empty() {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "No certificates found"));
+  }
+
+  let key_file = File::open(key)?;
+  let mut key_reader = io::BufReader::new(key_file);
+  let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_reader).collect::<Result<Vec<_>, _>>()?;
+
+  let key = match keys.len() {
+    1 => PrivateKey(keys.remove(0).secret_pkcs8_der().to_vec()),
+    0 => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No keys found")),
+    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Multiple keys found")),
+  };
+
+  let key = match any_supported_type(&key) {
+    Ok(key) => key,
+    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid key type")),
+  };
+
+  Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[derive(Default)]
+struct Entry {
+  cert: Option<Arc<CertifiedKey>>,
+  // Modification times of the files at the last load attempt
+  modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+// Certificates can be swapped while the server runs, handshakes pick up the new one right away
+// `www.` is served with the certificate of the bare domain
+#[derive(Default)]
+pub struct CertResolver {
+  data_dir: String,
+  domains: Vec<String>,
+  certs: RwLock<HashMap<String, Entry>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+  fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    let name = client_hello.server_name()?.to_lowercase();
+    let certs = self.certs.read().unwrap();
+    let cert = |name: &str| certs.get(name).and_then(|entry| entry.cert.clone());
+
+    cert(&name).or_else(|| name.strip_prefix("www.").and_then(cert))
+  }
+}
+
+impl CertResolver {
+  // A domain without a certificate is logged and skipped, it starts working once one shows up
+  pub fn new(data_dir: &str, domains: Vec<String>) -> Arc<Self> {
+    let resolver = Arc::new(CertResolver { data_dir: data_dir.to_owned(), domains, ..Default::default() });
+    for domain in &resolver.domains {
+      if let Err(err) = resolver.load(domain) {
+        error!("Couldn't load the certificate for {}: {}", domain, err);
+      }
+    }
+
+    resolver
+  }
+
+  // Certificates live in <data dir>/<domain>/, installs from before DOMAINS kept them under the top-level domain alone
+  pub fn cert_paths(&self, domain: &str) -> (PathBuf, PathBuf) {
+    let dir = PathBuf::from(format!("{}{}", self.data_dir, domain));
+    let legacy = PathBuf::from(format!("{}{}", self.data_dir, domain.rsplit('.').next().unwrap_or(domain)));
+    let dir = if !dir.exists() && legacy.join("certificate.pem").exists() { legacy } else { dir };
+
+    (dir.join("certificate.pem"), dir.join("private.pem"))
+  }
+
+  // On failure the previous certificate stays in use
+  pub fn load(&self, domain: &str) -> io::Result<()> {
+    let (cert, key) = self.cert_paths(domain);
+    let modified = (modified(&cert), modified(&key));
+    let certified = load_cert(&cert, &key);
+
+    let mut certs = self.certs.write().unwrap();
+    let entry = certs.entry(domain.to_owned()).or_default();
+    entry.modified = modified;
+    entry.cert = Some(Arc::new(certified?));
+
+    info!("Loaded the certificate for {}", domain);
+    Ok(())
+  }
+
+  pub fn domains(&self) -> &[String] {
+    &self.domains
+  }
+
+  pub fn data_dir(&self) -> &str {
+    &self.data_dir
+  }
+
+  fn is_stale(&self, domain: &str) -> bool {
+    let (cert, key) = self.cert_paths(domain);
+    let modified = (modified(&cert), modified(&key));
+    self.certs.read().unwrap().get(domain).map(|entry| entry.modified) != Some(modified)
+  }
+}
+
+// Polls the certificate files and reloads the ones that changed, a broken file keeps the old certificate in use
+pub fn start_watch_loop(resolver: Arc<CertResolver>) {
+  tokio::spawn(async move {
+    info!("Watching certificates of {}...", resolver.domains().join(", "));
+    let mut interval = interval(Duration::from_secs(WATCH_INTERVAL));
+
+    loop {
+      interval.tick().await;
+      for domain in resolver.domains().iter().filter(|domain| resolver.is_stale(domain)) {
+        if let Err(err) = resolver.load(domain) {
+          warning!("Couldn't reload the certificate for {}, keeping the previous one: {}", domain, err);
+        }
+      }
+    }
+  });
+}
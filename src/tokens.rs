@@ -0,0 +1,51 @@
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use subtle::ConstantTimeEq;
+
+// Access tokens, SSE tokens and OAuth hand-off codes are random bytes from the OS, hex encoded.
+// How many is up to the config (token_bytes), which refuses anything under MIN_BYTES.
+pub const DEFAULT_BYTES: usize = 32;
+pub const MIN_BYTES: usize = 16;
+
+pub fn generate(bytes: usize) -> String {
+  let mut token = vec![0u8; bytes];
+  OsRng.fill_bytes(&mut token);
+  token.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Takes the same time wherever the first mismatch is, so a token can't be guessed byte by byte
+pub fn matches(expected: &str, given: &str) -> bool {
+  expected.as_bytes().ct_eq(given.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::collections::HashSet;
+  use std::thread;
+
+  #[test]
+  fn tokens_have_the_requested_length() {
+    assert_eq!(generate(16).len(), 32);
+    assert_eq!(generate(48).len(), 96);
+    assert!(generate(DEFAULT_BYTES).chars().all(|c| c.is_ascii_hexdigit()));
+  }
+
+  #[test]
+  fn tokens_are_unique_under_concurrent_creation() {
+    let threads = (0..8).map(|_| thread::spawn(|| (0..1000).map(|_| generate(DEFAULT_BYTES)).collect::<Vec<_>>())).collect::<Vec<_>>();
+    let tokens = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect::<Vec<_>>();
+
+    assert_eq!(tokens.iter().collect::<HashSet<_>>().len(), tokens.len());
+  }
+
+  #[test]
+  fn matches_only_identical_tokens() {
+    let token = generate(DEFAULT_BYTES);
+    assert!(matches(&token, &token.clone()));
+    assert!(!matches(&token, &generate(DEFAULT_BYTES)));
+    assert!(!matches(&token, &token[..token.len() - 1]));
+    assert!(!matches(&token, ""));
+  }
+}
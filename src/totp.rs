@@ -0,0 +1,176 @@
+use crate::tokens;
+
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+// RFC 6238 with the parameters every authenticator app defaults to
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_BYTES: usize = 20;
+const BACKUP_CODES: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Totp {
+  // Base32, the way authenticator apps expect it
+  pub secret: String,
+  // Set once the user proved their app generates matching codes
+  pub confirmed: bool,
+  // SHA-256 of the unused backup codes
+  pub backup_codes: Vec<String>,
+  // Each code is only accepted once
+  pub last_step: u64,
+}
+
+impl Totp {
+  pub fn new() -> Self {
+    let mut secret = [0u8; SECRET_BYTES];
+    OsRng.fill_bytes(&mut secret);
+
+    Totp {
+      secret: BASE32_NOPAD.encode(&secret),
+      confirmed: false,
+      backup_codes: Vec::new(),
+      last_step: 0,
+    }
+  }
+
+  pub fn url(&self, email: &str) -> String {
+    format!("otpauth://totp/Dashboard:{}?secret={}&issuer=Dashboard&digits={}&period={}", email, self.secret, DIGITS, STEP)
+  }
+
+  fn code_at(&self, step: u64) -> Option<u32> {
+    let key = BASE32_NOPAD.decode(self.secret.as_bytes()).ok()?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().ok()?) & 0x7fff_ffff;
+    Some(code % 10u32.pow(DIGITS))
+  }
+
+  pub fn verify(&mut self, code: &str) -> bool {
+    self.verify_at(code, Utc::now().timestamp() as u64)
+  }
+
+  // Accepts the current code and its neighbours to allow for clock drift
+  fn verify_at(&mut self, code: &str, now: u64) -> bool {
+    let step = now / STEP;
+    let matched = (step.saturating_sub(1)..=step + 1)
+      .filter(|&candidate| candidate > self.last_step)
+      .find(|&candidate| self.code_at(candidate).is_some_and(|expected| tokens::matches(&format!("{:0width$}", expected, width = DIGITS as usize), code)));
+
+    match matched {
+      Some(step) => {
+        self.last_step = step;
+        true
+      },
+      None => false,
+    }
+  }
+
+  // Returns the new codes, only their hashes are kept
+  pub fn regenerate_backup_codes(&mut self) -> Vec<String> {
+    let codes = (0..BACKUP_CODES).map(|_| tokens::generate(5)).collect::<Vec<_>>();
+    self.backup_codes = codes.iter().map(|code| hash(code)).collect();
+    codes
+  }
+
+  pub fn use_backup_code(&mut self, code: &str) -> bool {
+    let code = hash(code.trim());
+    match self.backup_codes.iter().position(|hash| tokens::matches(hash, &code)) {
+      Some(position) => {
+        self.backup_codes.remove(position);
+        true
+      },
+      None => false,
+    }
+  }
+}
+
+impl Totp {
+  // Step-up confirmations take either a code from the app or one of the backup codes
+  pub fn confirm(&mut self, code: &str) -> bool {
+    self.confirmed && (self.verify(code) || self.use_backup_code(code))
+  }
+}
+
+fn hash(code: &str) -> String {
+  format!("{:x}", Sha256::digest(code.to_lowercase().as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The RFC 6238 SHA-1 key, "12345678901234567890"
+  fn rfc() -> Totp {
+    Totp { secret: BASE32_NOPAD.encode(b"12345678901234567890"), ..Totp::new() }
+  }
+
+  fn code(totp: &Totp, time: u64) -> String {
+    format!("{:06}", totp.code_at(time / STEP).unwrap())
+  }
+
+  #[test]
+  fn matches_the_rfc_test_vectors() {
+    // Appendix B lists 8 digit codes, 6 digit ones are their last 6 digits
+    let totp = rfc();
+    for (time, expected) in [(59, "287082"), (1111111109, "081804"), (1111111111, "050471"), (1234567890, "005924"), (2000000000, "279037"), (20000000000, "353130")] {
+      assert_eq!(code(&totp, time), expected, "at {}", time);
+    }
+  }
+
+  #[test]
+  fn accepts_one_step_of_drift_either_way() {
+    let now: u64 = 1234567890;
+    for (offset, accepted) in [(-60i64, false), (-30, true), (0, true), (30, true), (60, false)] {
+      let mut totp = rfc();
+      let code = code(&totp, now.checked_add_signed(offset).unwrap());
+      assert_eq!(totp.verify_at(&code, now), accepted, "{}s off", offset);
+    }
+
+    assert!(!rfc().verify_at("000000", 1234567890));
+  }
+
+  #[test]
+  fn rejects_replayed_codes() {
+    let now = 1234567890;
+    let mut totp = rfc();
+    let (previous, current) = (code(&totp, now - STEP), code(&totp, now));
+
+    assert!(totp.verify_at(&current, now));
+    assert!(!totp.verify_at(&current, now));
+    // Nor is an older step accepted once a newer one was used
+    assert!(!totp.verify_at(&previous, now));
+  }
+
+  #[test]
+  fn backup_codes_work_once() {
+    let mut totp = Totp { confirmed: true, ..rfc() };
+    let codes = totp.regenerate_backup_codes();
+    assert_eq!(codes.len(), BACKUP_CODES);
+
+    assert!(totp.confirm(&format!(" {} ", codes[0].to_uppercase())));
+    assert!(!totp.confirm(&codes[0]));
+    assert!(totp.use_backup_code(&codes[1]));
+    assert_eq!(totp.backup_codes.len(), BACKUP_CODES - 2);
+
+    // Regenerating throws away the codes that weren't used
+    totp.regenerate_backup_codes();
+    assert!(!totp.use_backup_code(&codes[2]));
+  }
+
+  #[test]
+  fn unconfirmed_setups_confirm_nothing() {
+    let mut totp = rfc();
+    let codes = totp.regenerate_backup_codes();
+    assert!(!totp.confirm(&codes[0]));
+  }
+}